@@ -50,9 +50,9 @@
               options.listener = lib.mkOption {
                 type = lib.types.str;
               };
-              options.ignore_qr_condition = lib.mkOption {
-                type = lib.types.str;
-                default = "false";
+              options.rules = lib.mkOption {
+                type = with lib.types; listOf attrs;
+                default = [ ];
               };
               options.data_path = lib.mkOption {
                 type = lib.types.path;
@@ -0,0 +1,241 @@
+//! Splitting receipt-level amounts (discounts, delivery fees, tips, bonus payments)
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use fiscal_data::{enums::ItemType, fields, Object};
+use serde::Deserialize;
+
+use crate::item_is_advance;
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Items are ticked by hand, receipt-level amounts are ignored
+    #[default]
+    Manual,
+    /// Proportionally to each user's share of the rest of the receipt
+    Proportional,
+    /// Evenly among everyone who has a share in the receipt
+    Even,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Rules {
+    /// Receipt-level discounts and markups (1064/1035, also inside 1112)
+    pub discounts: Option<Mode>,
+    /// Delivery, service and tip items
+    pub fees: Option<Mode>,
+    /// Sum paid with bonus points (1217)
+    pub bonuses: Option<Mode>,
+    /// Item types (1212) considered fees
+    pub fee_item_types: Option<Vec<ItemType>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AllocationConfig {
+    #[serde(flatten)]
+    pub default: Rules,
+    /// Overrides by store INN
+    pub stores: HashMap<String, Rules>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Resolved {
+    pub discounts: Mode,
+    pub fees: Mode,
+    pub bonuses: Mode,
+    pub fee_item_types: Vec<ItemType>,
+}
+
+impl AllocationConfig {
+    pub fn rules(&self, rec: &Object) -> Resolved {
+        let store = rec
+            .get::<fields::UserInn>()
+            .ok()
+            .flatten()
+            .and_then(|inn| self.stores.get(inn.trim()));
+        let pick = |f: fn(&Rules) -> Option<Mode>| {
+            store
+                .and_then(f)
+                .or_else(|| f(&self.default))
+                .unwrap_or_default()
+        };
+        Resolved {
+            discounts: pick(|x| x.discounts),
+            fees: pick(|x| x.fees),
+            bonuses: pick(|x| x.bonuses),
+            fee_item_types: store
+                .and_then(|x| x.fee_item_types.clone())
+                .or_else(|| self.default.fee_item_types.clone())
+                .unwrap_or_else(|| vec![ItemType::Labor, ItemType::Service, ItemType::AgentReward]),
+        }
+    }
+}
+
+impl Resolved {
+    /// Whether the item is split automatically rather than ticked by hand
+    pub fn is_fee(&self, item: &Object) -> bool {
+        self.fees != Mode::Manual
+            && !item_is_advance(item).unwrap_or_default()
+            && item
+                .get::<fields::ItemType>()
+                .ok()
+                .flatten()
+                .is_some_and(|x| self.fee_item_types.contains(&x))
+    }
+}
+
+#[allow(deprecated)]
+fn discount(rec: &Object) -> i64 {
+    let get = |obj: &Object| {
+        let discount = obj.get::<fields::DiscountSum>().ok().flatten().unwrap_or(0);
+        let markup = obj.get::<fields::MarkupSum>().ok().flatten().unwrap_or(0);
+        i64::try_from(discount).expect("u64->i64 conversion failed")
+            - i64::try_from(markup).expect("u64->i64 conversion failed")
+    };
+    get(rec)
+        + rec
+            .get_all::<fields::Modifiers>()
+            .unwrap_or_default()
+            .iter()
+            .map(get)
+            .sum::<i64>()
+}
+
+/// Amount each user gets when splitting `amount` (may be negative) according to `weights`
+fn distribute(amount: i64, weights: &BTreeMap<String, i64>, mode: Mode) -> BTreeMap<String, i64> {
+    let total_weight: i64 = weights.values().sum();
+    let count = i64::try_from(weights.len()).expect("usize->i64 conversion failed");
+    weights
+        .iter()
+        .map(|(user, weight)| {
+            let share = match mode {
+                Mode::Manual => 0,
+                Mode::Even => amount / count,
+                Mode::Proportional if total_weight == 0 => amount / count,
+                Mode::Proportional => i64::try_from(
+                    i128::from(amount) * i128::from(*weight) / i128::from(total_weight),
+                )
+                .expect("i128->i64 conversion failed"),
+            };
+            (user.clone(), share)
+        })
+        .collect()
+}
+
+/// Compute how much of the receipt each user consumed. `per_item` is the set of users ticked for
+/// each item; `payer` gets whatever can't be attributed to anyone else.
+pub fn split(
+    rec: &Object,
+    rules: &Resolved,
+    per_item: &HashMap<usize, HashSet<String>>,
+    payer: &str,
+) -> BTreeMap<String, i64> {
+    let items = rec.get_all::<fields::ReceiptItem>().unwrap_or_default();
+    let price = |item: &Object| {
+        i64::try_from(
+            item.get::<fields::ItemTotalPrice>()
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+        )
+        .expect("u64->i64 conversion failed")
+    };
+    let mut groups = HashMap::<Vec<String>, Vec<usize>>::new();
+    for (idx, users) in per_item {
+        if items.get(*idx).is_some_and(|item| rules.is_fee(item)) {
+            continue;
+        }
+        let mut users = users.iter().cloned().collect::<Vec<_>>();
+        users.sort();
+        groups.entry(users).or_default().push(*idx);
+    }
+    let mut shares = BTreeMap::<String, i64>::new();
+    for (users, idx) in &groups {
+        let total: i64 = idx.iter().filter_map(|x| items.get(*x)).map(price).sum();
+        let count = i64::try_from(users.len()).expect("usize->i64 conversion failed");
+        for user in users {
+            *shares.entry(user.clone()).or_default() += total / count;
+        }
+    }
+    shares.retain(|_, v| *v != 0);
+    if shares.is_empty() {
+        shares.insert(payer.to_owned(), 0);
+    }
+    let fees: i64 = items
+        .iter()
+        .filter(|item| rules.is_fee(item))
+        .map(price)
+        .sum();
+    let bonuses = i64::try_from(
+        rec.get::<fields::TotalProvisionSum>()
+            .ok()
+            .flatten()
+            .unwrap_or_default(),
+    )
+    .expect("u64->i64 conversion failed");
+    let base = shares.clone();
+    for (amount, mode) in [
+        (fees, rules.fees),
+        (-discount(rec), rules.discounts),
+        (-bonuses, rules.bonuses),
+    ] {
+        if amount == 0 {
+            continue;
+        }
+        for (user, x) in distribute(amount, &base, mode) {
+            *shares.entry(user).or_default() += x;
+        }
+    }
+    shares
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use fiscal_data::{enums::ItemType, fields, Object};
+
+    use super::{AllocationConfig, Mode, Rules};
+
+    fn item(price: u64, item_type: ItemType) -> Object {
+        let mut ret = Object::new();
+        ret.set::<fields::ItemTotalPrice>(price).unwrap();
+        ret.set::<fields::ItemType>(item_type).unwrap();
+        ret
+    }
+
+    #[test]
+    fn test() {
+        let mut rec = Object::new();
+        rec.push::<fields::ReceiptItem>(item(3000, ItemType::Product))
+            .unwrap();
+        rec.push::<fields::ReceiptItem>(item(1000, ItemType::Product))
+            .unwrap();
+        rec.push::<fields::ReceiptItem>(item(400, ItemType::Service))
+            .unwrap();
+        rec.set::<fields::TotalProvisionSum>(200).unwrap();
+        let per_item = HashMap::from([
+            (0, HashSet::from(["a".to_owned()])),
+            (1, HashSet::from(["b".to_owned()])),
+            (2, HashSet::from(["a".to_owned()])),
+        ]);
+        let manual = AllocationConfig::default().rules(&rec);
+        let split = super::split(&rec, &manual, &per_item, "a");
+        assert_eq!(split.get("a"), Some(&3400));
+        assert_eq!(split.get("b"), Some(&1000));
+        let config = AllocationConfig {
+            default: Rules {
+                fees: Some(Mode::Proportional),
+                bonuses: Some(Mode::Even),
+                ..Rules::default()
+            },
+            ..AllocationConfig::default()
+        };
+        let split = super::split(&rec, &config.rules(&rec), &per_item, "a");
+        assert_eq!(split.get("a"), Some(&(3000 + 300 - 100)));
+        assert_eq!(split.get("b"), Some(&(1000 + 100 - 100)));
+    }
+}
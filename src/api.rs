@@ -0,0 +1,732 @@
+//! JSON API covering what the web pages can do, described by the OpenAPI document served at
+//! `/api/openapi.json`
+
+use std::collections::{BTreeSet, HashMap};
+
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::{
+    approval, audit, auth, history, ofd, parse_qr, rules,
+    server::{self, ReceiptItemView, ReceiptView, Split, SplitError, State, Submitted},
+    transaction_paths, ListItem, Transaction, TransactionMeta,
+};
+
+type AxumState = axum::extract::State<State>;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    /// Machine-readable error kind
+    #[schema(example = "already_paid")]
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl ToString) -> Self {
+        Self {
+            status,
+            code,
+            message: message.to_string(),
+        }
+    }
+    pub(crate) fn bad_request(message: impl ToString) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request", message)
+    }
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+impl From<ofd::Error> for ApiError {
+    fn from(err: ofd::Error) -> Self {
+        match err {
+            ofd::Error::MissingData(_) => Self::new(StatusCode::BAD_REQUEST, "invalid_qr", err),
+            // no provider even tried, so the code isn't something we can fetch
+            ofd::Error::AllFailed(ref errors) if errors.is_empty() => {
+                Self::new(StatusCode::BAD_REQUEST, "invalid_qr", err)
+            }
+            ofd::Error::Redirect(_) => {
+                Self::new(StatusCode::UNAUTHORIZED, "provider_auth_required", err)
+            }
+            err => Self::new(StatusCode::BAD_GATEWAY, "fetch_failed", err),
+        }
+    }
+}
+
+impl From<rules::Ignored> for ApiError {
+    fn from(err: rules::Ignored) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, "ignored", err)
+    }
+}
+
+impl From<SplitError> for ApiError {
+    fn from(err: SplitError) -> Self {
+        match err {
+            SplitError::MissingReceipt => {
+                Self::new(StatusCode::NOT_FOUND, "receipt_not_found", err)
+            }
+            SplitError::InvalidReceipt => {
+                Self::new(StatusCode::INTERNAL_SERVER_ERROR, "invalid_receipt", err)
+            }
+            SplitError::InvalidPaymentType => Self::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_payment_type",
+                err,
+            ),
+            SplitError::AlreadyPaid => Self::new(StatusCode::CONFLICT, "already_paid", err),
+            SplitError::Ignored(_) => Self::new(StatusCode::UNPROCESSABLE_ENTITY, "ignored", err),
+        }
+    }
+}
+
+fn check_user(state: &State, username: &str) -> Result<(), ApiError> {
+    if state.config.usernames.contains(username) {
+        Ok(())
+    } else {
+        Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "unknown_user",
+            format!("unknown user {username}"),
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ReceiptQuery {
+    /// Contents of the receipt QR code or a link to the receipt
+    q: String,
+    /// Who is adding the receipt, used for picking the provider account
+    username: Option<String>,
+}
+
+/// Fetch a receipt by its QR code
+#[utoipa::path(
+    get,
+    path = "/api/receipt",
+    params(ReceiptQuery),
+    responses(
+        (status = 200, body = ReceiptView),
+        (status = 400, body = ApiError, description = "Not a receipt QR code"),
+        (status = 401, body = ApiError, description = "A provider needs authorization"),
+        (status = 403, body = ApiError, description = "Fetching on behalf of another user"),
+        (status = 422, body = ApiError, description = "Ignored by a receipt rule or unknown payment type"),
+        (status = 502, body = ApiError, description = "No provider returned the receipt"),
+    )
+)]
+pub async fn receipt(
+    axum::extract::State(state): AxumState,
+    identity: auth::Identity,
+    axum::extract::Query(q): axum::extract::Query<ReceiptQuery>,
+) -> Result<Json<ReceiptView>, ApiError> {
+    let mut rec = parse_qr(&q.q).await;
+    let username = q.username.as_ref().or(identity.username.as_ref());
+    if let Some(username) = username {
+        check_user(&state, username)?;
+        if !identity.can_act_as(username) {
+            return Err(auth::forbidden());
+        }
+        let _ = rec.set::<ofd::custom::Username>(username.clone());
+    }
+    rules::apply(&state.config.rules, &mut rec).await?;
+    let doc = ofd::fetch(&state, rec).await?;
+    server::receipt_view(&state, doc.data(), username.map_or("", String::as_str))
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// What the receipt rules do with a QR code, without fetching the receipt
+#[utoipa::path(
+    get,
+    path = "/api/rules/check",
+    params(ReceiptQuery),
+    responses(
+        (status = 200, body = Outcome),
+        (status = 400, body = ApiError, description = "Unknown user"),
+        (status = 403, body = ApiError, description = "Checking on behalf of another user"),
+    )
+)]
+pub async fn rules_check(
+    axum::extract::State(state): AxumState,
+    identity: auth::Identity,
+    axum::extract::Query(q): axum::extract::Query<ReceiptQuery>,
+) -> Result<Json<rules::Outcome>, ApiError> {
+    let mut rec = parse_qr(&q.q).await;
+    if let Some(username) = q.username.as_ref().or(identity.username.as_ref()) {
+        check_user(&state, username)?;
+        if !identity.can_act_as(username) {
+            return Err(auth::forbidden());
+        }
+        let _ = rec.set::<ofd::custom::Username>(username.clone());
+    }
+    Ok(Json(rules::evaluate(&state.config.rules, &rec)))
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct SplitRequest {
+    r#fn: String,
+    i: u32,
+    /// Who paid for the receipt
+    username: String,
+    /// Indices of the items each user is paying for. If empty, the receipt rules may split the
+    /// receipt
+    #[serde(default)]
+    paid: HashMap<String, BTreeSet<usize>>,
+    /// Id of the receipt this is a refund for
+    refund_of: Option<String>,
+    /// Same as the `Idempotency-Key` header
+    idempotency_key: Option<String>,
+    /// Add the split even if the receipt was already paid
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SplitResponse {
+    /// Balances after the split, in kopecks
+    balance: HashMap<String, i64>,
+    /// Shopping list items that were bought
+    removed: Vec<String>,
+    /// Approval id if the split waits for the users it charges to confirm it, the balance doesn't
+    /// include it yet
+    pending: Option<String>,
+}
+
+/// Split a receipt fetched with `/api/receipt`
+#[utoipa::path(
+    post,
+    path = "/api/receipt/split",
+    request_body = SplitRequest,
+    params(("Idempotency-Key" = Option<String>, Header, description = "A split with the same key from the same user is only added once within 24 hours")),
+    responses(
+        (status = 200, body = SplitResponse),
+        (status = 202, body = SplitResponse, description = "Waiting for confirmation"),
+        (status = 400, body = ApiError, description = "Invalid request or unknown user"),
+        (status = 403, body = ApiError, description = "Splitting on behalf of another user"),
+        (status = 404, body = ApiError, description = "The receipt wasn't fetched"),
+        (status = 409, body = ApiError, description = "The receipt was already paid"),
+        (status = 422, body = ApiError, description = "Ignored by a receipt rule"),
+    )
+)]
+pub async fn split(
+    axum::extract::State(state): AxumState,
+    identity: auth::Identity,
+    audit: audit::Context,
+    headers: axum::http::HeaderMap,
+    Json(req): Json<SplitRequest>,
+) -> Result<(StatusCode, Json<SplitResponse>), ApiError> {
+    if req.r#fn.is_empty() || !req.r#fn.bytes().all(|x| x.is_ascii_digit()) {
+        return Err(ApiError::bad_request("invalid fn"));
+    }
+    check_user(&state, &req.username)?;
+    if !identity.can_act_as(&req.username) {
+        return Err(auth::forbidden());
+    }
+    for user in req.paid.keys() {
+        check_user(&state, user)?;
+    }
+    let params = serde_json::to_value(&req).unwrap_or_default();
+    let idempotency_key = headers
+        .get("idempotency-key")
+        .and_then(|x| x.to_str().ok())
+        .map(str::to_owned)
+        .or(req.idempotency_key)
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty());
+    let (r#fn, i) = (req.r#fn.clone(), req.i);
+    let res = server::split_receipt(
+        &state,
+        Split {
+            r#fn: req.r#fn,
+            i: req.i,
+            author: identity.username.unwrap_or_else(|| req.username.clone()),
+            username: req.username,
+            paid: req.paid,
+            refund_of: req.refund_of,
+            idempotency_key,
+            force: req.force,
+        },
+    )
+    .await;
+    audit
+        .record(
+            &state,
+            params,
+            res.as_ref()
+                .map(|x| x.ids(&r#fn, i))
+                .map_err(ToString::to_string),
+        )
+        .await;
+    let Submitted {
+        balance,
+        removed,
+        pending,
+        ..
+    } = res?;
+    let status = if pending.is_some() {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    Ok((
+        status,
+        Json(SplitResponse {
+            balance,
+            removed,
+            pending,
+        }),
+    ))
+}
+
+/// The shopping list
+#[utoipa::path(get, path = "/api/list", responses((status = 200, body = [ListItem])))]
+pub async fn list(axum::extract::State(state): AxumState) -> Json<Vec<ListItem>> {
+    Json(state.list.read().await.clone())
+}
+
+/// Add an item to the shopping list, returns the updated list
+#[utoipa::path(
+    post,
+    path = "/api/list",
+    request_body = ListItem,
+    responses(
+        (status = 200, body = [ListItem]),
+        (status = 400, body = ApiError),
+    )
+)]
+pub async fn list_add(
+    axum::extract::State(state): AxumState,
+    audit: audit::Context,
+    Json(item): Json<ListItem>,
+) -> Result<Json<Vec<ListItem>>, ApiError> {
+    if item.name.is_empty() {
+        return Err(ApiError::bad_request("empty name"));
+    }
+    if !item.amount.is_finite() {
+        return Err(ApiError::bad_request("invalid amount"));
+    }
+    let list = server::list_add(&state, &item.name, item.amount).await;
+    audit
+        .record(&state, &item, Ok(vec![item.name.clone()]))
+        .await;
+    Ok(Json(list))
+}
+
+/// Remove an item from the shopping list
+#[utoipa::path(
+    delete,
+    path = "/api/list/{name}",
+    params(("name" = String, Path, description = "Name of the item")),
+    responses(
+        (status = 204),
+        (status = 404, body = ApiError),
+    )
+)]
+pub async fn list_remove(
+    axum::extract::State(state): AxumState,
+    audit: audit::Context,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if server::list_remove(&state, &name).await {
+        let params = serde_json::json!({ "name": name });
+        audit.record(&state, params, Ok(vec![name])).await;
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("{name} isn't in the list"),
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct TransactionsQuery {
+    /// Only return the transactions changing this user's balance
+    user: Option<String>,
+    /// 50 by default
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TransactionView {
+    id: String,
+    date: chrono::DateTime<Utc>,
+    /// In kopecks
+    balance_changes: HashMap<String, i64>,
+    /// Id of the split receipt
+    receipt: Option<String>,
+    comment: Option<String>,
+    /// Added by the receipt rules
+    tags: BTreeSet<String>,
+}
+
+impl TransactionView {
+    pub fn new(id: String, tr: Transaction) -> Self {
+        let (receipt, comment) = match tr.meta {
+            Some(TransactionMeta::Receipt { r#fn, i, .. }) => (Some(format!("{fn}_{i:07}")), None),
+            Some(TransactionMeta::Comment(x) | TransactionMeta::Comment2(x, _)) => (None, Some(x)),
+            None => (None, None),
+        };
+        Self {
+            id,
+            date: tr.date,
+            balance_changes: tr.balance_changes,
+            receipt,
+            comment,
+            tags: tr.tags,
+        }
+    }
+}
+
+/// Transactions, newest first
+#[utoipa::path(
+    get,
+    path = "/api/transactions",
+    params(TransactionsQuery),
+    responses(
+        (status = 200, body = [TransactionView]),
+        (status = 500, body = ApiError),
+    )
+)]
+pub async fn transactions(
+    axum::extract::State(state): AxumState,
+    axum::extract::Query(q): axum::extract::Query<TransactionsQuery>,
+) -> Result<Json<Vec<TransactionView>>, ApiError> {
+    let internal = |err: std::io::Error| {
+        log::error!("failed to read transactions: {err}");
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", err)
+    };
+    let limit = q.limit.unwrap_or(50).min(1000);
+    let mut ret = vec![];
+    let mut skipped = 0;
+    for path in transaction_paths(&state.config)
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .rev()
+    {
+        if ret.len() >= limit {
+            break;
+        }
+        let data = tokio::fs::read(&path).await.map_err(internal)?;
+        let tr = match serde_json::from_slice::<Transaction>(&data) {
+            Ok(tr) => tr,
+            Err(err) => {
+                log::error!("invalid transaction {path:?}: {err}");
+                continue;
+            }
+        };
+        if q.user
+            .as_ref()
+            .is_some_and(|user| !tr.balance_changes.contains_key(user))
+        {
+            continue;
+        }
+        if skipped < q.offset {
+            skipped += 1;
+            continue;
+        }
+        let id = path
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default()
+            .to_owned();
+        ret.push(TransactionView::new(id, tr));
+    }
+    Ok(Json(ret))
+}
+
+impl From<approval::Error> for ApiError {
+    fn from(err: approval::Error) -> Self {
+        match err {
+            approval::Error::NotFound => Self::new(StatusCode::NOT_FOUND, "not_found", err),
+            approval::Error::NotWaiting => Self::new(StatusCode::CONFLICT, "not_waiting", err),
+            approval::Error::AlreadyRejected => {
+                Self::new(StatusCode::CONFLICT, "already_rejected", err)
+            }
+            approval::Error::Io(_) => {
+                log::error!("failed to save approval: {err}");
+                Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", err)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApprovalView {
+    id: String,
+    /// Who added the transaction
+    author: String,
+    created: chrono::DateTime<Utc>,
+    transaction: TransactionView,
+    /// Users that haven't confirmed yet
+    waiting: Vec<String>,
+    /// `pending` or `rejected`
+    status: &'static str,
+    /// Who rejected the transaction, empty if it couldn't be added after being confirmed
+    rejected_by: Option<String>,
+    reason: Option<String>,
+}
+
+impl ApprovalView {
+    fn new(approval: approval::Approval) -> Self {
+        let (status, rejected_by, reason) = match approval.status {
+            approval::Status::Pending => ("pending", None, None),
+            approval::Status::Rejected { by, reason, .. } => ("rejected", Some(by), Some(reason)),
+        };
+        Self {
+            transaction: TransactionView::new(approval.id.clone(), approval.transaction),
+            id: approval.id,
+            author: approval.author,
+            created: approval.created,
+            waiting: approval.waiting.into_iter().collect(),
+            status,
+            rejected_by,
+            reason,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ApprovalsQuery {
+    /// Only return the transactions added by or changing this user's balance
+    user: Option<String>,
+}
+
+/// Transactions waiting for confirmation, and the rejected ones
+#[utoipa::path(
+    get,
+    path = "/api/approvals",
+    params(ApprovalsQuery),
+    responses((status = 200, body = [ApprovalView]))
+)]
+pub async fn approvals(
+    axum::extract::State(state): AxumState,
+    axum::extract::Query(q): axum::extract::Query<ApprovalsQuery>,
+) -> Json<Vec<ApprovalView>> {
+    let mut ret = state
+        .approvals
+        .iter()
+        .filter(|x| {
+            q.user.as_ref().is_none_or(|user| {
+                &x.author == user || x.transaction.balance_changes.contains_key(user)
+            })
+        })
+        .map(|x| x.value().clone())
+        .collect::<Vec<_>>();
+    ret.sort_by_key(|x| std::cmp::Reverse(x.created));
+    Json(ret.into_iter().map(ApprovalView::new).collect())
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ActAs {
+    /// The user confirming or rejecting, the logged in user by default
+    username: Option<String>,
+}
+
+fn acting_user(state: &State, identity: &auth::Identity, q: ActAs) -> Result<String, ApiError> {
+    let user = q
+        .username
+        .or_else(|| identity.username.clone())
+        .ok_or_else(|| ApiError::bad_request("missing username"))?;
+    check_user(state, &user)?;
+    if !identity.can_act_as(&user) {
+        return Err(auth::forbidden());
+    }
+    Ok(user)
+}
+
+/// Confirm a transaction charging you, it's added once everyone confirms it
+#[utoipa::path(
+    post,
+    path = "/api/approvals/{id}/confirm",
+    params(("id" = String, Path, description = "Approval id"), ActAs),
+    responses(
+        (status = 204),
+        (status = 404, body = ApiError),
+        (status = 409, body = ApiError, description = "Not waiting for this user or already rejected"),
+    )
+)]
+pub async fn approval_confirm(
+    axum::extract::State(state): AxumState,
+    identity: auth::Identity,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    audit: audit::Context,
+    axum::extract::Query(q): axum::extract::Query<ActAs>,
+) -> Result<StatusCode, ApiError> {
+    let user = acting_user(&state, &identity, q)?;
+    let res = approval::confirm(&state, &id, &user).await;
+    let params = serde_json::json!({ "username": user });
+    let ids = res
+        .as_ref()
+        .map(|()| vec![id.clone()])
+        .map_err(ToString::to_string);
+    audit.record(&state, params, ids).await;
+    res?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RejectRequest {
+    reason: String,
+}
+
+/// Reject a transaction charging you
+#[utoipa::path(
+    post,
+    path = "/api/approvals/{id}/reject",
+    params(("id" = String, Path, description = "Approval id"), ActAs),
+    request_body = RejectRequest,
+    responses(
+        (status = 204),
+        (status = 404, body = ApiError),
+        (status = 409, body = ApiError, description = "Not waiting for this user or already rejected"),
+    )
+)]
+pub async fn approval_reject(
+    axum::extract::State(state): AxumState,
+    identity: auth::Identity,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    audit: audit::Context,
+    axum::extract::Query(q): axum::extract::Query<ActAs>,
+    Json(req): Json<RejectRequest>,
+) -> Result<StatusCode, ApiError> {
+    let user = acting_user(&state, &identity, q)?;
+    let res = approval::reject(&state, &id, &user, &req.reason).await;
+    let params = serde_json::json!({ "username": user, "reason": req.reason });
+    let ids = res
+        .as_ref()
+        .map(|()| vec![id.clone()])
+        .map_err(ToString::to_string);
+    audit.record(&state, params, ids).await;
+    res?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Confirmed balances, in kopecks
+#[utoipa::path(
+    get,
+    path = "/api/balance",
+    responses((status = 200, body = HashMap<String, i64>))
+)]
+pub async fn balance(axum::extract::State(state): AxumState) -> Json<HashMap<String, i64>> {
+    Json(state.balance.read().await.clone())
+}
+
+/// Balances at the end of every day, week or month
+#[utoipa::path(
+    get,
+    path = "/api/balance/history",
+    params(history::Query),
+    responses(
+        (status = 200, body = Vec<history::Point>),
+        (status = 400, body = ApiError, description = "Too many points requested"),
+    )
+)]
+pub async fn balance_history(
+    axum::extract::State(state): AxumState,
+    axum::extract::Query(q): axum::extract::Query<history::Query>,
+) -> Result<Json<Vec<history::Point>>, ApiError> {
+    history::load(&state, &q).await.map(Json)
+}
+
+/// Balance changes waiting for confirmation, in kopecks
+#[utoipa::path(
+    get,
+    path = "/api/balance/pending",
+    responses((status = 200, body = HashMap<String, i64>))
+)]
+pub async fn pending_balance(axum::extract::State(state): AxumState) -> Json<HashMap<String, i64>> {
+    Json(approval::pending_balance(&state))
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        receipt,
+        split,
+        list,
+        list_add,
+        list_remove,
+        transactions,
+        approvals,
+        approval_confirm,
+        approval_reject,
+        pending_balance,
+        rules_check,
+        balance,
+        balance_history,
+        crate::server::api_pay,
+        crate::events::stream,
+    ),
+    components(schemas(
+        ApiError,
+        ReceiptView,
+        ReceiptItemView,
+        SplitRequest,
+        SplitResponse,
+        ListItem,
+        TransactionView,
+        ApprovalView,
+        RejectRequest,
+        rules::Outcome,
+        history::Point,
+        history::Bucket,
+    ))
+)]
+pub struct ApiDoc;
+
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{http::StatusCode, response::IntoResponse};
+    use utoipa::OpenApi;
+
+    use super::{ApiDoc, ApiError};
+    use crate::{ofd, server::SplitError};
+
+    #[test]
+    fn test() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for path in [
+            "/api/receipt",
+            "/api/receipt/split",
+            "/api/list",
+            "/api/list/{name}",
+            "/api/transactions",
+        ] {
+            assert!(doc["paths"].get(path).is_some(), "{path} is missing");
+        }
+        assert!(doc["components"]["schemas"]["ReceiptView"]["properties"]
+            .get("fn")
+            .is_some());
+        let err = ApiError::from(SplitError::AlreadyPaid);
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            serde_json::json!({
+                "code": "already_paid",
+                "message": "receipt already paid",
+            })
+        );
+        assert_eq!(err.into_response().status(), StatusCode::CONFLICT);
+        let err = ApiError::from(ofd::Error::Redirect("auth".to_owned()));
+        assert_eq!(err.into_response().status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,340 @@
+//! Transactions that charge someone other than their author, kept in `data/approvals` until the
+//! charged users confirm them (or `auto_confirm_days` pass). Only used when `approval` is set in
+//! the config, otherwise every transaction is added right away.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    add_transaction, events,
+    server::{self, State},
+    Added, Config, Rejected, Transaction, TransactionMeta,
+};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ApprovalConfig {
+    /// Confirm automatically after this many days, never by default
+    #[serde(default)]
+    pub auto_confirm_days: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Status {
+    Pending,
+    Rejected {
+        /// Empty if the transaction couldn't be added after everyone confirmed it
+        by: String,
+        reason: String,
+        #[serde(with = "crate::iso8601")]
+        at: DateTime<Utc>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Approval {
+    pub id: String,
+    /// Who added the transaction
+    pub author: String,
+    pub transaction: Transaction,
+    /// Add the receipt even if it was already paid
+    #[serde(default)]
+    force: bool,
+    #[serde(with = "crate::iso8601")]
+    pub created: DateTime<Utc>,
+    /// Users that haven't confirmed yet
+    pub waiting: BTreeSet<String>,
+    #[serde(flatten)]
+    pub status: Status,
+}
+
+impl Approval {
+    pub fn is_pending(&self) -> bool {
+        matches!(self.status, Status::Pending)
+    }
+    fn receipt_id(&self) -> Option<String> {
+        match &self.transaction.meta {
+            Some(TransactionMeta::Receipt { r#fn, i, .. }) => Some(format!("{fn}_{i:07}")),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no such transaction")]
+    NotFound,
+    #[error("not waiting for this user")]
+    NotWaiting,
+    #[error("already rejected")]
+    AlreadyRejected,
+    #[error("{0}")]
+    Io(
+        #[source]
+        #[from]
+        io::Error,
+    ),
+}
+
+async fn save(config: &Config, approval: &Approval) -> io::Result<()> {
+    let dir = config.data_path("approvals");
+    tokio::fs::create_dir_all(&dir).await?;
+    let path1 = dir.join(format!("{}.json.tmp", approval.id));
+    let path2 = dir.join(format!("{}.json", approval.id));
+    tokio::fs::write(&path1, serde_json::to_vec(approval)?).await?;
+    tokio::fs::rename(path1, path2).await
+}
+
+async fn remove(config: &Config, id: &str) -> io::Result<()> {
+    match tokio::fs::remove_file(config.data_path(format!("approvals/{id}.json"))).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+pub async fn load(config: &Config) -> io::Result<Vec<Approval>> {
+    let mut ret = vec![];
+    let Ok(mut dir) = tokio::fs::read_dir(config.data_path("approvals")).await else {
+        return Ok(ret);
+    };
+    while let Some(file) = dir.next_entry().await? {
+        let path = file.path();
+        if path.extension().and_then(|x| x.to_str()) != Some("json") {
+            continue;
+        }
+        let data = tokio::fs::read(&path).await?;
+        match serde_json::from_slice::<Approval>(&data) {
+            Ok(x) => ret.push(x),
+            Err(err) => log::error!("invalid approval {path:?}: {err}"),
+        }
+    }
+    Ok(ret)
+}
+
+/// Users other than the author that `tr` takes money from
+fn charged(tr: &Transaction, author: &str) -> BTreeSet<String> {
+    tr.balance_changes
+        .iter()
+        .filter(|(user, change)| **change < 0 && *user != author)
+        .map(|(user, _)| user.clone())
+        .collect()
+}
+
+/// Sum of the changes still waiting for confirmation
+pub fn pending_balance(state: &State) -> HashMap<String, i64> {
+    let mut ret = HashMap::<String, i64>::new();
+    for approval in state.approvals.iter().filter(|x| x.is_pending()) {
+        for (user, change) in &approval.transaction.balance_changes {
+            *ret.entry(user.clone()).or_default() += change;
+        }
+    }
+    ret.retain(|_, v| *v != 0);
+    ret
+}
+
+/// Add `tr`, or hold it until the users it charges confirm it. Returns the confirmed balance and
+/// the approval id if the transaction is held.
+pub async fn add(
+    state: &State,
+    tr: Transaction,
+    author: &str,
+    force: bool,
+) -> Result<Added, Rejected> {
+    let waiting = charged(&tr, author);
+    if state.config.approval.is_none() || waiting.is_empty() {
+        return add_transaction(state, tr, force).await;
+    }
+    // checked and inserted under the lock, so a repeated submit can't be held twice
+    let _lock = state.approvals_lock.lock().await;
+    let balance = state.balance.read().await.clone();
+    if let Some(key) = &tr.idempotency_key {
+        if let Some(x) = state
+            .idempotency
+            .get(key)
+            .filter(|x| !x.is_expired(Utc::now()))
+        {
+            return Err(Rejected::Replay(x.balance.clone()));
+        }
+        if state
+            .approvals
+            .iter()
+            .any(|x| x.transaction.idempotency_key.as_ref() == Some(key))
+        {
+            return Err(Rejected::Replay(balance));
+        }
+    }
+    let approval = Approval {
+        id: uuid::Uuid::new_v4().to_string(),
+        author: author.to_owned(),
+        force,
+        transaction: tr,
+        created: Utc::now(),
+        waiting,
+        status: Status::Pending,
+    };
+    if !approval.force
+        && approval.receipt_id().is_some_and(|id| {
+            state.paid_receipts.contains(&id)
+                || state
+                    .approvals
+                    .iter()
+                    .any(|x| x.is_pending() && x.receipt_id().as_ref() == Some(&id))
+        })
+    {
+        return Err(Rejected::AlreadyPaid);
+    }
+    if let Err(err) = save(&state.config, &approval).await {
+        log::error!("failed to save approval {}: {err}", approval.id);
+    }
+    let id = approval.id.clone();
+    state.approvals.insert(id.clone(), approval);
+    state.events.publish(events::Event::Approvals);
+    Ok(Added {
+        balance,
+        id: None,
+        pending: Some(id),
+    })
+}
+
+/// Check and change the pending approval `id` in place, returning a copy to save
+fn update(
+    state: &State,
+    id: &str,
+    f: impl FnOnce(&mut Approval) -> Result<(), Error>,
+) -> Result<Approval, Error> {
+    let mut approval = state.approvals.get_mut(id).ok_or(Error::NotFound)?;
+    if !approval.is_pending() {
+        return Err(Error::AlreadyRejected);
+    }
+    f(&mut approval)?;
+    Ok(approval.clone())
+}
+
+/// Add the transaction once everyone has confirmed it. It's taken out of `state.approvals` first,
+/// so it can't be added twice.
+async fn finish(state: &State, id: &str) -> io::Result<()> {
+    let Some((_, mut approval)) = state.approvals.remove(id) else {
+        return Ok(());
+    };
+    match add_transaction(state, approval.transaction.clone(), approval.force).await {
+        Ok(Added { id: Some(_), .. }) => {
+            if let Some(TransactionMeta::Receipt { r#fn, i, .. }) = &approval.transaction.meta {
+                match server::load_receipt(state, r#fn, *i).await {
+                    Ok(doc) => {
+                        server::receipt_paid(state, doc.data()).await;
+                    }
+                    Err(err) => log::error!("failed to apply receipt {fn}_{i:07}: {err}"),
+                }
+            }
+            remove(&state.config, id).await
+        }
+        Ok(_) | Err(Rejected::Replay(_)) => remove(&state.config, id).await,
+        Err(Rejected::AlreadyPaid) => {
+            approval.status = Status::Rejected {
+                by: String::new(),
+                reason: "receipt already paid".to_owned(),
+                at: Utc::now(),
+            };
+            let ret = save(&state.config, &approval).await;
+            state.approvals.insert(approval.id.clone(), approval);
+            ret
+        }
+    }
+}
+
+pub async fn confirm(state: &State, id: &str, user: &str) -> Result<(), Error> {
+    let _lock = state.approvals_lock.lock().await;
+    let approval = update(state, id, |x| {
+        if x.waiting.remove(user) {
+            Ok(())
+        } else {
+            Err(Error::NotWaiting)
+        }
+    })?;
+    log::info!("{user} confirmed {id}");
+    if approval.waiting.is_empty() {
+        finish(state, id).await?;
+    } else {
+        save(&state.config, &approval).await?;
+    }
+    state.events.publish(events::Event::Approvals);
+    Ok(())
+}
+
+pub async fn reject(state: &State, id: &str, user: &str, reason: &str) -> Result<(), Error> {
+    let _lock = state.approvals_lock.lock().await;
+    let approval = update(state, id, |x| {
+        if !x.waiting.contains(user) {
+            return Err(Error::NotWaiting);
+        }
+        x.status = Status::Rejected {
+            by: user.to_owned(),
+            reason: reason.to_owned(),
+            at: Utc::now(),
+        };
+        Ok(())
+    })?;
+    log::info!("{user} rejected {id}: {reason}");
+    save(&state.config, &approval).await?;
+    state.events.publish(events::Event::Approvals);
+    Ok(())
+}
+
+/// Confirm everything that has waited for longer than `auto_confirm_days`
+pub async fn auto_confirm(state: &State) {
+    let Some(days) = state
+        .config
+        .approval
+        .as_ref()
+        .and_then(|x| x.auto_confirm_days)
+    else {
+        return;
+    };
+    let deadline = Utc::now() - chrono::Days::new(days.into());
+    let due = state
+        .approvals
+        .iter()
+        .filter(|x| x.is_pending() && x.created <= deadline)
+        .map(|x| x.id.clone())
+        .collect::<Vec<_>>();
+    for id in due {
+        let _lock = state.approvals_lock.lock().await;
+        // rejected in the meantime
+        if !state.approvals.get(&id).is_some_and(|x| x.is_pending()) {
+            continue;
+        }
+        log::info!("auto-confirming {id}");
+        if let Err(err) = finish(state, &id).await {
+            log::error!("failed to auto-confirm {id}: {err}");
+        }
+        state.events.publish(events::Event::Approvals);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::charged;
+    use crate::Transaction;
+
+    #[test]
+    fn test() {
+        let mut tr = Transaction::new(None);
+        tr.pay("b", "a", 100);
+        tr.pay("c", "a", 50);
+        tr.finalize();
+        assert_eq!(
+            charged(&tr, "a").into_iter().collect::<Vec<_>>(),
+            ["b", "c"]
+        );
+        // paying back your own debt doesn't need anyone's confirmation
+        let mut tr = Transaction::new(None);
+        tr.pay("b", "a", 100);
+        tr.finalize();
+        assert!(charged(&tr, "b").is_empty());
+    }
+}
@@ -0,0 +1,293 @@
+//! Append-only log of the changes users make, one JSON object per line in `data/audit.jsonl`.
+//! Unlike the transaction files it also records who made the change, from where, and what the
+//! request contained. Admins can browse it at `/admin/audit`.
+
+use std::net::SocketAddr;
+
+use axum::extract::{ConnectInfo, FromRequestParts, MatchedPath};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{auth, i18n, server::State, Config};
+
+/// Form and JSON fields that are never written to the log
+const SECRETS: &[&str] = &["password", "current", "code", "token"];
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Client {
+    /// The peer address, which is the reverse proxy if there is one
+    pub addr: Option<String>,
+    /// `X-Forwarded-For` as sent by the client or the reverse proxy, not verified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded_for: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    /// The logged in user, or the one picked on the main page when auth is disabled
+    pub actor: Option<String>,
+    /// Method and route, like `POST /api/pay` or `DELETE /api/list/:name`
+    pub endpoint: String,
+    pub client: Client,
+    /// The request, without passwords and codes
+    pub params: serde_json::Value,
+    /// Transactions, approvals, receipts and list items the request created or changed
+    #[serde(default)]
+    pub ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Serializes appends to the log file
+#[derive(Debug, Default)]
+pub struct Log(tokio::sync::Mutex<()>);
+
+/// Who is making the request, extracted before the handler runs
+#[derive(Clone, Debug)]
+pub struct Context {
+    actor: Option<String>,
+    endpoint: String,
+    client: Client,
+}
+
+#[axum::async_trait]
+impl FromRequestParts<State> for Context {
+    type Rejection = std::convert::Infallible;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _state: &State,
+    ) -> Result<Self, Self::Rejection> {
+        let header = |name: axum::http::HeaderName| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_owned)
+        };
+        let cookies = axum_extra::extract::CookieJar::from_headers(&parts.headers);
+        let identity = parts
+            .extensions
+            .get::<auth::Identity>()
+            .cloned()
+            .unwrap_or_default();
+        Ok(Self {
+            actor: identity.username_or_cookie(&cookies),
+            endpoint: format!(
+                "{} {}",
+                parts.method,
+                parts
+                    .extensions
+                    .get::<MatchedPath>()
+                    .map_or(parts.uri.path(), MatchedPath::as_str)
+            ),
+            client: Client {
+                addr: parts
+                    .extensions
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|x| x.0.to_string()),
+                forwarded_for: header(axum::http::HeaderName::from_static("x-forwarded-for")),
+                user_agent: header(axum::http::header::USER_AGENT),
+            },
+        })
+    }
+}
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (k, v) in map {
+                if SECRETS.contains(&k.as_str()) {
+                    *v = serde_json::Value::String("***".to_owned());
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+impl Context {
+    /// Append the request to the log, `result` is either the affected ids or an error
+    pub async fn record(
+        self,
+        state: &State,
+        params: impl Serialize,
+        result: Result<Vec<String>, String>,
+    ) {
+        let mut params = serde_json::to_value(params).unwrap_or_default();
+        redact(&mut params);
+        let (ids, error) = match result {
+            Ok(ids) => (ids, None),
+            Err(err) => (vec![], Some(err)),
+        };
+        let entry = Entry {
+            time: Utc::now(),
+            actor: self.actor,
+            endpoint: self.endpoint,
+            client: self.client,
+            params,
+            ids,
+            error,
+        };
+        if let Err(err) = append(state, &entry).await {
+            log::error!("failed to write audit log: {err} ({entry:?})");
+        }
+    }
+}
+
+async fn append(state: &State, entry: &Entry) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let _lock = state.audit.0.lock().await;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state.config.data_path("audit.jsonl"))
+        .await?;
+    file.write_all(&line).await?;
+    file.flush().await
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Filter {
+    actor: String,
+    endpoint: String,
+    /// `YYYY-MM-DD`, inclusive
+    from: String,
+    /// `YYYY-MM-DD`, inclusive
+    to: String,
+    /// Searched for in the parameters and ids
+    q: String,
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        let date = |x: &str| NaiveDate::parse_from_str(x, "%Y-%m-%d").ok();
+        let day = entry.time.date_naive();
+        (self.actor.is_empty() || entry.actor.as_deref() == Some(self.actor.as_str()))
+            && (self.endpoint.is_empty() || entry.endpoint == self.endpoint)
+            && date(&self.from).is_none_or(|x| day >= x)
+            && date(&self.to).is_none_or(|x| day <= x)
+            && (self.q.is_empty()
+                || entry.ids.iter().any(|x| x.contains(&self.q))
+                || entry.params.to_string().contains(&self.q))
+    }
+}
+
+const LIMIT: usize = 500;
+
+/// Newest entries first, and every endpoint in the log for the filter
+async fn read(config: &Config, filter: &Filter) -> std::io::Result<(Vec<Entry>, Vec<String>)> {
+    let data = match tokio::fs::read_to_string(config.data_path("audit.jsonl")).await {
+        Ok(x) => x,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let mut endpoints = std::collections::BTreeSet::new();
+    let mut ret = vec![];
+    for line in data.lines().rev() {
+        let entry = match serde_json::from_str::<Entry>(line) {
+            Ok(x) => x,
+            Err(err) => {
+                log::warn!("invalid audit log entry: {err}");
+                continue;
+            }
+        };
+        endpoints.insert(entry.endpoint.clone());
+        if ret.len() < LIMIT && filter.matches(&entry) {
+            ret.push(entry);
+        }
+    }
+    Ok((ret, endpoints.into_iter().collect()))
+}
+
+pub async fn page(
+    axum::extract::State(state): axum::extract::State<State>,
+    lang: i18n::Lang,
+    axum::extract::Query(filter): axum::extract::Query<Filter>,
+) -> axum::response::Html<String> {
+    let (entries, endpoints) = match read(&state.config, &filter).await {
+        Ok(x) => x,
+        Err(err) => return axum::response::Html::from(format!("Error: {err}")),
+    };
+    let entries = entries
+        .into_iter()
+        .map(|x| {
+            liquid::object!({
+                "time": x.time.format("%Y-%m-%d %H:%M:%S").to_string(),
+                "actor": x.actor.unwrap_or_default(),
+                "endpoint": x.endpoint,
+                "addr": x.client.addr.unwrap_or_default(),
+                "forwarded_for": x.client.forwarded_for.unwrap_or_default(),
+                "user_agent": x.client.user_agent.unwrap_or_default(),
+                "params": x.params.to_string(),
+                "ids": x.ids,
+                "error": x.error.unwrap_or_default(),
+            })
+        })
+        .collect::<Vec<_>>();
+    axum::response::Html::from(
+        state
+            .audit_t
+            .get()
+            .await
+            .render(&liquid::object!({
+                "t": &*i18n::catalog(&state, lang).await,
+                "usernames": &state.config.usernames,
+                "endpoints": endpoints,
+                "filter": liquid::to_object(&filter).unwrap_or_default(),
+                "entries": entries,
+                "limit": LIMIT,
+            }))
+            .unwrap_or_else(|err| format!("Error: {err}")),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::{redact, Client, Entry, Filter};
+
+    #[test]
+    fn test() {
+        let mut params = serde_json::json!({
+            "action": "password",
+            "password": "hunter22",
+            "nested": [{ "code": "1234", "name": "x" }],
+        });
+        redact(&mut params);
+        assert_eq!(
+            params,
+            serde_json::json!({
+                "action": "password",
+                "password": "***",
+                "nested": [{ "code": "***", "name": "x" }],
+            })
+        );
+        let entry = Entry {
+            time: Utc.with_ymd_and_hms(2024, 3, 5, 12, 0, 0).unwrap(),
+            actor: Some("a".to_owned()),
+            endpoint: "POST /listadd".to_owned(),
+            client: Client::default(),
+            params: serde_json::json!({ "name": "milk", "amount": "2" }),
+            ids: vec!["milk".to_owned()],
+            error: None,
+        };
+        let filter = |x: serde_json::Value| serde_json::from_value::<Filter>(x).unwrap();
+        assert!(filter(serde_json::json!({})).matches(&entry));
+        assert!(filter(serde_json::json!({ "actor": "a", "from": "2024-03-05" })).matches(&entry));
+        assert!(!filter(serde_json::json!({ "actor": "b" })).matches(&entry));
+        assert!(!filter(serde_json::json!({ "to": "2024-03-04" })).matches(&entry));
+        assert!(!filter(serde_json::json!({ "endpoint": "POST /submit" })).matches(&entry));
+        assert!(filter(serde_json::json!({ "q": "milk" })).matches(&entry));
+        assert!(!filter(serde_json::json!({ "q": "bread" })).matches(&entry));
+    }
+}
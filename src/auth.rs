@@ -0,0 +1,600 @@
+//! Password logins, sessions and API tokens. Only enforced when `auth` is set in the config, without
+//! it anyone can act as anyone, like before accounts existed.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+};
+
+use axum::{http::StatusCode, response::IntoResponse};
+use axum_extra::extract::{cookie::Cookie, CookieJar};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::{api::ApiError, audit, decode_hex, i18n, server::State, Config};
+
+type AxumState = axum::extract::State<State>;
+
+const SESSION_COOKIE: &str = "session";
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuthConfig {
+    /// Users that may act on behalf of others and use the admin pages
+    #[serde(default)]
+    pub admins: BTreeSet<String>,
+    /// How long a login lasts, 30 days by default
+    #[serde(default)]
+    pub session_days: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Token {
+    pub id: String,
+    pub name: String,
+    /// SHA-256 of the token
+    hash: String,
+    #[serde(with = "crate::iso8601")]
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Account {
+    /// `pbkdf2-sha256$iterations$salt$hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(default)]
+    pub tokens: Vec<Token>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Session {
+    username: String,
+    #[serde(with = "crate::iso8601")]
+    expires: DateTime<Utc>,
+}
+
+/// Everything stored in `secret/auth.json`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Store {
+    #[serde(default)]
+    pub users: BTreeMap<String, Account>,
+    /// Session token hash -> session
+    #[serde(default)]
+    sessions: BTreeMap<String, Session>,
+}
+
+impl Store {
+    pub fn set_password(&mut self, username: &str, password: &str) {
+        self.set_password_hash(username, hash_password(password, PBKDF2_ITERATIONS));
+    }
+    fn set_password_hash(&mut self, username: &str, hash: String) {
+        self.users.entry(username.to_owned()).or_default().password = Some(hash);
+        // log out everywhere else
+        self.sessions.retain(|_, x| x.username != username);
+    }
+    /// Returns the session token
+    fn create_session(&mut self, username: &str, days: u32) -> String {
+        let now = Utc::now();
+        self.sessions.retain(|_, x| x.expires > now);
+        let token = random_token();
+        self.sessions.insert(
+            sha256_hex(&token),
+            Session {
+                username: username.to_owned(),
+                expires: now + chrono::Days::new(days.into()),
+            },
+        );
+        token
+    }
+    fn session_user(&self, token: &str) -> Option<&str> {
+        self.sessions
+            .get(&sha256_hex(token))
+            .filter(|x| x.expires > Utc::now())
+            .map(|x| x.username.as_str())
+    }
+    /// Returns the token, which is only shown once
+    pub fn create_token(&mut self, username: &str, name: &str) -> String {
+        let token = random_token();
+        self.users
+            .entry(username.to_owned())
+            .or_default()
+            .tokens
+            .push(Token {
+                id: uuid::Uuid::new_v4().to_string(),
+                name: name.to_owned(),
+                hash: sha256_hex(&token),
+                created: Utc::now(),
+            });
+        token
+    }
+    pub fn revoke_token(&mut self, username: &str, id: &str) {
+        if let Some(account) = self.users.get_mut(username) {
+            account.tokens.retain(|x| x.id != id);
+        }
+    }
+    fn token_user(&self, token: &str) -> Option<&str> {
+        let hash = sha256_hex(token);
+        self.users
+            .iter()
+            .find(|(_, account)| account.tokens.iter().any(|x| x.hash == hash))
+            .map(|(username, _)| username.as_str())
+    }
+}
+
+pub async fn load(config: &Config) -> io::Result<Store> {
+    match tokio::fs::read(config.data_path("secret/auth.json")).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Store::default()),
+        Err(err) => Err(err),
+    }
+}
+
+pub async fn save(config: &Config, store: &Store) -> io::Result<()> {
+    let path1 = config.data_path("secret/auth.json.tmp");
+    tokio::fs::write(&path1, serde_json::to_vec(store)?).await?;
+    tokio::fs::rename(path1, config.data_path("secret/auth.json")).await
+}
+
+fn sha256_hex(data: &str) -> String {
+    hex(&Sha256::digest(data.as_bytes()))
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+fn random_token() -> String {
+    let mut buf = [0u8; 32];
+    getrandom::getrandom(&mut buf).expect("no randomness source");
+    hex(&buf)
+}
+
+/// PBKDF2-HMAC-SHA256 with a single output block
+fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password, salt, iterations)
+}
+
+fn hash_password(password: &str, iterations: u32) -> String {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).expect("no randomness source");
+    let hash = pbkdf2(password.as_bytes(), &salt, iterations);
+    format!("pbkdf2-sha256${iterations}${}${}", hex(&salt), hex(&hash))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    let mut parts = hash.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(hash), None) = (
+        parts.next(),
+        parts.next().and_then(|x| x.parse::<u32>().ok()),
+        parts.next().and_then(decode_hex),
+        parts.next().and_then(decode_hex),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    pbkdf2(password.as_bytes(), &salt, iterations)
+        .ct_eq(&hash)
+        .into()
+}
+
+/// Check `username`'s password on the blocking pool with the store unlocked, hashing is slow on
+/// purpose and would hold up every other request
+async fn check_password(state: &State, username: &str, password: &str) -> bool {
+    let hash = state
+        .auth
+        .read()
+        .await
+        .users
+        .get(username)
+        .and_then(|x| x.password.clone());
+    let Some(hash) = hash else {
+        return false;
+    };
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+        .await
+        .unwrap_or(false)
+}
+
+async fn hash_password_blocking(password: &str) -> String {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || hash_password(&password, PBKDF2_ITERATIONS))
+        .await
+        .expect("password hashing panicked")
+}
+
+/// Who is making the request, put into the request extensions by [`middleware`]
+#[derive(Clone, Debug, Default)]
+pub struct Identity {
+    /// `None` when auth is disabled
+    pub username: Option<String>,
+    pub admin: bool,
+}
+
+impl Identity {
+    pub fn can_act_as(&self, username: &str) -> bool {
+        self.admin || self.username.as_deref() == Some(username)
+    }
+    /// The logged in user, or the `username` cookie when auth is disabled
+    pub fn username_or_cookie(&self, cookies: &CookieJar) -> Option<String> {
+        self.username.clone().or_else(|| {
+            cookies
+                .get("username")
+                .map(Cookie::value)
+                .map(str::to_owned)
+        })
+    }
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for Identity {
+    type Rejection = std::convert::Infallible;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<Self>().cloned().unwrap_or_default())
+    }
+}
+
+pub fn forbidden() -> ApiError {
+    ApiError::new(
+        StatusCode::FORBIDDEN,
+        "forbidden",
+        "not allowed to act on behalf of this user",
+    )
+}
+
+/// Paths that can be opened without logging in
+fn is_public(path: &str) -> bool {
+    matches!(path, "/login" | "/language" | "/style.css" | "/healthz")
+}
+
+/// Authenticate the request by the session cookie or an `Authorization: Bearer` API token
+pub async fn middleware<B>(
+    axum::extract::State(state): AxumState,
+    cookies: CookieJar,
+    mut req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let Some(config) = &state.config.auth else {
+        req.extensions_mut().insert(Identity {
+            username: None,
+            admin: true,
+        });
+        return next.run(req).await;
+    };
+    let path = req.uri().path().to_owned();
+    let username = {
+        let store = state.auth.read().await;
+        req.headers()
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "))
+            .and_then(|x| store.token_user(x.trim()))
+            .or_else(|| {
+                cookies
+                    .get(SESSION_COOKIE)
+                    .and_then(|x| store.session_user(x.value()))
+            })
+            .filter(|x| state.config.usernames.contains(*x))
+            .map(str::to_owned)
+    };
+    let Some(username) = username else {
+        if is_public(&path) {
+            return next.run(req).await;
+        }
+        if path.starts_with("/api/") {
+            return ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", "not logged in")
+                .into_response();
+        }
+        // relative, so that it works behind a reverse proxy with a path prefix
+        let depth = path.matches('/').count() - 1;
+        let next = match req.uri().query() {
+            Some(q) => format!("{}?{q}", &path[1..]),
+            None => path[1..].to_owned(),
+        };
+        return axum::response::Redirect::to(&format!(
+            "{}login?next={}",
+            "../".repeat(depth),
+            urlencoding(&next),
+        ))
+        .into_response();
+    };
+    let admin = config.admins.contains(&username);
+    if path.starts_with("/admin/") && !admin {
+        return (StatusCode::FORBIDDEN, "admins only").into_response();
+    }
+    req.extensions_mut().insert(Identity {
+        username: Some(username),
+        admin,
+    });
+    next.run(req).await
+}
+
+fn urlencoding(s: &str) -> String {
+    s.bytes()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                char::from(x).to_string()
+            }
+            x => format!("%{x:02X}"),
+        })
+        .collect()
+}
+
+/// Only follow redirects within the app
+pub(crate) fn sanitize_next(next: Option<&String>) -> String {
+    match next {
+        Some(x) if !x.contains("//") && !x.contains('\\') && !x.starts_with('/') => {
+            format!("./{x}")
+        }
+        _ => ".".to_owned(),
+    }
+}
+
+/// `error` is a key in the message catalog
+async fn render_login(state: &State, lang: i18n::Lang, next: &str, error: &str) -> String {
+    let t = i18n::catalog(state, lang).await;
+    state
+        .login_t
+        .get()
+        .await
+        .render(&liquid::object!({
+            "t": &*t,
+            "usernames": &state.config.usernames,
+            "next": next,
+            "error": t.get(error),
+        }))
+        .unwrap_or_else(|err| format!("Error: {err}"))
+}
+
+pub async fn login_page(
+    axum::extract::State(state): AxumState,
+    lang: i18n::Lang,
+    axum::extract::Query(q): axum::extract::Query<BTreeMap<String, String>>,
+) -> axum::response::Html<String> {
+    let next = q.get("next").map_or("", String::as_str);
+    axum::response::Html::from(render_login(&state, lang, next, "").await)
+}
+
+pub async fn login(
+    axum::extract::State(state): AxumState,
+    lang: i18n::Lang,
+    cookies: CookieJar,
+    axum::extract::Form(f): axum::extract::Form<BTreeMap<String, String>>,
+) -> axum::response::Response {
+    let username = f.get("username").map_or("", String::as_str);
+    let password = f.get("password").map_or("", String::as_str);
+    let Some(config) = &state.config.auth else {
+        return axum::response::Redirect::to(".").into_response();
+    };
+    if !state.config.usernames.contains(username)
+        || !check_password(&state, username, password).await
+    {
+        log::warn!("failed login for {username:?}");
+        return (
+            StatusCode::UNAUTHORIZED,
+            axum::response::Html::from(
+                render_login(
+                    &state,
+                    lang,
+                    f.get("next").map_or("", String::as_str),
+                    "login_failed",
+                )
+                .await,
+            ),
+        )
+            .into_response();
+    }
+    let days = config.session_days.unwrap_or(30);
+    let mut store = state.auth.write().await;
+    let token = store.create_session(username, days);
+    if let Err(err) = save(&state.config, &store).await {
+        log::error!("failed to save sessions: {err}");
+    }
+    // sessions expire on the server
+    let session = Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(axum_extra::extract::cookie::SameSite::Lax)
+        .permanent()
+        .finish();
+    // the scanner page preselects this user
+    let user = Cookie::build("username", username.to_owned())
+        .path("/")
+        .permanent()
+        .finish();
+    (
+        cookies.add(session).add(user),
+        axum::response::Redirect::to(&sanitize_next(f.get("next"))),
+    )
+        .into_response()
+}
+
+pub async fn logout(
+    axum::extract::State(state): AxumState,
+    cookies: CookieJar,
+) -> impl IntoResponse {
+    if let Some(token) = cookies.get(SESSION_COOKIE) {
+        let mut store = state.auth.write().await;
+        store.sessions.remove(&sha256_hex(token.value()));
+        if let Err(err) = save(&state.config, &store).await {
+            log::error!("failed to save sessions: {err}");
+        }
+    }
+    (
+        cookies.remove(Cookie::build(SESSION_COOKIE, "").path("/").finish()),
+        axum::response::Redirect::to("login"),
+    )
+}
+
+/// `message` is a key in the message catalog
+async fn render_account(
+    state: &State,
+    identity: &Identity,
+    lang: i18n::Lang,
+    message: &str,
+    token: &str,
+) -> String {
+    let t = i18n::catalog(state, lang).await;
+    let username = identity.username.clone().unwrap_or_default();
+    let tokens = state
+        .auth
+        .read()
+        .await
+        .users
+        .get(&username)
+        .map(|x| {
+            x.tokens
+                .iter()
+                .map(|x| {
+                    liquid::object!({
+                        "id": x.id,
+                        "name": x.name,
+                        "created": x.created.format("%Y-%m-%d %H:%M").to_string(),
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    state
+        .account_t
+        .get()
+        .await
+        .render(&liquid::object!({
+            "t": &*t,
+            "username": username,
+            "admin": identity.admin,
+            "usernames": &state.config.usernames,
+            "tokens": tokens,
+            "message": t.get(message),
+            "token": token,
+        }))
+        .unwrap_or_else(|err| format!("Error: {err}"))
+}
+
+pub async fn account(
+    axum::extract::State(state): AxumState,
+    identity: Identity,
+    lang: i18n::Lang,
+) -> axum::response::Response {
+    if identity.username.is_none() {
+        let t = i18n::catalog(&state, lang).await;
+        return axum::response::Html::from(t.get("auth_disabled").to_owned()).into_response();
+    }
+    axum::response::Html::from(render_account(&state, &identity, lang, "", "").await)
+        .into_response()
+}
+
+/// Password changes and token management, the form's `action` field selects what to do
+pub async fn account_submit(
+    axum::extract::State(state): AxumState,
+    identity: Identity,
+    audit: audit::Context,
+    lang: i18n::Lang,
+    axum::extract::Form(f): axum::extract::Form<BTreeMap<String, String>>,
+) -> axum::response::Response {
+    let Some(username) = identity.username.clone() else {
+        let t = i18n::catalog(&state, lang).await;
+        return axum::response::Html::from(t.get("auth_disabled").to_owned()).into_response();
+    };
+    let field = |k: &str| f.get(k).map_or("", String::as_str);
+    let target = Some(field("username"))
+        .filter(|x| !x.is_empty())
+        .unwrap_or(&username);
+    // hashing is slow, so it's done before locking the store
+    let password = if field("action") != "password" {
+        Ok(String::new())
+    } else if !state.config.usernames.contains(target) || !identity.can_act_as(target) {
+        Err("password_forbidden")
+    } else if target == username && !check_password(&state, &username, field("current")).await {
+        Err("wrong_current_password")
+    } else if field("password").len() < 8 {
+        Err("password_too_short")
+    } else {
+        Ok(hash_password_blocking(field("password")).await)
+    };
+    let mut store = state.auth.write().await;
+    let mut token = String::new();
+    let mut id = "";
+    let message = match field("action") {
+        "password" => match password {
+            Ok(hash) => {
+                store.set_password_hash(target, hash);
+                id = target;
+                "password_changed"
+            }
+            Err(message) => message,
+        },
+        "create_token" if !field("name").is_empty() => {
+            token = store.create_token(&username, field("name"));
+            id = field("name");
+            "token_created"
+        }
+        "revoke_token" => {
+            store.revoke_token(&username, field("id"));
+            id = field("id");
+            "token_revoked"
+        }
+        _ => "unknown_action",
+    };
+    if let Err(err) = save(&state.config, &store).await {
+        log::error!("failed to save accounts: {err}");
+    }
+    drop(store);
+    let res = if id.is_empty() {
+        Err(message.to_owned())
+    } else {
+        Ok(vec![id.to_owned()])
+    };
+    audit.record(&state, &f, res).await;
+    axum::response::Html::from(render_account(&state, &identity, lang, message, &token).await)
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash_password, pbkdf2, verify_password, Identity, Store};
+
+    #[test]
+    fn test() {
+        // RFC 7914 section 11
+        assert_eq!(
+            super::hex(&pbkdf2(b"passwd", b"salt", 1)),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+        let hash = hash_password("hunter22", 10);
+        assert!(verify_password("hunter22", &hash));
+        assert!(!verify_password("hunter23", &hash));
+        assert!(!verify_password("hunter22", "plain"));
+
+        let mut store = Store::default();
+        let token = store.create_token("a", "bot");
+        assert_eq!(store.token_user(&token), Some("a"));
+        let id = store.users["a"].tokens[0].id.clone();
+        store.revoke_token("a", &id);
+        assert_eq!(store.token_user(&token), None);
+        let session = store.create_session("b", 1);
+        assert_eq!(store.session_user(&session), Some("b"));
+        assert_eq!(store.session_user("nope"), None);
+        store.set_password("b", "hunter22");
+        assert!(verify_password(
+            "hunter22",
+            store.users["b"].password.as_deref().unwrap()
+        ));
+        // changing the password ends the sessions
+        assert_eq!(store.session_user(&session), None);
+
+        let user = Identity {
+            username: Some("a".to_owned()),
+            admin: false,
+        };
+        assert!(user.can_act_as("a"));
+        assert!(!user.can_act_as("b"));
+        assert!(!Identity::default().can_act_as("a"));
+    }
+}
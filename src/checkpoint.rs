@@ -0,0 +1,391 @@
+//! Checkpoints of the aggregates derived from `transactions` and `ffd`, so that startup only has
+//! to replay files added since the last checkpoint.
+//!
+//! A checkpoint remembers a fingerprint (name, size, mtime) of every file it covers and is
+//! discarded if any of them changed, disappeared, or an older transaction appeared. Checkpoints are
+//! only written on startup, from the files, so they never race with the running server.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io,
+    path::PathBuf,
+    time::UNIX_EPOCH,
+};
+
+use chrono::NaiveDate;
+use fiscal_data::{fields, Document, TlvType};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    history, refund, Comment, Commodity, Config, Idempotent, Transaction, TransactionMeta,
+};
+
+/// How many checkpoints to keep around
+const KEEP: usize = 3;
+const DEFAULT_INTERVAL: usize = 100;
+/// Bumped when `Aggregates` gains a field that has to be rebuilt from the files
+const VERSION: u32 = 1;
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct Aggregates {
+    pub balance: HashMap<String, i64>,
+    /// Latest split of every paid receipt, the keys are the paid receipt ids
+    pub receipt_payments: HashMap<String, HashMap<String, BTreeSet<usize>>>,
+    pub comments: HashMap<String, Comment>,
+    pub commodities: HashMap<String, Commodity>,
+    /// Idempotency key -> balance after the transaction, only the recent ones
+    #[serde(default)]
+    pub idempotency: HashMap<String, Idempotent>,
+    /// Sale receipts by store INN, for matching refunds
+    #[serde(default)]
+    pub sales: HashMap<String, Vec<refund::Sale>>,
+    /// Sum of the balance changes of every day, for the balance history
+    #[serde(default)]
+    pub daily_balance_changes: BTreeMap<NaiveDate, HashMap<String, i64>>,
+}
+
+impl Aggregates {
+    fn apply_transaction(&mut self, tr: Transaction) {
+        history::record(
+            &mut self.daily_balance_changes,
+            tr.date,
+            &tr.balance_changes,
+        );
+        match tr.meta {
+            Some(TransactionMeta::Receipt { r#fn, i, paid, .. }) => {
+                self.receipt_payments.insert(format!("{fn}_{i:07}"), paid);
+            }
+            Some(TransactionMeta::Comment(comment)) => {
+                let val = self.comments.entry(comment).or_default();
+                val.last_time = tr.date;
+                val.count += 1;
+            }
+            Some(TransactionMeta::Comment2(comment, price)) => {
+                let val = self.comments.entry(comment).or_default();
+                val.last_time = tr.date;
+                val.last_price = price;
+                val.count += 1;
+            }
+            None => {}
+        }
+        for (k, v) in &tr.balance_changes {
+            let x = self.balance.entry(k.clone()).or_default();
+            *x = x.checked_add(*v).expect("balance overflowed");
+        }
+        self.balance.retain(|_, v| *v != 0);
+        self.idempotency.retain(|_, x| !x.is_expired(tr.date));
+        if let Some(key) = tr.idempotency_key {
+            self.idempotency.insert(
+                key,
+                Idempotent {
+                    date: tr.date,
+                    balance: self.balance.clone(),
+                },
+            );
+        }
+    }
+    fn apply_document(&mut self, doc: &Document) {
+        let rec = doc.data();
+        if let Some((inn, sale)) = refund::sale(rec) {
+            refund::insert(self.sales.entry(inn).or_default(), sale);
+        }
+        let date = rec.get::<fields::DateTime>().ok().flatten();
+        for item in rec.get_all::<fields::ReceiptItem>().unwrap_or_default() {
+            let name = item
+                .get::<fields::ItemName>()
+                .ok()
+                .flatten()
+                .expect("failed to read item name");
+            let val = self.commodities.entry(name).or_default();
+            if let Some(unit) = item
+                .get::<fields::Unit>()
+                .ok()
+                .flatten()
+                .filter(|unit| !unit.is_empty())
+                .or_else(|| {
+                    item.get::<fields::ItemQuantityUnit>()
+                        .ok()
+                        .flatten()
+                        .map(|x| x.to_string())
+                })
+            {
+                val.unit = unit;
+            }
+            if let Some(date) = date {
+                val.last_time = val.last_time.max(date);
+            }
+            val.count += 1;
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Checkpoint {
+    /// Older checkpoints lack some aggregates and are rebuilt
+    #[serde(default)]
+    version: u32,
+    /// File name of the last transaction included
+    last_transaction: Option<String>,
+    /// Combined fingerprint of every transaction up to `last_transaction`
+    transactions: u64,
+    /// Fingerprints of the included receipts
+    ffd: BTreeMap<String, u64>,
+    #[serde(flatten)]
+    aggregates: Aggregates,
+}
+
+struct FileInfo {
+    name: String,
+    path: PathBuf,
+    fingerprint: u64,
+}
+
+/// FNV-1a, because std's hasher isn't guaranteed to be stable across releases
+fn fnv(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, x| {
+        (hash ^ u64::from(*x)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+const FNV_INIT: u64 = 0xcbf2_9ce4_8422_2325;
+
+async fn list(config: &Config, dir: &str, ext: &str) -> io::Result<Vec<FileInfo>> {
+    let mut dir = tokio::fs::read_dir(config.data_path(dir)).await?;
+    let mut ret = vec![];
+    while let Some(file) = dir.next_entry().await? {
+        let path = file.path();
+        if !matches!(path.extension().and_then(|x| x.to_str()).map(str::to_lowercase), Some(x) if x == ext)
+        {
+            continue;
+        }
+        let Some(name) = path.file_name().and_then(|x| x.to_str()).map(str::to_owned) else {
+            continue;
+        };
+        let meta = tokio::fs::metadata(&path).await?;
+        let mtime = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let fingerprint = fnv(
+            fnv(fnv(FNV_INIT, name.as_bytes()), &meta.len().to_le_bytes()),
+            &mtime.to_le_bytes(),
+        );
+        ret.push(FileInfo {
+            name,
+            path,
+            fingerprint,
+        });
+    }
+    ret.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    Ok(ret)
+}
+
+fn combine(files: &[FileInfo]) -> u64 {
+    files
+        .iter()
+        .fold(FNV_INIT, |hash, x| fnv(hash, &x.fingerprint.to_le_bytes()))
+}
+
+/// Number of leading transactions covered by the checkpoint, if it's still valid
+fn validate(cp: &Checkpoint, transactions: &[FileInfo], ffd: &[FileInfo]) -> Option<usize> {
+    if cp.version != VERSION {
+        return None;
+    }
+    let count = match &cp.last_transaction {
+        Some(last) => {
+            transactions
+                .binary_search_by(|x| x.name.as_str().cmp(last))
+                .ok()?
+                + 1
+        }
+        None => 0,
+    };
+    if combine(&transactions[..count]) != cp.transactions {
+        return None;
+    }
+    let ffd = ffd
+        .iter()
+        .map(|x| (x.name.as_str(), x.fingerprint))
+        .collect::<HashMap<_, _>>();
+    cp.ffd
+        .iter()
+        .all(|(name, fingerprint)| ffd.get(name.as_str()) == Some(fingerprint))
+        .then_some(count)
+}
+
+async fn checkpoint_paths(config: &Config) -> io::Result<Vec<PathBuf>> {
+    let mut ret = vec![];
+    let Ok(mut dir) = tokio::fs::read_dir(config.data_path("checkpoints")).await else {
+        return Ok(ret);
+    };
+    while let Some(file) = dir.next_entry().await? {
+        let path = file.path();
+        if matches!(path.extension().and_then(|x| x.to_str()), Some("json")) {
+            ret.push(path);
+        }
+    }
+    // newest first
+    ret.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(ret)
+}
+
+async fn save(config: &Config, cp: &Checkpoint) -> io::Result<()> {
+    let dir = config.data_path("checkpoints");
+    tokio::fs::create_dir_all(&dir).await?;
+    let name = cp
+        .last_transaction
+        .as_deref()
+        .and_then(|x| x.strip_suffix(".json"))
+        // sorts before the transaction names, so it's the oldest
+        .unwrap_or("0000-empty");
+    let path1 = dir.join(format!("{name}.json.tmp"));
+    let path2 = dir.join(format!("{name}.json"));
+    tokio::fs::write(&path1, serde_json::to_vec(cp)?).await?;
+    tokio::fs::rename(path1, path2).await?;
+    for old in checkpoint_paths(config).await?.into_iter().skip(KEEP) {
+        let _ = tokio::fs::remove_file(old).await;
+    }
+    Ok(())
+}
+
+/// Load the aggregates from the newest valid checkpoint and the files added after it, writing a
+/// new checkpoint if enough files had to be replayed.
+pub async fn load(config: &Config) -> io::Result<Aggregates> {
+    let start = std::time::Instant::now();
+    let (transactions, ffd) = tokio::try_join!(
+        list(config, "transactions", "json"),
+        list(config, "ffd", "tlv")
+    )?;
+    let mut base = None;
+    for path in checkpoint_paths(config).await? {
+        let Some(cp) = tokio::fs::read(&path)
+            .await
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Checkpoint>(&data).ok())
+        else {
+            log::warn!("ignoring unreadable checkpoint {}", path.display());
+            continue;
+        };
+        if let Some(count) = validate(&cp, &transactions, &ffd) {
+            log::info!("using checkpoint {}", path.display());
+            base = Some((cp, count));
+            break;
+        }
+        log::warn!("checkpoint {} is outdated", path.display());
+    }
+    let (mut cp, count) = base.unwrap_or_else(|| {
+        (
+            Checkpoint {
+                version: VERSION,
+                last_transaction: None,
+                transactions: combine(&[]),
+                ffd: BTreeMap::new(),
+                aggregates: Aggregates::default(),
+            },
+            0,
+        )
+    });
+    let mut replayed = 0;
+    for file in &transactions[count..] {
+        let data = tokio::fs::read(&file.path).await?;
+        let tr = serde_json::from_slice::<Transaction>(&data).map_err(|err| {
+            io::Error::other(format!(
+                "failed to deserialize transaction {}: {err}",
+                file.path.display()
+            ))
+        })?;
+        cp.aggregates.apply_transaction(tr);
+        replayed += 1;
+    }
+    for file in &ffd {
+        if cp.ffd.contains_key(&file.name) {
+            continue;
+        }
+        let data = tokio::fs::read(&file.path).await?;
+        let doc = Document::from_bytes(data).map_err(|err| {
+            io::Error::other(format!(
+                "failed to deserialize receipt {}: {err}",
+                file.path.display()
+            ))
+        })?;
+        cp.aggregates.apply_document(&doc);
+        cp.ffd.insert(file.name.clone(), file.fingerprint);
+        replayed += 1;
+    }
+    log::info!(
+        "loaded state in {:?}, replayed {replayed} files",
+        start.elapsed()
+    );
+    if replayed >= config.checkpoint_interval.unwrap_or(DEFAULT_INTERVAL) {
+        cp.last_transaction = transactions.last().map(|x| x.name.clone());
+        cp.transactions = combine(&transactions);
+        if let Err(err) = save(config, &cp).await {
+            log::error!("failed to write checkpoint: {err}");
+        }
+    }
+    Ok(cp.aggregates)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    use chrono::{TimeZone, Utc};
+
+    use super::{combine, validate, Aggregates, Checkpoint, FileInfo, VERSION};
+    use crate::Transaction;
+
+    fn file(name: &str, fingerprint: u64) -> FileInfo {
+        FileInfo {
+            name: name.to_owned(),
+            path: PathBuf::new(),
+            fingerprint,
+        }
+    }
+
+    #[test]
+    fn test() {
+        let transactions = [file("a.json", 1), file("b.json", 2), file("c.json", 3)];
+        let ffd = [file("x.tlv", 4)];
+        let cp = Checkpoint {
+            version: VERSION,
+            last_transaction: Some("b.json".to_owned()),
+            transactions: combine(&transactions[..2]),
+            ffd: BTreeMap::from([("x.tlv".to_owned(), 4)]),
+            aggregates: Aggregates::default(),
+        };
+        assert_eq!(validate(&cp, &transactions, &ffd), Some(2));
+        // an older transaction was modified
+        let changed = [file("a.json", 5), file("b.json", 2), file("c.json", 3)];
+        assert_eq!(validate(&cp, &changed, &ffd), None);
+        // an older transaction was added
+        let added = [file("0.json", 0), file("a.json", 1), file("b.json", 2)];
+        assert_eq!(validate(&cp, &added, &ffd), None);
+        // a receipt was reparsed
+        assert_eq!(validate(&cp, &transactions, &[file("x.tlv", 6)]), None);
+        // checkpoints from before `version` still parse, but are rebuilt
+        let mut old = serde_json::to_value(&cp).unwrap();
+        let old = old.as_object_mut().unwrap();
+        for key in ["version", "sales", "daily_balance_changes"] {
+            old.remove(key).unwrap();
+        }
+        let old = serde_json::from_value::<Checkpoint>(old.clone().into()).unwrap();
+        assert_eq!(old.version, 0);
+        assert_eq!(validate(&old, &transactions, &ffd), None);
+
+        // idempotency keys are forgotten after a day
+        let mut aggregates = Aggregates::default();
+        for (hour, key) in [(0, "a"), (12, "b"), (25, "c")] {
+            let mut tr = Transaction::new(None);
+            tr.pay("x", "y", 100);
+            tr.date =
+                Utc.with_ymd_and_hms(2024, 3, 5, 0, 0, 0).unwrap() + chrono::Duration::hours(hour);
+            tr.set_idempotency_key("x", Some(key.to_owned()));
+            aggregates.apply_transaction(tr);
+        }
+        let mut keys = aggregates.idempotency.keys().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["x/b", "x/c"]);
+        assert_eq!(aggregates.idempotency["x/c"].balance["y"], 300);
+    }
+}
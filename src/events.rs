@@ -0,0 +1,119 @@
+//! Live updates for the open pages, sent as server-sent events from `/api/events`
+
+use std::{collections::HashMap, convert::Infallible};
+
+use axum::response::sse;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{server::State, ListItem};
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    /// The confirmed balance after a transaction
+    Balance(HashMap<String, i64>),
+    /// The whole shopping list after a change
+    List(Vec<ListItem>),
+    /// A receipt was queued, a queued one arrived, retrying it was given up, or it was removed
+    Pending {
+        id: String,
+        /// Who scanned the receipt
+        username: String,
+        ready: bool,
+        failed: bool,
+        removed: bool,
+    },
+    /// A receipt was imported from a provider account or hidden
+    Synced,
+    /// A transaction was held for confirmation, confirmed or rejected
+    Approvals,
+}
+
+impl Event {
+    /// SSE event type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Balance(_) => "balance",
+            Self::List(_) => "list",
+            Self::Pending { .. } => "pending",
+            Self::Synced => "synced",
+            Self::Approvals => "approvals",
+        }
+    }
+}
+
+pub struct Bus(broadcast::Sender<Event>);
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self(broadcast::channel(64).0)
+    }
+}
+
+impl Bus {
+    pub fn publish(&self, event: Event) {
+        // nobody listening is fine
+        let _ = self.0.send(event);
+    }
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
+
+fn to_sse(event: &Event) -> sse::Event {
+    sse::Event::default()
+        .event(event.name())
+        .json_data(event)
+        .unwrap_or_else(|err| {
+            log::error!("failed to serialize event: {err}");
+            sse::Event::default().event("lagged")
+        })
+}
+
+/// Server-sent events: `balance`, `list`, `pending`, `synced` and `approvals` as they happen, `lagged` if
+/// some were missed and everything has to be reloaded
+#[utoipa::path(
+    get,
+    path = "/api/events",
+    responses((status = 200, content_type = "text/event-stream"))
+)]
+pub async fn stream(
+    axum::extract::State(state): axum::extract::State<State>,
+) -> sse::Sse<impl futures_util::Stream<Item = Result<sse::Event, Infallible>>> {
+    let stream = futures_util::stream::unfold(state.events.subscribe(), |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(event) => to_sse(&event),
+            // the client missed something and has to reload everything
+            Err(broadcast::error::RecvError::Lagged(_)) => sse::Event::default().event("lagged"),
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((Ok(event), rx))
+    });
+    sse::Sse::new(stream).keep_alive(sse::KeepAlive::default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bus, Event};
+    use crate::ListItem;
+
+    #[test]
+    fn test() {
+        let bus = Bus::default();
+        // no subscribers
+        bus.publish(Event::Approvals);
+        let mut rx = bus.subscribe();
+        bus.publish(Event::List(vec![ListItem {
+            name: "молоко".to_owned(),
+            amount: 2.0,
+        }]));
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.name(), "list");
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!([{ "name": "молоко", "amount": 2.0 }])
+        );
+        assert!(rx.try_recv().is_err());
+    }
+}
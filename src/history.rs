@@ -0,0 +1,195 @@
+//! Balance history derived from the transaction log
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::http::StatusCode;
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{api::ApiError, server::State};
+
+/// Most points a single request can ask for, a bit under three years of days
+pub const MAX_POINTS: u32 = 1000;
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl Bucket {
+    /// First day of the bucket containing `date`
+    pub fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Day => date,
+            Self::Week => date - chrono::Days::new(date.weekday().num_days_from_monday().into()),
+            Self::Month => date.with_day(1).expect("every month has a first day"),
+        }
+    }
+    /// First day of the next bucket
+    pub fn next(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Day => start + chrono::Days::new(1),
+            Self::Week => start + chrono::Days::new(7),
+            Self::Month => start + chrono::Months::new(1),
+        }
+    }
+    /// First day of the bucket `n` buckets before the one starting at `start`
+    fn back(self, start: NaiveDate, n: u32) -> NaiveDate {
+        match self {
+            Self::Day => start - chrono::Days::new(n.into()),
+            Self::Week => start - chrono::Days::new(u64::from(n) * 7),
+            Self::Month => start - chrono::Months::new(n),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, IntoParams)]
+pub struct Query {
+    /// Only return this user's balance
+    pub user: Option<String>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub bucket: Bucket,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Point {
+    /// First day of the bucket
+    pub date: NaiveDate,
+    /// Balances at the end of the bucket
+    pub balance: BTreeMap<String, i64>,
+}
+
+/// Balances at the end of every bucket between `query.from` and `query.to` (both default to the
+/// span of the log, limited to the last [`MAX_POINTS`] buckets). `log` must be sorted by date.
+pub fn history<'a>(
+    log: impl IntoIterator<Item = (NaiveDate, &'a HashMap<String, i64>)>,
+    query: &Query,
+    today: NaiveDate,
+) -> Result<Vec<Point>, ApiError> {
+    let mut log = log.into_iter().peekable();
+    let to = query.to.unwrap_or(today);
+    let earliest = query.bucket.back(query.bucket.start(to), MAX_POINTS - 1);
+    let Some(from) = query
+        .from
+        .or_else(|| log.peek().map(|(date, _)| (*date).max(earliest)))
+    else {
+        return Ok(vec![]);
+    };
+    if from < earliest {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "range_too_large",
+            format!("at most {MAX_POINTS} points can be requested at once"),
+        ));
+    }
+    let mut balance = BTreeMap::<String, i64>::new();
+    let mut ret = vec![];
+    let mut start = query.bucket.start(from);
+    while start <= to {
+        let end = query.bucket.next(start);
+        while let Some((_, changes)) = log.next_if(|(date, _)| *date < end) {
+            for (user, change) in changes {
+                if query.user.as_ref().is_some_and(|x| x != user) {
+                    continue;
+                }
+                *balance.entry(user.clone()).or_default() += change;
+            }
+        }
+        if let Some(user) = &query.user {
+            balance.entry(user.clone()).or_default();
+        }
+        ret.push(Point {
+            date: start,
+            balance: balance.clone(),
+        });
+        start = end;
+    }
+    Ok(ret)
+}
+
+/// Add a transaction's balance changes to the per-day log
+pub fn record(
+    log: &mut BTreeMap<NaiveDate, HashMap<String, i64>>,
+    date: chrono::DateTime<Utc>,
+    changes: &HashMap<String, i64>,
+) {
+    let day = log.entry(date.date_naive()).or_default();
+    for (user, change) in changes {
+        *day.entry(user.clone()).or_default() += change;
+    }
+}
+
+pub async fn load(state: &State, query: &Query) -> Result<Vec<Point>, ApiError> {
+    history(
+        state
+            .daily_balance_changes
+            .read()
+            .await
+            .iter()
+            .map(|(date, changes)| (*date, changes)),
+        query,
+        Utc::now().date_naive(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use chrono::NaiveDate;
+
+    use super::{Bucket, Query, MAX_POINTS};
+
+    #[test]
+    fn test() {
+        let day = |d| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+        let a = HashMap::from([("a".to_owned(), 100), ("b".to_owned(), -100)]);
+        let b = HashMap::from([("a".to_owned(), -30), ("b".to_owned(), 30)]);
+        let log = [(day(2), &a), (day(9), &b)];
+        let query = Query {
+            bucket: Bucket::Week,
+            ..Query::default()
+        };
+        let points = super::history(log, &query, day(10)).unwrap();
+        assert_eq!(
+            points.iter().map(|x| x.date).collect::<Vec<_>>(),
+            [day(1), day(8)]
+        );
+        assert_eq!(points[0].balance.get("a"), Some(&100));
+        assert_eq!(points[1].balance.get("a"), Some(&70));
+        let query = Query {
+            user: Some("b".to_owned()),
+            from: Some(day(3)),
+            to: Some(day(4)),
+            bucket: Bucket::Day,
+        };
+        let points = super::history(log, &query, day(10)).unwrap();
+        assert_eq!(points.len(), 2);
+        assert!(points.iter().all(|x| x.balance.len() == 1));
+        assert!(points.iter().all(|x| x.balance.get("b") == Some(&-100)));
+        // a long log without an explicit range only returns the latest points
+        let today = day(2) + chrono::Days::new(u64::from(MAX_POINTS) + 10);
+        let points = super::history(log, &Query::default(), today).unwrap();
+        assert_eq!(points.len(), MAX_POINTS as usize);
+        assert_eq!(points[0].balance.get("a"), Some(&70));
+        // an explicit one is rejected
+        let query = Query {
+            from: Some(day(2)),
+            ..Query::default()
+        };
+        assert!(super::history(log, &query, today).is_err());
+        let query = Query {
+            from: Some(day(2)),
+            bucket: Bucket::Month,
+            ..Query::default()
+        };
+        assert!(super::history(log, &query, today).is_ok());
+    }
+}
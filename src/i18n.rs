@@ -0,0 +1,203 @@
+//! UI strings in several languages. The catalogs are `locales/<lang>.json` and are passed to the
+//! templates as `t`; a catalog in `templates_path` (`ru.json`, `en.json`) replaces single
+//! strings. The language is picked by the `lang` cookie, then `Accept-Language`, then the config.
+
+use std::{collections::BTreeMap, collections::HashMap, sync::Arc};
+
+use axum::response::IntoResponse;
+use axum_extra::extract::{cookie::Cookie, CookieJar};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::ApiError,
+    server::{override_path, FileRes, State},
+    Config,
+};
+
+const COOKIE: &str = "lang";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    #[default]
+    Ru,
+    En,
+}
+
+impl Lang {
+    pub const ALL: [Self; 2] = [Self::Ru, Self::En];
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::Ru => "ru",
+            Self::En => "en",
+        }
+    }
+    /// Accepts language tags like `en-US`
+    pub fn parse(tag: &str) -> Option<Self> {
+        let tag = tag.trim();
+        let code = tag.split(['-', '_']).next().unwrap_or(tag);
+        Self::ALL
+            .into_iter()
+            .find(|x| x.code().eq_ignore_ascii_case(code))
+    }
+    /// The most preferred supported language in an `Accept-Language` header
+    fn from_accept(header: &str) -> Option<Self> {
+        let mut best = None::<(Self, f32)>;
+        for item in header.split(',') {
+            let mut parts = item.split(';');
+            let Some(lang) = parts.next().and_then(Self::parse) else {
+                continue;
+            };
+            let q = parts
+                .find_map(|x| x.trim().strip_prefix("q="))
+                .and_then(|x| x.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if best.is_none_or(|(_, best)| q > best) {
+                best = Some((lang, q));
+            }
+        }
+        best.map(|x| x.0)
+    }
+    fn builtin(self) -> &'static str {
+        match self {
+            Self::Ru => include_str!("../locales/ru.json"),
+            Self::En => include_str!("../locales/en.json"),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl axum::extract::FromRequestParts<State> for Lang {
+    type Rejection = std::convert::Infallible;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &State,
+    ) -> Result<Self, Self::Rejection> {
+        let cookies = CookieJar::from_headers(&parts.headers);
+        Ok(cookies
+            .get(COOKIE)
+            .and_then(|x| Self::parse(x.value()))
+            .or_else(|| {
+                parts
+                    .headers
+                    .get(axum::http::header::ACCEPT_LANGUAGE)
+                    .and_then(|x| x.to_str().ok())
+                    .and_then(Self::from_accept)
+            })
+            .unwrap_or(state.config.language))
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct Catalog(BTreeMap<String, String>);
+
+impl Catalog {
+    /// The string for `key`, or the key itself if it's missing
+    pub fn get<'a>(&'a self, key: &'a str) -> &'a str {
+        self.0.get(key).map_or(key, String::as_str)
+    }
+    /// Describe an error by its API code, keeping the details
+    pub fn error(&self, err: &ApiError) -> String {
+        let key = format!("error_{}", err.code);
+        match self.0.get(&key) {
+            Some(title) => format!("{}: {title} ({})", self.get("error"), err.message),
+            None => format!("{}: {}", self.get("error"), err.message),
+        }
+    }
+}
+
+async fn load_catalog(path: Option<&std::path::Path>, lang: Lang) -> Catalog {
+    let mut ret = serde_json::from_str::<BTreeMap<String, String>>(lang.builtin())
+        .unwrap_or_else(|err| panic!("locales/{}.json: {err}", lang.code()));
+    if let Some(path) = path {
+        match tokio::fs::read(path).await {
+            Ok(data) => match serde_json::from_slice::<BTreeMap<String, String>>(&data) {
+                Ok(x) => ret.extend(x),
+                Err(err) => log::error!("invalid catalog {path:?}: {err}"),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => log::error!("failed to read {path:?}: {err}"),
+        }
+    }
+    ret.insert("lang".to_owned(), lang.code().to_owned());
+    Catalog(ret)
+}
+
+pub async fn load(config: &Config) -> HashMap<Lang, FileRes<Catalog>> {
+    let mut ret = HashMap::new();
+    for lang in Lang::ALL {
+        let path = override_path(config, &format!("locales/{}.json", lang.code()));
+        let path1 = path.clone();
+        let res = FileRes::new(path, move || {
+            let path = path1.clone();
+            async move { load_catalog(path.as_deref(), lang).await }
+        })
+        .await;
+        ret.insert(lang, res);
+    }
+    ret
+}
+
+pub async fn catalog(state: &State, lang: Lang) -> Arc<Catalog> {
+    match state.messages.get(&lang) {
+        Some(x) => x.get().await,
+        None => Arc::default(),
+    }
+}
+
+/// Remember the language picked in the selector
+pub async fn set_language(
+    cookies: CookieJar,
+    axum::extract::Form(f): axum::extract::Form<BTreeMap<String, String>>,
+) -> axum::response::Response {
+    let next = crate::auth::sanitize_next(f.get("next"));
+    let Some(lang) = f.get("lang").and_then(|x| Lang::parse(x)) else {
+        return axum::response::Redirect::to(&next).into_response();
+    };
+    let cookie = Cookie::build(COOKIE, lang.code())
+        .path("/")
+        .permanent()
+        .finish();
+    (cookies.add(cookie), axum::response::Redirect::to(&next)).into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::Lang;
+
+    #[test]
+    fn test() {
+        assert_eq!(Lang::parse("en-US"), Some(Lang::En));
+        assert_eq!(Lang::parse("de"), None);
+        assert_eq!(
+            Lang::from_accept("de-DE, en;q=0.5, ru;q=0.9"),
+            Some(Lang::Ru)
+        );
+        assert_eq!(Lang::from_accept("en-GB,en;q=0.9"), Some(Lang::En));
+        assert_eq!(Lang::from_accept("fr"), None);
+        // every language has every string the templates use
+        let ru = serde_json::from_str::<BTreeMap<String, String>>(Lang::Ru.builtin()).unwrap();
+        let en = serde_json::from_str::<BTreeMap<String, String>>(Lang::En.builtin()).unwrap();
+        assert!(ru.keys().eq(en.keys()));
+        for template in [
+            include_str!("../templates/index.html"),
+            include_str!("../templates/submitted.html"),
+            include_str!("../templates/add.html"),
+            include_str!("../templates/list.html"),
+            include_str!("../templates/history.html"),
+            include_str!("../templates/reparse.html"),
+            include_str!("../templates/login.html"),
+            include_str!("../templates/account.html"),
+            include_str!("../templates/audit.html"),
+            include_str!("../templates/irkkt-mobile/auth.html"),
+        ] {
+            for rest in template.split("{{ t.").skip(1) {
+                let key = rest.split_once(' ').unwrap().0;
+                assert!(key == "lang" || ru.contains_key(key), "missing {key}");
+            }
+        }
+    }
+}
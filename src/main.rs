@@ -1,5 +1,5 @@
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeSet, HashMap, HashSet},
     io,
     os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
@@ -11,8 +11,24 @@ use indexmap::IndexSet;
 use liquid_core::{Display_filter, Filter, FilterReflection, ParseFilter, Runtime, ValueView};
 use serde::{Deserialize, Serialize};
 
+mod allocation;
+mod api;
+mod approval;
+mod audit;
+mod auth;
+mod checkpoint;
+mod events;
+mod history;
+mod i18n;
+mod metrics;
 mod ofd;
+mod pending;
+mod refund;
+mod rules;
+mod scan;
 mod server;
+mod sync;
+mod webhook;
 
 const QR_DATE_FORMAT1: &str = "%Y%m%dT%H%M";
 const QR_DATE_FORMAT2: &str = "%Y%m%dT%H%M%S";
@@ -58,13 +74,11 @@ fn parse_sum(s: &str) -> Option<u64> {
     }
 }
 
-async fn parse_qr(s: &str) -> Object {
+/// Fields of a QR code in the usual `t=...&s=...&fn=...` format
+fn parse_qr_params(s: &str) -> Object {
     let mut ret = Object::new();
     for (k, v) in s.split('&').filter_map(|x| x.split_once('=')) {
         match k {
-            "ofd" => {
-                let _ = ofd::registry().await.fill(v, &mut ret);
-            }
             "date" => {
                 if let Ok(x) = chrono::NaiveDate::parse_from_str(v, "%Y-%m-%d") {
                     let _ = ret.set::<fiscal_data::fields::DateTime>(x.into());
@@ -85,10 +99,8 @@ async fn parse_qr(s: &str) -> Object {
                     let _ = ret.set::<fiscal_data::fields::TotalSum>(x);
                 }
             }
-            "fn" => {
-                if v.bytes().all(|x| x.is_ascii_digit()) {
-                    let _ = ret.set::<fiscal_data::fields::DriveNum>(v.to_owned());
-                }
+            "fn" if v.bytes().all(|x| x.is_ascii_digit()) => {
+                let _ = ret.set::<fiscal_data::fields::DriveNum>(v.to_owned());
             }
             "n" => {
                 if let Ok(x) = v.parse::<u8>() {
@@ -114,6 +126,26 @@ async fn parse_qr(s: &str) -> Object {
     ret
 }
 
+async fn parse_qr(s: &str) -> Object {
+    let mut ret = Object::new();
+    let mut s = s;
+    // the provider chosen in the UI
+    if let Some((id, rest)) = s.strip_prefix("ofd=").and_then(|x| x.split_once('&')) {
+        let _ = ofd::registry().await.fill(id, &mut ret);
+        s = rest;
+    }
+    let rec = match reqwest::Url::parse(s) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => ofd::registry()
+            .await
+            .parse_url(&url)
+            .await
+            .unwrap_or_default(),
+        _ => parse_qr_params(s),
+    };
+    ofd::fill_missing_fields(&mut ret, &rec);
+    ret
+}
+
 mod iso8601 {
     use serde::{
         de::{self, Unexpected},
@@ -273,6 +305,9 @@ enum TransactionMeta {
         #[serde(deserialize_with = "str_or_int::deserialize")]
         i: u32,
         paid: HashMap<String, BTreeSet<usize>>,
+        /// `{fn}_{i:07}` of the sale this receipt is a refund for
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        refund_of: Option<String>,
     },
     Comment(String),
     Comment2(String, i64),
@@ -287,6 +322,13 @@ struct Transaction {
     /// This is redundant, and I store this just in case the FS breaks and I lose files or something
     #[serde(default)]
     prev_state: Option<HashMap<String, i64>>,
+    /// Client-provided key prefixed with the author, a transaction with the same key is never
+    /// added twice within [`IDEMPOTENCY_HOURS`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
+    /// Labels added by the receipt rules
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    tags: BTreeSet<String>,
 }
 
 impl Transaction {
@@ -295,6 +337,8 @@ impl Transaction {
             balance_changes: HashMap::new(),
             date: chrono::Utc::now(),
             prev_state: None,
+            idempotency_key: None,
+            tags: BTreeSet::new(),
             meta,
         }
     }
@@ -310,32 +354,169 @@ impl Transaction {
     pub fn finalize(&mut self) {
         self.balance_changes.retain(|_, v| *v != 0);
     }
+    /// Keys are per author, so that users can't replay or collide with each other's transactions
+    pub fn set_idempotency_key(&mut self, author: &str, key: Option<String>) {
+        self.idempotency_key = key.map(|key| format!("{author}/{key}"));
+    }
 }
 
-async fn add_transaction(state: &server::State, mut tr: Transaction) -> HashMap<String, i64> {
+/// How long idempotency keys are remembered
+const IDEMPOTENCY_HOURS: i64 = 24;
+
+/// Balance after the transaction with an idempotency key
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Idempotent {
+    date: chrono::DateTime<Utc>,
+    balance: HashMap<String, i64>,
+}
+
+impl Idempotent {
+    fn is_expired(&self, now: chrono::DateTime<Utc>) -> bool {
+        self.date + chrono::Duration::hours(IDEMPOTENCY_HOURS) <= now
+    }
+}
+
+/// All transaction files, oldest first
+async fn transaction_paths(config: &Config) -> io::Result<Vec<PathBuf>> {
+    let mut dir = tokio::fs::read_dir(config.data_path("transactions")).await?;
+    let mut files = vec![];
+    while let Some(file) = dir.next_entry().await? {
+        let path = file.path();
+        if matches!(path.extension().and_then(|x| x.to_str()).map(str::to_lowercase), Some(x) if x.as_str() == "json")
+        {
+            files.push(path);
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
+#[derive(Debug)]
+enum Rejected {
+    /// A transaction with this idempotency key was already added, this is the balance it resulted in
+    Replay(HashMap<String, i64>),
+    /// The receipt was already paid
+    AlreadyPaid,
+}
+
+/// A transaction that was accepted
+#[derive(Debug, Default)]
+struct Added {
+    balance: HashMap<String, i64>,
+    /// Id of the stored transaction, `None` if it changed nothing or waits for confirmation
+    id: Option<String>,
+    /// Approval id if the transaction waits for confirmation
+    pending: Option<String>,
+}
+
+/// Add and store `tr`. `force` adds a receipt even if it was already paid.
+async fn add_transaction(
+    state: &server::State,
+    mut tr: Transaction,
+    force: bool,
+) -> Result<Added, Rejected> {
     let mut lock = state.balance.write().await;
+    let now = chrono::Utc::now();
+    state.idempotency.retain(|_, x| !x.is_expired(now));
+    if let Some(x) = tr
+        .idempotency_key
+        .as_ref()
+        .and_then(|key| state.idempotency.get(key))
+    {
+        return Err(Rejected::Replay(x.balance.clone()));
+    }
+    let receipt_id = match &tr.meta {
+        Some(TransactionMeta::Receipt { r#fn, i, .. }) => Some(format!("{fn}_{i:07}")),
+        _ => None,
+    };
+    if !force
+        && receipt_id
+            .as_ref()
+            .is_some_and(|x| state.paid_receipts.contains(x))
+    {
+        return Err(Rejected::AlreadyPaid);
+    }
     if tr.balance_changes.is_empty() && tr.meta.is_none() {
-        return lock.clone();
+        return Ok(Added {
+            balance: lock.clone(),
+            ..Added::default()
+        });
     }
-    tr.date = chrono::Utc::now();
+    tr.date = now;
     tr.prev_state = Some(lock.clone());
-    let mut path = state.config.data_path("transactions");
-    path.push(
-        tr.date.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
-            + "_"
-            + &uuid::Uuid::new_v4().to_string()
-            + ".json",
-    );
+    let id = tr.date.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        + "_"
+        + &uuid::Uuid::new_v4().to_string();
+    let path1 = state
+        .config
+        .data_path(format!("transactions/{id}.json.tmp"));
+    let path2 = state.config.data_path(format!("transactions/{id}.json"));
     let b = serde_json::to_vec(&tr).expect("failed to serialize transaction");
-    tokio::fs::write(path, b)
+    // a crash mid-write must not leave a partial file for the next startup to choke on
+    tokio::fs::write(&path1, b)
+        .await
+        .expect("failed to write transaction");
+    tokio::fs::rename(path1, path2)
         .await
         .expect("failed to write transaction");
+    state.metrics.inc("coop_fd_transactions_total", &[]);
+    history::record(
+        &mut *state.daily_balance_changes.write().await,
+        tr.date,
+        &tr.balance_changes,
+    );
     for (k, v) in &tr.balance_changes {
         let x = lock.entry(k.clone()).or_default();
         *x = x.checked_add(*v).expect("balance overflowed");
     }
     lock.retain(|_, v| *v != 0);
-    lock.clone()
+    let settled = tr
+        .balance_changes
+        .keys()
+        .filter(|x| !lock.contains_key(*x))
+        .cloned()
+        .collect::<Vec<_>>();
+    if let Some(key) = &tr.idempotency_key {
+        state.idempotency.insert(
+            key.clone(),
+            Idempotent {
+                date: tr.date,
+                balance: lock.clone(),
+            },
+        );
+    }
+    if let (Some(id), Some(TransactionMeta::Receipt { paid, .. })) = (receipt_id, &tr.meta) {
+        state.paid_receipts.insert(id.clone());
+        state.receipt_payments.insert(id, paid.clone());
+    }
+    let balance = lock.clone();
+    state
+        .events
+        .publish(events::Event::Balance(balance.clone()));
+    // queueing webhooks writes files, don't hold up other transactions meanwhile
+    drop(lock);
+    webhook::fire(
+        state,
+        "transaction",
+        serde_json::json!({
+            "transaction": api::TransactionView::new(id.clone(), tr),
+            "balance": &balance,
+        }),
+    )
+    .await;
+    for username in settled {
+        webhook::fire(
+            state,
+            "settlement",
+            serde_json::json!({ "username": username, "transaction": id }),
+        )
+        .await;
+    }
+    Ok(Added {
+        balance,
+        id: Some(id),
+        pending: None,
+    })
 }
 
 #[repr(u8)]
@@ -373,7 +554,9 @@ struct Config {
     usernames: IndexSet<String>,
     listener: String,
     data_path: PathBuf,
-    ignore_qr_condition: String,
+    /// No longer used, replaced by `rules`
+    #[serde(default)]
+    ignore_qr_condition: Option<String>,
     // public_url: String,
     #[serde(default)]
     irkkt_mobile_client_secret: Option<String>,
@@ -383,6 +566,48 @@ struct Config {
     irkkt_mobile_api_base: Option<String>,
     #[serde(default)]
     private1_endpoint: Option<String>,
+    #[serde(default)]
+    allocation: allocation::AllocationConfig,
+    /// Write a checkpoint after replaying this many files on startup
+    #[serde(default)]
+    checkpoint_interval: Option<usize>,
+    /// How often to import the receipts attached to our provider accounts, in seconds
+    #[serde(default)]
+    sync_interval: Option<u64>,
+    /// Timeout for a single provider request, in seconds
+    #[serde(default)]
+    fetch_timeout: Option<u64>,
+    /// Time limit for trying every provider for a receipt, in seconds
+    #[serde(default)]
+    fetch_deadline: Option<u64>,
+    /// How many times to retry a provider after a transient error
+    #[serde(default)]
+    fetch_retries: Option<u32>,
+    /// Providers defined by scraping rules
+    #[serde(default)]
+    scrapers: Vec<ofd::scraper::ScraperConfig>,
+    /// Providers implemented as external programs
+    #[serde(default)]
+    commands: Vec<ofd::command::CommandConfig>,
+    /// Require logging in, without this anyone can act as any user
+    #[serde(default)]
+    auth: Option<auth::AuthConfig>,
+    /// Hold transactions charging other users until they confirm them
+    #[serde(default)]
+    approval: Option<approval::ApprovalConfig>,
+    /// Where to send ledger and list events
+    #[serde(default)]
+    webhooks: Vec<webhook::WebhookConfig>,
+    /// Directory with files replacing the built-in templates, static files and message catalogs
+    /// by name (`index.html`, `style.css`, `en.json`), picked up when they change
+    #[serde(default)]
+    templates_path: Option<PathBuf>,
+    /// UI language for those who haven't picked one and whose browser doesn't ask for a known one
+    #[serde(default)]
+    language: i18n::Lang,
+    /// What to do with receipts depending on what's in their QR codes
+    #[serde(default)]
+    rules: Vec<rules::Rule>,
 }
 
 impl Config {
@@ -446,20 +671,20 @@ impl Filter for CEscapeFilter {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, utoipa::ToSchema)]
 struct ListItem {
     name: String,
     amount: f64,
 }
 
-#[derive(Default)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 struct Commodity {
     unit: String,
     last_time: chrono::NaiveDateTime,
     count: usize,
 }
 
-#[derive(Default)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 struct Comment {
     last_price: i64,
     last_time: chrono::DateTime<Utc>,
@@ -471,7 +696,7 @@ async fn save_list(config: &Config, list: &[ListItem]) -> io::Result<()> {
     let path2 = config.data_path("list.json");
     tokio::fs::write(
         &path1,
-        serde_json::to_string(list).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?,
+        serde_json::to_string(list).map_err(io::Error::other)?,
     )
     .await?;
     tokio::fs::rename(path1, path2).await
@@ -511,6 +736,48 @@ async fn main() {
             .expect("failed to read config.json"),
     )
     .expect("invalid config.json");
+    if config
+        .ignore_qr_condition
+        .as_ref()
+        .is_some_and(|x| !matches!(x.trim(), "" | "false"))
+    {
+        log::warn!("ignore_qr_condition is no longer supported, use rules with \"ignore\" instead");
+    }
+    for rule in &config.rules {
+        if let Some(user) = rule.split.iter().find(|x| !config.usernames.contains(*x)) {
+            panic!("rule {:?} splits with unknown user {user:?}", rule.name);
+        }
+    }
+    let mut hooks = HashSet::new();
+    for hook in &config.webhooks {
+        assert!(hooks.insert(&hook.id), "duplicate webhook id {:?}", hook.id);
+    }
+
+    // `coop-fd set-password <username>` reads the password from stdin, for creating the first
+    // account
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("set-password") {
+        let username = args.next().expect("usage: coop-fd set-password <username>");
+        assert!(
+            config.usernames.contains(&username),
+            "unknown user {username}"
+        );
+        let mut password = String::new();
+        std::io::stdin()
+            .read_line(&mut password)
+            .expect("failed to read the password");
+        let password = password.trim_end_matches(['\r', '\n']);
+        assert!(!password.is_empty(), "empty password");
+        tokio::fs::create_dir_all(config.data_path("secret"))
+            .await
+            .unwrap();
+        let mut store = auth::load(&config).await.expect("failed to load accounts");
+        store.set_password(&username, password);
+        auth::save(&config, &store)
+            .await
+            .expect("failed to save accounts");
+        return;
+    }
 
     tokio::join!(
         async {
@@ -539,6 +806,9 @@ async fn main() {
 
     let mut app = axum::Router::new();
     ofd::init_registry(&state, &mut app).await;
+    if let Err(err) = ofd::routes::bootstrap(&state).await {
+        log::error!("failed to learn routes from stored receipts: {err}");
+    }
 
     // state actor
     let state1 = state.clone();
@@ -554,6 +824,41 @@ async fn main() {
         }
     });
 
+    // pending receipt actor
+    let state1 = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            pending::retry(&state1).await;
+        }
+    });
+
+    // approval actor
+    let state1 = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            approval::auto_confirm(&state1).await;
+        }
+    });
+
+    // webhook delivery actor
+    tokio::spawn(webhook::run(state.clone()));
+
+    // provider account sync actor
+    let state1 = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+            state1.config.sync_interval.unwrap_or(3600),
+        ));
+        loop {
+            interval.tick().await;
+            sync::run(&state1).await;
+        }
+    });
+
     let app = app
         .route("/", axum::routing::get(server::root))
         .route("/fzf.js", server::js(|state| &state.fzf))
@@ -574,22 +879,90 @@ async fn main() {
             server::json(|state| &state.qr_scanner_worker_map),
         )
         .route("/style.css", server::css(|state| &state.style))
+        .route("/api/balance", axum::routing::get(api::balance))
         .route(
-            "/api/balance",
-            server::json_endpoint_get(|state| async move {
-                serde_json::to_string(&*state.balance.read().await)
-                    .expect("balance serialization failed")
-            }),
+            "/api/balance/history",
+            axum::routing::get(api::balance_history),
         )
         .route("/api/pay", axum::routing::post(server::api_pay))
+        .route("/api/events", axum::routing::get(events::stream))
+        .route("/api/openapi.json", axum::routing::get(api::openapi))
+        .route("/api/receipt", axum::routing::get(api::receipt))
+        .route("/api/receipt/split", axum::routing::post(api::split))
+        .route("/api/rules/check", axum::routing::get(api::rules_check))
+        .route(
+            "/api/list",
+            axum::routing::get(api::list).post(api::list_add),
+        )
+        .route("/api/list/:name", axum::routing::delete(api::list_remove))
+        .route("/api/transactions", axum::routing::get(api::transactions))
+        .route("/api/approvals", axum::routing::get(api::approvals))
+        .route(
+            "/api/approvals/:id/confirm",
+            axum::routing::post(api::approval_confirm),
+        )
+        .route(
+            "/api/approvals/:id/reject",
+            axum::routing::post(api::approval_reject),
+        )
+        .route(
+            "/api/balance/pending",
+            axum::routing::get(api::pending_balance),
+        )
+        .route(
+            "/approvals/confirm",
+            axum::routing::post(server::approval_confirm),
+        )
+        .route(
+            "/approvals/reject",
+            axum::routing::post(server::approval_reject),
+        )
+        .route(
+            "/admin/reconcile",
+            axum::routing::get(server::reconcile).post(server::reconcile_save),
+        )
+        .route("/admin/audit", axum::routing::get(audit::page))
+        .route(
+            "/admin/reparse",
+            axum::routing::get(server::reparse).post(server::reparse_apply),
+        )
+        .route("/history", axum::routing::get(server::history))
         .route("/list", axum::routing::get(server::list))
         .route("/listremove", axum::routing::post(server::listremove))
         .route("/listadd", axum::routing::post(server::listadd))
+        .route(
+            "/pending/remove",
+            axum::routing::post(server::pending_remove),
+        )
+        .route(
+            "/scan",
+            axum::routing::post(server::scan)
+                // phone photos are larger than the default limit
+                .layer(axum::extract::DefaultBodyLimit::max(32 * 1024 * 1024)),
+        )
+        .route("/synced/hide", axum::routing::post(server::synced_hide))
         .route("/submit", axum::routing::post(server::submit))
-        .route("/add", axum::routing::get(server::add));
-    let app = app.with_state(state);
+        .route("/add", axum::routing::get(server::add))
+        .route(
+            "/login",
+            axum::routing::get(auth::login_page).post(auth::login),
+        )
+        .route("/logout", axum::routing::post(auth::logout))
+        .route("/language", axum::routing::post(i18n::set_language))
+        .route("/metrics", axum::routing::get(metrics::metrics))
+        .route("/healthz", axum::routing::get(metrics::healthz))
+        .route(
+            "/account",
+            axum::routing::get(auth::account).post(auth::account_submit),
+        );
+    let app = app
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth::middleware,
+        ))
+        .with_state(state);
     axum::Server::bind(&config.listener.parse().unwrap())
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<std::net::SocketAddr>())
         .await
         .unwrap();
 }
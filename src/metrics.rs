@@ -0,0 +1,296 @@
+//! Monitoring: counters, gauges and histograms exposed at `/metrics` in the Prometheus text
+//! format, and a `/healthz` check for load balancers and service managers.
+
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Instant};
+
+use axum::{http::StatusCode, response::IntoResponse};
+
+use crate::{ofd, server::State};
+
+/// Metric name and label pairs
+type Key = (&'static str, Vec<(&'static str, String)>);
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Type and description of every metric, anything not listed here isn't exported
+const DESCRIPTIONS: &[(&str, &str, &str)] = &[
+    (
+        "coop_fd_fetch_attempts_total",
+        "counter",
+        "Requests to a provider for a receipt",
+    ),
+    (
+        "coop_fd_fetch_failures_total",
+        "counter",
+        "Failed receipt requests by error kind",
+    ),
+    (
+        "coop_fd_fetch_duration_seconds",
+        "histogram",
+        "Time spent on a receipt request, including failed ones",
+    ),
+    (
+        "coop_fd_cache_hits_total",
+        "counter",
+        "Receipts read from disk instead of asking the provider",
+    ),
+    (
+        "coop_fd_transactions_total",
+        "counter",
+        "Transactions added since startup",
+    ),
+    ("coop_fd_list_items", "gauge", "Items on the shopping list"),
+    (
+        "coop_fd_pending_receipts",
+        "gauge",
+        "Receipts waiting to become available",
+    ),
+    (
+        "coop_fd_webhook_deliveries",
+        "gauge",
+        "Webhook deliveries waiting in the queue",
+    ),
+    (
+        "coop_fd_replay_duration_seconds",
+        "gauge",
+        "Time it took to load the state on startup",
+    ),
+];
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    /// Not cumulative, one per bucket and one for +Inf
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<Key, u64>>,
+    gauges: Mutex<BTreeMap<Key, f64>>,
+    histograms: Mutex<BTreeMap<Key, Histogram>>,
+}
+
+fn key(name: &'static str, labels: &[(&'static str, &str)]) -> Key {
+    (
+        name,
+        labels.iter().map(|(k, v)| (*k, (*v).to_owned())).collect(),
+    )
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .entry(key(name, labels))
+            .or_default() += 1;
+    }
+    pub fn set(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        self.gauges.lock().unwrap().insert(key(name, labels), value);
+    }
+    pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let hist = histograms.entry(key(name, labels)).or_default();
+        let bucket = BUCKETS
+            .iter()
+            .position(|x| value <= *x)
+            .unwrap_or(BUCKETS.len());
+        hist.counts[bucket] += 1;
+        hist.sum += value;
+    }
+    pub fn cache_hit(&self, provider: &str, cache: &str) {
+        self.inc(
+            "coop_fd_cache_hits_total",
+            &[("provider", provider), ("cache", cache)],
+        );
+    }
+    pub fn fetch_failed(&self, provider: &str, err: &ofd::Error) {
+        self.inc(
+            "coop_fd_fetch_failures_total",
+            &[("provider", provider), ("error", err.variant())],
+        );
+    }
+    /// Start timing a request to a provider. If it's dropped without [`Attempt::finish`], the
+    /// request was cancelled by the fetch timeout.
+    pub fn attempt<'a>(&'a self, provider: &'a str) -> Attempt<'a> {
+        Attempt {
+            metrics: self,
+            provider,
+            start: Instant::now(),
+            error: Some("Timeout"),
+        }
+    }
+    fn render(&self) -> String {
+        let mut ret = String::new();
+        let mut samples = Vec::<(&'static str, String)>::new();
+        for ((name, labels), value) in &*self.counters.lock().unwrap() {
+            samples.push((name, format!("{name}{} {value}", format_labels(labels))));
+        }
+        for ((name, labels), value) in &*self.gauges.lock().unwrap() {
+            samples.push((name, format!("{name}{} {value}", format_labels(labels))));
+        }
+        for ((name, labels), hist) in &*self.histograms.lock().unwrap() {
+            let mut line = String::new();
+            let mut total = 0;
+            for (i, count) in hist.counts.iter().enumerate() {
+                total += count;
+                let le = BUCKETS.get(i).map_or("+Inf".to_owned(), f64::to_string);
+                let mut labels = labels.clone();
+                labels.push(("le", le));
+                let _ = writeln!(line, "{name}_bucket{} {total}", format_labels(&labels));
+            }
+            let labels = format_labels(labels);
+            let _ = writeln!(line, "{name}_sum{labels} {}", hist.sum);
+            let _ = write!(line, "{name}_count{labels} {total}");
+            samples.push((name, line));
+        }
+        for (name, kind, help) in DESCRIPTIONS {
+            let _ = writeln!(ret, "# HELP {name} {help}");
+            let _ = writeln!(ret, "# TYPE {name} {kind}");
+            for (_, line) in samples.iter().filter(|x| x.0 == *name) {
+                ret.push_str(line);
+                ret.push('\n');
+            }
+        }
+        ret
+    }
+}
+
+fn format_labels(labels: &[(&'static str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let mut ret = String::from("{");
+    for (i, (k, v)) in labels.iter().enumerate() {
+        if i > 0 {
+            ret.push(',');
+        }
+        let v = v
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(ret, "{k}=\"{v}\"");
+    }
+    ret.push('}');
+    ret
+}
+
+pub struct Attempt<'a> {
+    metrics: &'a Metrics,
+    provider: &'a str,
+    start: Instant,
+    error: Option<&'static str>,
+}
+
+impl Attempt<'_> {
+    pub fn finish<T>(mut self, res: &Result<T, ofd::Error>) {
+        self.error = res.as_ref().err().map(ofd::Error::variant);
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        let labels = [("provider", self.provider)];
+        self.metrics.inc("coop_fd_fetch_attempts_total", &labels);
+        self.metrics.observe(
+            "coop_fd_fetch_duration_seconds",
+            &labels,
+            self.start.elapsed().as_secs_f64(),
+        );
+        if let Some(error) = self.error {
+            self.metrics.inc(
+                "coop_fd_fetch_failures_total",
+                &[("provider", self.provider), ("error", error)],
+            );
+        }
+    }
+}
+
+pub async fn metrics(
+    axum::extract::State(state): axum::extract::State<State>,
+) -> axum::response::Response {
+    let metrics = &state.metrics;
+    metrics.set(
+        "coop_fd_list_items",
+        &[],
+        state.list.read().await.len() as f64,
+    );
+    metrics.set("coop_fd_pending_receipts", &[], state.pending.len() as f64);
+    metrics.set(
+        "coop_fd_webhook_deliveries",
+        &[],
+        state.webhooks.deliveries.len() as f64,
+    );
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        metrics.render(),
+    )
+        .into_response()
+}
+
+/// Healthy as long as new transactions can be written
+pub async fn healthz(
+    axum::extract::State(state): axum::extract::State<State>,
+) -> axum::response::Response {
+    let path = state.config.data_path(".healthz");
+    let res = async {
+        tokio::fs::write(&path, uuid::Uuid::new_v4().to_string()).await?;
+        tokio::fs::remove_file(&path).await
+    }
+    .await;
+    match res {
+        Ok(()) => "ok\n".into_response(),
+        Err(err) => {
+            log::error!("health check failed: {err}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("data directory is not writable: {err}\n"),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use crate::ofd::Error;
+
+    #[test]
+    fn test() {
+        let metrics = Metrics::default();
+        metrics.inc("coop_fd_transactions_total", &[]);
+        metrics.inc("coop_fd_transactions_total", &[]);
+        metrics.cache_hit("taxcom", "ffd");
+        metrics.set("coop_fd_list_items", &[], 3.0);
+        metrics.attempt("taxcom").finish(&Ok::<_, Error>(()));
+        metrics
+            .attempt("ofd-ru")
+            .finish(&Err::<(), _>(Error::ParseError));
+        drop(metrics.attempt("ofd-ru"));
+        metrics.fetch_failed("say \"hi\"", &Error::MissingData("fn"));
+        let text = metrics.render();
+        for line in [
+            "# TYPE coop_fd_transactions_total counter",
+            "coop_fd_transactions_total 2",
+            "coop_fd_cache_hits_total{provider=\"taxcom\",cache=\"ffd\"} 1",
+            "coop_fd_list_items 3",
+            "coop_fd_fetch_attempts_total{provider=\"ofd-ru\"} 2",
+            "coop_fd_fetch_failures_total{provider=\"ofd-ru\",error=\"ParseError\"} 1",
+            "coop_fd_fetch_failures_total{provider=\"ofd-ru\",error=\"Timeout\"} 1",
+            "coop_fd_fetch_failures_total{provider=\"say \\\"hi\\\"\",error=\"MissingData\"} 1",
+            "coop_fd_fetch_duration_seconds_bucket{provider=\"taxcom\",le=\"0.1\"} 1",
+            "coop_fd_fetch_duration_seconds_bucket{provider=\"taxcom\",le=\"+Inf\"} 1",
+            "coop_fd_fetch_duration_seconds_count{provider=\"ofd-ru\"} 2",
+        ] {
+            assert!(text.lines().any(|x| x == line), "missing {line} in\n{text}");
+        }
+        assert!(!text.contains("coop_fd_fetch_failures_total{provider=\"taxcom\""));
+    }
+}
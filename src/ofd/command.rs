@@ -0,0 +1,185 @@
+//! Providers implemented as external programs.
+//!
+//! The program gets the QR code fields as an FNS JSON object on stdin and prints either a TLV
+//! document, an FNS JSON document, or `{"error": "..."}` to stdout.
+
+use std::{collections::BTreeMap, process::Stdio, time::Duration};
+
+use async_trait::async_trait;
+use fiscal_data::{Document, Object, TlvType};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::server::State;
+
+use super::{
+    scraper::{parse_fns_json, qr_json},
+    Error, Provider,
+};
+
+const DEFAULT_TIMEOUT: u64 = 60;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CommandConfig {
+    pub id: String,
+    pub name: String,
+    /// Website shown in the UI
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub inn: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// In seconds
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+pub struct Command {
+    config: CommandConfig,
+}
+
+impl Command {
+    pub fn new(config: &CommandConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+}
+
+/// Output of the program, `Ok(None)` if it's a TLV document
+fn check_output(data: &[u8]) -> Result<Option<serde_json::Value>, Error> {
+    if data.trim_ascii_start().first() != Some(&b'{') {
+        return Ok(None);
+    }
+    if let Ok(res) = serde_json::from_slice::<ErrorResponse>(data) {
+        return Err(Error::Custom(res.error));
+    }
+    Ok(Some(serde_json::from_slice(data)?))
+}
+
+#[async_trait]
+impl Provider for Command {
+    fn id(&self) -> &str {
+        &self.config.id
+    }
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+    fn url(&self) -> &str {
+        &self.config.url
+    }
+    fn exts(&self) -> &'static [&'static str] {
+        &["out"]
+    }
+    fn inn(&self) -> &str {
+        &self.config.inn
+    }
+    async fn fetch_raw_data(&self, _state: &State, rec: &mut Object) -> Result<Vec<u8>, Error> {
+        let mut child = tokio::process::Command::new(&self.config.command)
+            .args(&self.config.args)
+            .envs(&self.config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let input = serde_json::to_vec(&qr_json(rec))?;
+        let mut stdin = child.stdin.take().ok_or(Error::NoResponse)?;
+        let timeout = Duration::from_secs(self.config.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        let output = tokio::time::timeout(timeout, async move {
+            // the program doesn't have to read its input
+            match stdin.write_all(&input).await {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err),
+                _ => drop(stdin),
+            }
+            child.wait_with_output().await
+        })
+        .await
+        .map_err(|_| Error::Timeout)??;
+        if !output.stderr.is_empty() {
+            log::info!(
+                "{} stderr: {}",
+                self.config.id,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        if !output.status.success() {
+            return Err(Error::Custom(format!(
+                "{} exited with {}",
+                self.config.command, output.status
+            )));
+        }
+        // don't let errors end up in the raw cache
+        check_output(&output.stdout)?;
+        Ok(output.stdout)
+    }
+    async fn parse(&self, _state: &State, data: &[u8], _rec: Object) -> Result<Document, Error> {
+        match check_output(data)? {
+            Some(json) => parse_fns_json(json),
+            None => Ok(Document::from_bytes(data.to_vec())?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fiscal_data::{fields, Object};
+
+    use super::{Command, CommandConfig, Provider};
+    use crate::ofd::Error;
+
+    #[test]
+    fn test() {
+        let command = |script: &str| {
+            Command::new(&CommandConfig {
+                id: "test".to_owned(),
+                name: "Test".to_owned(),
+                url: String::new(),
+                inn: String::new(),
+                command: "sh".to_owned(),
+                args: vec!["-c".to_owned(), script.to_owned()],
+                env: Default::default(),
+                timeout: Some(5),
+            })
+        };
+        let mut rec = Object::new();
+        rec.set::<fields::DocNum>(12).unwrap();
+        let state = Default::default();
+        tokio_test::block_on(async {
+            // the QR code fields are passed on stdin
+            let data = command("cat")
+                .fetch_raw_data(&state, &mut rec.clone())
+                .await
+                .unwrap();
+            assert_eq!(data, br#"{"fiscalDocumentNumber":12}"#);
+            // the program doesn't have to read its input, repeated since writing only fails if
+            // the program has already closed it
+            for _ in 0..100 {
+                let data = command("exec 0<&-; echo tlv")
+                    .fetch_raw_data(&state, &mut rec.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(data, b"tlv\n");
+            }
+            let err = command(r#"echo '{"error": "not found"}'"#)
+                .fetch_raw_data(&state, &mut rec.clone())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::Custom(x) if x == "not found"));
+            let err = command("exit 1")
+                .fetch_raw_data(&state, &mut rec.clone())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::Custom(_)));
+        });
+    }
+}
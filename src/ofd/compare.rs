@@ -0,0 +1,223 @@
+//! Field-level comparison of fiscal documents
+
+use std::collections::BTreeMap;
+
+use fiscal_data::{fields, internal::FieldInternal, Object, TlvType};
+use serde::Serialize;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Change {
+    /// Tag path, i.e. `1020` or `1059[2].1043`
+    pub path: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Human-readable representation of a single value
+fn repr(tag: u16, value: &[u8]) -> String {
+    let mut obj = Object::new();
+    obj.set_raw(tag, &[value.to_owned()]);
+    let ret = format!("{obj:?}");
+    // strip the surrounding `{tag: ...}`
+    ret.strip_prefix(&format!("{{{tag}: "))
+        .and_then(|x| x.strip_suffix('}'))
+        .map_or_else(|| ret.clone(), str::to_owned)
+}
+
+fn is_object(tag: u16) -> bool {
+    tag == <fields::ReceiptItem as FieldInternal>::TAG
+}
+
+fn diff_into(prefix: &str, a: &Object, b: &Object, ret: &mut Vec<Change>) {
+    let mut tags = a.iter_raw().map(|(k, _)| k).collect::<Vec<_>>();
+    tags.extend(b.iter_raw().map(|(k, _)| k));
+    tags.sort_unstable();
+    tags.dedup();
+    let empty: &[Vec<u8>] = &[];
+    for tag in tags {
+        let old = a.iter_raw().find(|(k, _)| *k == tag).map_or(empty, |x| x.1);
+        let new = b.iter_raw().find(|(k, _)| *k == tag).map_or(empty, |x| x.1);
+        if old == new {
+            continue;
+        }
+        let multi = old.len().max(new.len()) > 1;
+        for idx in 0..old.len().max(new.len()) {
+            let path = if multi {
+                format!("{prefix}{tag}[{idx}]")
+            } else {
+                format!("{prefix}{tag}")
+            };
+            let (x, y) = (old.get(idx), new.get(idx));
+            if x == y {
+                continue;
+            }
+            if is_object(tag) {
+                if let (Some(x), Some(y)) = (x, y) {
+                    if let (Ok(x), Ok(y)) =
+                        (Object::from_bytes(x.clone()), Object::from_bytes(y.clone()))
+                    {
+                        diff_into(&format!("{path}."), &x, &y, ret);
+                        continue;
+                    }
+                }
+            }
+            ret.push(Change {
+                path,
+                old: x.map(|x| repr(tag, x)),
+                new: y.map(|y| repr(tag, y)),
+            });
+        }
+    }
+}
+
+/// Every field that differs between `a` and `b`, descending into receipt items
+pub fn diff(a: &Object, b: &Object) -> Vec<Change> {
+    let mut ret = vec![];
+    diff_into("", a, b, &mut ret);
+    ret
+}
+
+/// Paths of every field in `obj`, descending into receipt items
+fn paths_into(prefix: &str, obj: &Object, ret: &mut Vec<String>) {
+    for (tag, values) in obj.iter_raw() {
+        for (idx, value) in values.iter().enumerate() {
+            let path = if values.len() > 1 {
+                format!("{prefix}{tag}[{idx}]")
+            } else {
+                format!("{prefix}{tag}")
+            };
+            match Object::from_bytes(value.clone()) {
+                Ok(item) if is_object(tag) => paths_into(&format!("{path}."), &item, ret),
+                _ => ret.push(path),
+            }
+        }
+    }
+}
+
+fn merge_into(
+    prefix: &str,
+    base: &mut Object,
+    other: &Object,
+    provider: &str,
+    sources: &mut BTreeMap<String, String>,
+) -> Result<(), fiscal_data::Error> {
+    for (tag, values) in other.iter_raw() {
+        let existing = base
+            .iter_raw()
+            .find(|(k, _)| *k == tag)
+            .map(|(_, v)| v.to_owned())
+            .unwrap_or_default();
+        let multi = existing.len().max(values.len()) > 1;
+        let path = |idx| {
+            if multi {
+                format!("{prefix}{tag}[{idx}]")
+            } else {
+                format!("{prefix}{tag}")
+            }
+        };
+        if existing.is_empty() {
+            base.set_raw(tag, values);
+            let mut paths = vec![];
+            let mut obj = Object::new();
+            obj.set_raw(tag, values);
+            paths_into(prefix, &obj, &mut paths);
+            for path in paths {
+                sources.insert(path, provider.to_owned());
+            }
+            continue;
+        }
+        // items can only be matched up if both sides agree on what was bought
+        if !is_object(tag) || existing.len() != values.len() {
+            continue;
+        }
+        let mut merged = vec![];
+        for (idx, (a, b)) in existing.iter().zip(values).enumerate() {
+            let mut a = Object::from_bytes(a.clone())?;
+            let b = Object::from_bytes(b.clone())?;
+            merge_into(&format!("{}.", path(idx)), &mut a, &b, provider, sources)?;
+            merged.push(a.into_bytes()?);
+        }
+        base.set_raw(tag, &merged);
+    }
+    Ok(())
+}
+
+/// Add every field of `other` missing from `base` (including fields of receipt items), and record
+/// the fields' origin in `sources`
+pub fn merge(
+    base: &mut Object,
+    other: &Object,
+    provider: &str,
+    sources: &mut BTreeMap<String, String>,
+) -> Result<(), fiscal_data::Error> {
+    merge_into("", base, other, provider, sources)
+}
+
+/// Record `provider` as the origin of every field of `obj`
+pub fn attribute(obj: &Object, provider: &str, sources: &mut BTreeMap<String, String>) {
+    let mut paths = vec![];
+    paths_into("", obj, &mut paths);
+    for path in paths {
+        sources.insert(path, provider.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use fiscal_data::{fields, Object};
+
+    #[test]
+    fn test() {
+        let mut item1 = Object::new();
+        item1.set::<fields::ItemName>("a".to_owned()).unwrap();
+        item1.set::<fields::ItemTotalPrice>(100).unwrap();
+        let mut item2 = item1.clone();
+        item2.set::<fields::ItemTotalPrice>(200).unwrap();
+        let mut a = Object::new();
+        a.set::<fields::TotalSum>(100).unwrap();
+        a.push::<fields::ReceiptItem>(item1).unwrap();
+        let mut b = Object::new();
+        b.set::<fields::TotalSum>(200).unwrap();
+        b.set::<fields::DocNum>(5).unwrap();
+        b.push::<fields::ReceiptItem>(item2).unwrap();
+        let changes = super::diff(&a, &b);
+        assert_eq!(
+            changes.iter().map(|x| x.path.as_str()).collect::<Vec<_>>(),
+            ["1020", "1040", "1059.1043"]
+        );
+        assert_eq!(changes[0].old.as_deref(), Some("100"));
+        assert_eq!(changes[1].old, None);
+        assert!(super::diff(&a, &a).is_empty());
+
+        let mut item3 = Object::new();
+        item3.set::<fields::ItemName>("a".to_owned()).unwrap();
+        item3.set::<fields::ItemUnitPrice>(100).unwrap();
+        let mut c = Object::new();
+        c.set::<fields::TotalSum>(300).unwrap();
+        c.push::<fields::ReceiptItem>(item3).unwrap();
+        let mut sources = BTreeMap::new();
+        super::attribute(&a, "a", &mut sources);
+        super::merge(&mut a, &b, "b", &mut sources).unwrap();
+        super::merge(&mut a, &c, "c", &mut sources).unwrap();
+        assert_eq!(a.get::<fields::TotalSum>().unwrap(), Some(100));
+        assert_eq!(a.get::<fields::DocNum>().unwrap(), Some(5));
+        let item = &a.get_all::<fields::ReceiptItem>().unwrap()[0];
+        assert_eq!(item.get::<fields::ItemTotalPrice>().unwrap(), Some(100));
+        assert_eq!(item.get::<fields::ItemUnitPrice>().unwrap(), Some(100));
+        assert_eq!(
+            sources
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("1020", "a"),
+                ("1040", "b"),
+                ("1059.1030", "a"),
+                ("1059.1043", "a"),
+                ("1059.1079", "c"),
+            ]
+        );
+    }
+}
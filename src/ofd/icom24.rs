@@ -2,7 +2,7 @@ use async_trait::async_trait;
 use fiscal_data::{fields, Object};
 use serde::Deserialize;
 
-use crate::{ofd::custom, parse_qr, server::State};
+use crate::{ofd::custom, server::State};
 
 use super::{fill_missing_fields, Error, Provider};
 
@@ -11,6 +11,22 @@ struct Res {
     url: String,
 }
 
+/// Fill the receipt fields from the link in the response, returns the id of the provider it links
+/// to (usually taxcom)
+async fn fill_from_url(rec: &mut Object, data: &[u8]) -> Result<String, Error> {
+    let res: Res = serde_json::from_slice(data)?;
+    let url = reqwest::Url::parse(&res.url).map_err(|_| Error::ParseError)?;
+    let parsed = super::registry()
+        .await
+        .parse_url(&url)
+        .await
+        .ok_or(Error::ParseError)?;
+    fill_missing_fields(rec, &parsed);
+    Ok(parsed
+        .get::<custom::ProviderId>()?
+        .unwrap_or_else(|| "taxcom".to_owned()))
+}
+
 pub struct Icom24;
 #[async_trait]
 impl Provider for Icom24 {
@@ -63,16 +79,11 @@ impl Provider for Icom24 {
             .await?
             .to_vec();
         log::info!("icom24 response: {ret:?}");
-        let res: Res = serde_json::from_slice(&ret)?;
-        if !res.url.starts_with("https://receipt.taxcom.ru/v01/show?") {
-            return Err(Error::ParseError);
-        }
-        let (_, params) = res.url.split_once('?').ok_or(Error::ParseError)?;
-        fill_missing_fields(rec, &parse_qr(params).await);
+        let id = fill_from_url(rec, &ret).await?;
         if let Some(provider) = {
             let x = super::registry()
                 .await
-                .by_id("taxcom", rec)
+                .by_id(&id, rec)
                 .find(|x| x.id() != self.id());
             x
         } {
@@ -86,17 +97,12 @@ impl Provider for Icom24 {
         data: &[u8],
         mut rec: Object,
     ) -> Result<fiscal_data::Document, Error> {
-        let res: Res = serde_json::from_slice(data)?;
-        if !res.url.starts_with("https://receipt.taxcom.ru/v01/show?") {
-            return Err(Error::ParseError);
-        }
-        let (_, params) = res.url.split_once('?').ok_or(Error::ParseError)?;
-        fill_missing_fields(&mut rec, &parse_qr(params).await);
-        super::registry().await.fill("taxcom", &mut rec)?;
+        let id = fill_from_url(&mut rec, data).await?;
+        super::registry().await.fill(&id, &mut rec)?;
         if let Some(provider) = {
             let x = super::registry()
                 .await
-                .by_id("taxcom", &rec)
+                .by_id(&id, &rec)
                 .find(|x| x.id() != self.id());
             x
         } {
@@ -1,7 +1,8 @@
-use std::{io, path::PathBuf, sync::Arc};
+use std::{collections::BTreeMap, io, path::PathBuf, sync::Arc};
 
 use crate::{
-    server::{FileRes, State},
+    i18n::Catalog,
+    server::{override_path, read_res, FileRes, State},
     Config,
 };
 use async_trait::async_trait;
@@ -18,34 +19,82 @@ struct Auth {
     refresh_token: String,
 }
 
-struct IrkktMobileAuth(PathBuf, OnceCell<RwLock<Auth>>);
+impl Auth {
+    fn is_empty(&self) -> bool {
+        self.session_id.is_empty() || self.refresh_token.is_empty()
+    }
+}
+
+/// Sessions of the FNS accounts, keyed by username. The empty username is the account shared by
+/// everyone, which is also where the session was stored before accounts were per user.
+struct IrkktMobileAuth {
+    dir: PathBuf,
+    usernames: Vec<String>,
+    accounts: OnceCell<RwLock<BTreeMap<String, Auth>>>,
+}
 
 impl IrkktMobileAuth {
-    async fn init(&self) -> &RwLock<Auth> {
-        self.1
+    fn path(&self, account: &str) -> PathBuf {
+        if account.is_empty() {
+            self.dir.join("auth.json")
+        } else {
+            self.dir.join(format!("users/{account}.json"))
+        }
+    }
+    fn is_valid(&self, account: &str) -> bool {
+        account.is_empty() || self.usernames.iter().any(|x| x == account)
+    }
+    async fn init(&self) -> &RwLock<BTreeMap<String, Auth>> {
+        self.accounts
             .get_or_init(|| async {
-                tokio::fs::create_dir_all(self.0.parent().unwrap())
+                tokio::fs::create_dir_all(self.dir.join("users"))
                     .await
                     .unwrap();
-                RwLock::new(
-                    tokio::fs::read(&self.0)
+                let mut ret = BTreeMap::new();
+                for account in std::iter::once("").chain(self.usernames.iter().map(String::as_str))
+                {
+                    if let Some(auth) = tokio::fs::read(self.path(account))
                         .await
                         .ok()
                         .and_then(|data| serde_json::from_slice(&data).ok())
-                        .unwrap_or_default(),
-                )
+                    {
+                        ret.insert(account.to_owned(), auth);
+                    }
+                }
+                RwLock::new(ret)
             })
             .await
     }
-    pub async fn get(&self) -> Auth {
-        self.init().await.read().await.clone()
+    pub async fn get(&self, account: &str) -> Auth {
+        self.init()
+            .await
+            .read()
+            .await
+            .get(account)
+            .cloned()
+            .unwrap_or_default()
     }
-    pub async fn set(&self, auth: Auth) -> io::Result<()> {
+    pub async fn set(&self, account: &str, auth: Auth) -> io::Result<()> {
         let mut lock = self.init().await.write().await;
-        tokio::fs::write(&self.0, serde_json::to_vec(&auth)?).await?;
-        *lock = auth;
+        tokio::fs::write(self.path(account), serde_json::to_vec(&auth)?).await?;
+        lock.insert(account.to_owned(), auth);
         Ok(())
     }
+    /// Accounts with a session, in the order they should be tried for `username`: their own,
+    /// then the other users', then the shared one
+    pub async fn order(&self, username: &str) -> Vec<String> {
+        let mut ret = self
+            .init()
+            .await
+            .read()
+            .await
+            .iter()
+            .filter(|(_, auth)| !auth.is_empty())
+            .map(|(account, _)| account.clone())
+            .collect::<Vec<_>>();
+        ret.sort_by_key(|x| (x != username, x.is_empty()));
+        ret
+    }
 }
 
 #[derive(Clone)]
@@ -54,6 +103,7 @@ pub struct IrkktMobile {
     device_id: String,
     api_base: String,
     auth: Arc<IrkktMobileAuth>,
+    auth_template: Option<PathBuf>,
 }
 
 // #[derive(Deserialize)]
@@ -140,6 +190,12 @@ struct TicketResponse {
     // and a bunch of other fields
 }
 
+#[derive(Deserialize)]
+struct TicketListItem {
+    id: String,
+    query: TicketQuery,
+}
+
 // #[derive(Deserialize)]
 // struct RedirectQuery {
 //     code: String,
@@ -152,10 +208,12 @@ impl IrkktMobile {
             client_secret: client_secret.to_owned(),
             device_id: device_id.to_owned(),
             api_base: api_base.to_owned(),
-            auth: Arc::new(IrkktMobileAuth(
-                cfg.data_path("secret/irkkt-mobile/auth.json"),
-                OnceCell::new(),
-            )),
+            auth: Arc::new(IrkktMobileAuth {
+                dir: cfg.data_path("secret/irkkt-mobile"),
+                usernames: cfg.usernames.iter().cloned().collect(),
+                accounts: OnceCell::new(),
+            }),
+            auth_template: override_path(cfg, "templates/irkkt-mobile/auth.html"),
         }
     }
     fn client(&self) -> Result<reqwest::Client, Error> {
@@ -175,12 +233,18 @@ impl IrkktMobile {
             })
             .build()?)
     }
-    async fn try_fetch(&self, req: reqwest::Request) -> Result<reqwest::Response, Error> {
+    /// Send the request with the account's session, refreshing it if needed. Fails with a redirect
+    /// to the auth page if the account is logged out.
+    async fn try_fetch(
+        &self,
+        req: reqwest::Request,
+        account: &str,
+    ) -> Result<reqwest::Response, Error> {
         let client = self.client()?;
         let mut req1 = Some(req);
         for _ in 0..2 {
-            let auth = self.auth.get().await;
-            if auth.session_id.is_empty() || auth.refresh_token.is_empty() {
+            let auth = self.auth.get(account).await;
+            if auth.is_empty() {
                 break;
             }
             let Some(mut req) = req1.take() else {
@@ -194,10 +258,7 @@ impl IrkktMobile {
                 reqwest::StatusCode::UNAUTHORIZED => {}
                 _ => return Ok(ret),
             }
-            let RefreshResponse {
-                session_id,
-                refresh_token,
-            } = client
+            let res = client
                 .execute(
                     client
                         .post(format!("{}/v2/mobile/users/refresh", self.api_base))
@@ -208,14 +269,25 @@ impl IrkktMobile {
                         })
                         .build()?,
                 )
-                .await?
-                .json()
                 .await?;
+            // the refresh token has expired too
+            if res.status().is_client_error() {
+                log::info!("irkkt-mobile account {account:?} has been logged out");
+                self.auth.set(account, Auth::default()).await?;
+                break;
+            }
+            let RefreshResponse {
+                session_id,
+                refresh_token,
+            } = res.error_for_status()?.json().await?;
             self.auth
-                .set(Auth {
-                    session_id,
-                    refresh_token,
-                })
+                .set(
+                    account,
+                    Auth {
+                        session_id,
+                        refresh_token,
+                    },
+                )
                 .await?;
         }
         // let url = client
@@ -255,12 +327,82 @@ impl IrkktMobile {
         // Err(Error::Redirect(url))
         Err(Error::Redirect("ofd/irkkt-mobile/auth".to_owned()))
     }
+    /// Send the request with the first account that is logged in and not rate limited, starting
+    /// with `username`'s own
+    async fn fetch_any(
+        &self,
+        req: reqwest::Request,
+        username: &str,
+    ) -> Result<(String, reqwest::Response), Error> {
+        let mut err = Error::Redirect("ofd/irkkt-mobile/auth".to_owned());
+        for account in self.auth.order(username).await {
+            let Some(req) = req.try_clone() else {
+                break;
+            };
+            match self.try_fetch(req, &account).await {
+                Ok(res) if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    log::info!("irkkt-mobile account {account:?} is rate limited");
+                    if let Err(e) = res.error_for_status() {
+                        err = e.into();
+                    }
+                }
+                Ok(res) => return Ok((account, res)),
+                Err(Error::Redirect(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(err)
+    }
+    async fn render_auth(
+        &self,
+        auth_t: &FileRes<liquid::Template>,
+        t: &Catalog,
+        account: &str,
+        phone: &str,
+        code_sent: bool,
+    ) -> axum::response::Html<String> {
+        let logged_in = self.auth.order("").await;
+        let accounts = std::iter::once("")
+            .chain(self.auth.usernames.iter().map(String::as_str))
+            .map(|x| {
+                liquid::object!({
+                    "username": x,
+                    "selected": x == account,
+                    "logged_in": logged_in.iter().any(|y| x == y),
+                })
+            })
+            .collect::<Vec<_>>();
+        axum::response::Html::from(
+            auth_t
+                .get()
+                .await
+                .render(&liquid::object!({
+                    "accounts": accounts,
+                    "phone": phone,
+                    "code_sent": code_sent,
+                    "t": t,
+                }))
+                .unwrap_or_else(|err| format!("Error: {err}")),
+        )
+    }
+}
+
+async fn forbidden(state: &State, lang: crate::i18n::Lang) -> axum::response::Response {
+    let t = crate::i18n::catalog(state, lang).await;
+    (
+        axum::http::StatusCode::FORBIDDEN,
+        axum::response::Html::from(format!("{}: {}", t.get("error"), t.get("forbidden"))),
+    )
+        .into_response()
 }
 
 #[derive(Deserialize)]
 struct FnsAuthSubmitRequest {
     phone: String,
     code: String,
+    /// Username whose account this is, empty for the shared one
+    #[serde(default)]
+    account: String,
 }
 
 #[derive(Deserialize)]
@@ -348,12 +490,14 @@ impl Provider for IrkktMobile {
                 send_to_email: false,
             })
             .build()?;
-        let res = self.try_fetch(req).await?.error_for_status()?;
-        let id = res.json::<TicketResponse>().await?.id;
+        let username = rec.get::<super::custom::Username>()?.unwrap_or_default();
+        let (account, res) = self.fetch_any(req, &username).await?;
+        let id = res.error_for_status()?.json::<TicketResponse>().await?.id;
+        // the ticket has been added to this account only
         let req = client
             .get(format!("{}/v2/tickets/{id}", self.api_base))
             .build()?;
-        let res = self.try_fetch(req).await?.error_for_status()?;
+        let res = self.try_fetch(req, &account).await?.error_for_status()?;
         let ret = res.bytes().await?.to_vec();
         rec.set::<super::custom::Id>(id)?;
         Ok(ret)
@@ -367,6 +511,45 @@ impl Provider for IrkktMobile {
         let data: FullTicketResponse = serde_json::from_slice(data)?;
         Ok(data.ticket.document.try_into()?)
     }
+    async fn list(&self, _state: &State) -> Result<Vec<fiscal_data::Object>, Error> {
+        let client = self.client()?;
+        let mut ret = vec![];
+        for account in self.auth.order("").await {
+            let req = client
+                .get(format!("{}/v2/tickets", self.api_base))
+                .build()?;
+            let items = match self.try_fetch(req, &account).await {
+                Ok(res) => {
+                    res.error_for_status()?
+                        .json::<Vec<serde_json::Value>>()
+                        .await?
+                }
+                Err(Error::Redirect(_)) => continue,
+                Err(err) => return Err(err),
+            };
+            for item in items {
+                // tickets that are still being processed may lack some fields
+                let Ok(TicketListItem { id, query }) = serde_json::from_value(item) else {
+                    continue;
+                };
+                let Ok(fiscal_sign) = query.fiscal_sign.parse::<u32>() else {
+                    continue;
+                };
+                let [a, b, c, d] = fiscal_sign.to_be_bytes();
+                let mut rec = fiscal_data::Object::new();
+                rec.set::<fields::DateTime>(query.date)?;
+                rec.set::<fields::PaymentType>(query.operation_type)?;
+                rec.set::<fields::TotalSum>(query.sum)?;
+                rec.set::<fields::DriveNum>(query.fs_id)?;
+                rec.set::<fields::DocNum>(query.document_id)?;
+                rec.set::<fields::DocFiscalSign>([0, 0, a, b, c, d])?;
+                rec.set::<super::custom::Id>(id)?;
+                rec.set::<super::custom::Username>(account.clone())?;
+                ret.push(rec);
+            }
+        }
+        Ok(ret)
+    }
     async fn register(
         &self,
         router: axum::Router<crate::server::State>,
@@ -379,43 +562,75 @@ impl Provider for IrkktMobile {
                 .build()
                 .unwrap(),
         );
-        let auth_t = FileRes::new(move || {
+        let path = self.auth_template.clone();
+        let auth_t = FileRes::new(path.clone(), move || {
             let parser = parser.clone();
+            let path = path.clone();
             async move {
-                parser
-                    .parse(
-                        &tokio::fs::read_to_string("templates/irkkt-mobile/auth.html")
-                            .await
-                            .unwrap_or_else(|_| {
-                                include_str!("../../templates/irkkt-mobile/auth.html").to_owned()
-                            }),
-                    )
-                    .unwrap_or_else(|err| panic!("irkkt_auth:\n{err}"))
+                let builtin = include_str!("../../templates/irkkt-mobile/auth.html");
+                let text =
+                    read_res(path.as_deref(), "templates/irkkt-mobile/auth.html", builtin).await;
+                parser.parse(&text).unwrap_or_else(|err| {
+                    log::error!("irkkt_auth:\n{err}");
+                    parser
+                        .parse(builtin)
+                        .unwrap_or_else(|err| panic!("irkkt_auth:\n{err}"))
+                })
             }
         })
         .await;
+        let this1 = this.clone();
+        let auth_t1 = auth_t.clone();
         router
             .route(
                 "/ofd/irkkt-mobile/auth",
-                axum::routing::get(move || {
-                    let auth_t = auth_t.clone();
-                    async move {
-                        axum::response::Html::from(
-                            auth_t
-                                .get()
+                axum::routing::get(
+                    move |axum::extract::State(state): axum::extract::State<State>,
+                          identity: crate::auth::Identity,
+                          lang: crate::i18n::Lang,
+                          cookies: axum_extra::extract::CookieJar| {
+                        let this = this1.clone();
+                        let auth_t = auth_t1.clone();
+                        async move {
+                            let account = identity
+                                .username_or_cookie(&cookies)
+                                .filter(|x| this.auth.is_valid(x))
+                                .unwrap_or_default();
+                            if !identity.can_act_as(&account) {
+                                return forbidden(&state, lang).await;
+                            }
+                            let t = crate::i18n::catalog(&state, lang).await;
+                            this.render_auth(&auth_t, &t, &account, "", false)
                                 .await
-                                .render(&liquid::object!({}))
-                                .unwrap_or_else(|err| format!("Error: {err}")),
-                        )
-                    }
-                }),
+                                .into_response()
+                        }
+                    },
+                ),
             )
             .route(
                 "/ofd/irkkt-mobile/auth/submit",
                 axum::routing::post(
-                    move |axum::extract::Form(f): axum::extract::Form<FnsAuthSubmitRequest>| {
+                    move |axum::extract::State(state): axum::extract::State<State>,
+                          identity: crate::auth::Identity,
+                          audit: crate::audit::Context,
+                          lang: crate::i18n::Lang,
+                          axum::extract::Form(f): axum::extract::Form<FnsAuthSubmitRequest>| {
                         let this = this.clone();
+                        let auth_t = auth_t.clone();
                         async move {
+                            let t = crate::i18n::catalog(&state, lang).await;
+                            if !this.auth.is_valid(&f.account) {
+                                return axum::response::Html::from(format!(
+                                    "{}: {}",
+                                    t.get("error"),
+                                    t.get("unknown_user")
+                                ))
+                                .into_response();
+                            }
+                            // the account's FNS session is used to fetch their receipts
+                            if !identity.can_act_as(&f.account) {
+                                return forbidden(&state, lang).await;
+                            }
                             let is_auth = !f.code.is_empty();
                             let phone: String = f
                                 .phone
@@ -434,7 +649,7 @@ impl Provider for IrkktMobile {
                                                 ))
                                                 .json(&PhoneRequest {
                                                     client_secret: this.client_secret.clone(),
-                                                    phone,
+                                                    phone: phone.clone(),
                                                 })
                                                 .build()?,
                                         )
@@ -450,7 +665,7 @@ impl Provider for IrkktMobile {
                                                 ))
                                                 .json(&PhoneAuthRequest {
                                                     client_secret: this.client_secret.clone(),
-                                                    phone,
+                                                    phone: phone.clone(),
                                                     code: f.code,
                                                 })
                                                 .build()?,
@@ -463,26 +678,44 @@ impl Provider for IrkktMobile {
                                         ..
                                     } = res.json::<AuthResponse>().await?;
                                     this.auth
-                                        .set(Auth {
-                                            session_id,
-                                            refresh_token,
-                                        })
+                                        .set(
+                                            &f.account,
+                                            Auth {
+                                                session_id,
+                                                refresh_token,
+                                            },
+                                        )
                                         .await?;
                                 }
                                 Ok(())
                             }
                             .await;
+                            // only enough of the phone number to tell accounts apart
+                            let params = serde_json::json!({
+                                "account": f.account,
+                                "phone": format!("***{}", &phone[phone.len().saturating_sub(4)..]),
+                                "step": if is_auth { "verify" } else { "request" },
+                            });
+                            let ids = res
+                                .as_ref()
+                                .map(|()| vec![f.account.clone()])
+                                .map_err(ToString::to_string);
+                            audit.record(&state, params, ids).await;
                             match res {
-                                Ok(()) => axum::response::Redirect::to(if is_auth {
-                                    "../../.."
-                                } else {
-                                    "../auth"
-                                })
-                                .into_response(),
+                                Ok(()) if is_auth => {
+                                    axum::response::Redirect::to("../../..").into_response()
+                                }
+                                Ok(()) => this
+                                    .render_auth(&auth_t, &t, &f.account, &phone, true)
+                                    .await
+                                    .into_response(),
                                 Err(err) => {
                                     log::error!("irkkt mobile phone error: {err}");
-                                    axum::response::Html::from(format!("Error: {err}"))
-                                        .into_response()
+                                    axum::response::Html::from(format!(
+                                        "{}: {err}",
+                                        t.get("error")
+                                    ))
+                                    .into_response()
                                 }
                             }
                         }
@@ -545,10 +778,41 @@ impl Provider for IrkktMobile {
 
 #[cfg(test)]
 mod test {
+    use std::collections::BTreeMap;
+
     use fiscal_data::{fields, Object};
+    use tokio::sync::{OnceCell, RwLock};
 
+    use super::{Auth, IrkktMobileAuth};
     use crate::{ofd::Provider, server::State};
 
+    #[test]
+    fn test_accounts() {
+        let auth = |x: &str| Auth {
+            session_id: x.to_owned(),
+            refresh_token: x.to_owned(),
+        };
+        let accounts = IrkktMobileAuth {
+            dir: "/nonexistent".into(),
+            usernames: vec!["a".to_owned(), "b".to_owned(), "c".to_owned()],
+            accounts: OnceCell::new_with(Some(RwLock::new(BTreeMap::from([
+                (String::new(), auth("shared")),
+                ("a".to_owned(), auth("a")),
+                ("b".to_owned(), Auth::default()),
+                ("c".to_owned(), auth("c")),
+            ])))),
+        };
+        tokio_test::block_on(async {
+            assert_eq!(accounts.order("c").await, ["c", "a", ""]);
+            // logged out
+            assert_eq!(accounts.order("b").await, ["a", "c", ""]);
+            assert_eq!(accounts.order("").await, ["", "a", "c"]);
+        });
+        assert!(accounts.is_valid(""));
+        assert!(accounts.is_valid("a"));
+        assert!(!accounts.is_valid("../x"));
+    }
+
     #[test]
     fn test() {
         tokio_test::block_on(async {
@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 use fiscal_data::{fields, Document, Object, TlvType};
-use std::{collections::BTreeMap, io, sync::Arc};
+use std::{collections::BTreeMap, fmt::Write, future::Future, io, sync::Arc, time::Duration};
 use thiserror::Error;
 use tokio::sync::OnceCell;
 
@@ -33,10 +33,23 @@ pub mod custom {
         const TAG: u16 = 29004;
         type Type = [u8; 3];
     }
+    /// Who scanned the receipt, for providers with per-user accounts
+    pub enum Username {}
+    impl fiscal_data::internal::FieldInternal for Username {
+        const TAG: u16 = 29005;
+        type Type = String;
+    }
 }
 
-mod oneofd;
+pub mod command;
+pub mod compare;
+pub mod reconcile;
+pub mod reparse;
+pub mod routes;
+pub mod scraper;
+
 mod astral;
+mod oneofd;
 // json, theoretically can give tlv but in practice it doesn't give tlv to mere mortals
 // mod beeline;
 // json, close to fns (changed user -> client_name, ФПС is 0)
@@ -48,8 +61,8 @@ mod icom24;
 // mod magnit;
 // json, !has full fiscal sign in base64!
 mod ofd_ru;
-// html
-// mod platforma_ofd;
+// html, only for links to receipts
+mod platforma_ofd;
 // json
 // mod proverkacheka;
 // json
@@ -102,16 +115,67 @@ pub enum Error {
     NoResponse,
     #[error("redirect to {0}")]
     Redirect(String),
+    #[error("timed out")]
+    Timeout,
+    #[error("all providers failed: {}", format_errors(.0))]
+    AllFailed(Vec<(String, Error)>),
+}
+
+fn format_errors(errors: &[(String, Error)]) -> String {
+    let mut ret = String::new();
+    for (id, err) in errors {
+        if !ret.is_empty() {
+            ret.push_str("; ");
+        }
+        let _ = write!(ret, "{id}: {err}");
+    }
+    if ret.is_empty() {
+        ret.push_str("no suitable provider");
+    }
+    ret
+}
+
+impl Error {
+    /// Whether retrying the same request might help: connection failures, timeouts and server
+    /// errors, from any of the providers for [`Error::AllFailed`]
+    pub(crate) fn is_transient(&self) -> bool {
+        match self {
+            Self::Reqwest(err) => {
+                err.is_timeout()
+                    || err.is_connect()
+                    || err.status().is_some_and(|x| x.is_server_error())
+            }
+            Self::Timeout => true,
+            Self::AllFailed(errors) => errors.iter().any(|(_, err)| err.is_transient()),
+            _ => false,
+        }
+    }
+    /// The variant name, for metrics
+    pub fn variant(&self) -> &'static str {
+        match self {
+            Self::Reqwest(_) => "Reqwest",
+            Self::ReqwestHeaderValue(_) => "ReqwestHeaderValue",
+            Self::Io(_) => "Io",
+            Self::FiscalData(_) => "FiscalData",
+            Self::Custom(_) => "Custom",
+            Self::Json(_) => "Json",
+            Self::MissingData(_) => "MissingData",
+            Self::ParseError => "ParseError",
+            Self::NoResponse => "NoResponse",
+            Self::Redirect(_) => "Redirect",
+            Self::Timeout => "Timeout",
+            Self::AllFailed(_) => "AllFailed",
+        }
+    }
 }
 
 #[async_trait]
 pub(crate) trait Provider: Send + Sync {
-    fn id(&self) -> &'static str;
-    fn name(&self) -> &'static str;
-    fn url(&self) -> &'static str;
+    fn id(&self) -> &str;
+    fn name(&self) -> &str;
+    fn url(&self) -> &str;
     fn exts(&self) -> &'static [&'static str];
-    #[allow(dead_code)]
-    fn inn(&self) -> &'static str;
+    fn inn(&self) -> &str;
     async fn fetch_raw_data(&self, state: &State, rec: &mut Object) -> Result<Vec<u8>, Error>;
     async fn parse(&self, state: &State, data: &[u8], rec: Object) -> Result<Document, Error>;
     fn cache_id(&self, rec: &Object) -> Result<String, Error> {
@@ -129,6 +193,16 @@ pub(crate) trait Provider: Send + Sync {
     fn condition(&self, _rec: &Object) -> bool {
         true
     }
+    /// Parse a link to a receipt on the provider's website (some receipts have those in the QR
+    /// code instead of the fields) into QR code fields, `None` if the link isn't the provider's
+    async fn parse_url(&self, _url: &reqwest::Url) -> Result<Option<Object>, Error> {
+        Ok(None)
+    }
+    /// Receipts attached to our account at the provider, as parsed QR codes with at least the
+    /// fields needed for `cache_id`
+    async fn list(&self, _state: &State) -> Result<Vec<Object>, Error> {
+        Ok(vec![])
+    }
 }
 
 pub struct OfdRegistry {
@@ -163,6 +237,15 @@ impl OfdRegistry {
         ret.add(ofd_ru::OfdRu, router).await;
         ret.add(taxcom::Taxcom, router).await;
         ret.add(oneofd::OneOfd, router).await;
+        for config in &c.scrapers {
+            match scraper::Scraper::new(config) {
+                Ok(x) => ret.add(x, router).await,
+                Err(err) => log::error!("invalid scraper {}: {err}", config.id),
+            }
+        }
+        for config in &c.commands {
+            ret.add(command::Command::new(config), router).await;
+        }
         ret
     }
     pub async fn add(&mut self, ofd: impl Provider + 'static, router: &mut axum::Router<State>) {
@@ -202,6 +285,33 @@ impl OfdRegistry {
             .chain(self.all.iter().cloned())
             .filter(move |x| x.condition(rec))
     }
+    /// Parse a receipt link with the provider it belongs to, which is also set as the receipt's
+    /// provider if it can fetch the receipt. Links no provider recognizes are scraped for the
+    /// fields printed on the page.
+    pub async fn parse_url(&self, url: &reqwest::Url) -> Option<Object> {
+        for provider in &self.all {
+            match provider.parse_url(url).await {
+                Ok(Some(mut rec)) => {
+                    if provider.condition(&rec) && !rec.contains::<custom::ProviderId>() {
+                        let _ = rec.push::<custom::ProviderId>(provider.id().to_owned());
+                    }
+                    return Some(rec);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    log::warn!("{} failed to parse {url}: {err}", provider.id());
+                    return None;
+                }
+            }
+        }
+        match platforma_ofd::scrape(url).await {
+            Ok(rec) => Some(rec),
+            Err(err) => {
+                log::warn!("failed to scrape {url}: {err}");
+                None
+            }
+        }
+    }
     pub fn fill(&self, id: &str, rec: &mut Object) -> fiscal_data::Result<Arc<dyn Provider>> {
         let ofd = self
             .by_id(id, rec)
@@ -243,12 +353,16 @@ async fn fetch_raw<P: Provider + ?Sized>(
     let _ = tokio::fs::create_dir_all(&raw_path).await;
     raw_path.push(format!("{cache_id}.{}", provider.exts().first().unwrap()));
     if force || !raw_path.is_file() {
-        let data = provider.fetch_raw_data(state, rec).await?;
+        let attempt = state.metrics.attempt(provider.id());
+        let data = provider.fetch_raw_data(state, rec).await;
+        attempt.finish(&data);
+        let data = data?;
         log::info!("raw data: {data:?}");
         log::info!("writing {raw_path:?}");
         let _ = tokio::fs::write(&raw_path, &data).await;
         Ok(data)
     } else {
+        state.metrics.cache_hit(provider.id(), "raw");
         Ok(tokio::fs::read(&raw_path).await?)
     }
 }
@@ -262,6 +376,7 @@ async fn fetch2<P: Provider + ?Sized>(
     let path = state.config.data_path(format!("ffd/{cache_id}.tlv"));
     if let Ok(data) = tokio::fs::read(&path).await {
         if let Ok(doc) = Document::from_bytes(data) {
+            state.metrics.cache_hit(provider.id(), "ffd");
             return Ok(doc);
         }
     }
@@ -275,12 +390,18 @@ async fn fetch2<P: Provider + ?Sized>(
         None
     };
     let mut parsed = if let Some(parsed) = parsed {
+        state.metrics.cache_hit(provider.id(), "raw");
         parsed
     } else {
         let data = fetch_raw(state, provider, &mut rec, true).await?;
-        provider.parse(state, &data, rec.clone()).await?
+        provider
+            .parse(state, &data, rec.clone())
+            .await
+            .inspect_err(|err| state.metrics.fetch_failed(provider.id(), err))?
     };
     fill_missing_fields(parsed.data_mut(), &rec);
+    // not a part of the receipt
+    parsed.data_mut().remove::<custom::Username>();
     if !path.is_symlink() && !path.is_file() {
         let drive_num = parsed
             .data()
@@ -297,13 +418,103 @@ async fn fetch2<P: Provider + ?Sized>(
     }
     Ok(parsed)
 }
+const DEFAULT_TIMEOUT: u64 = 30;
+const DEFAULT_DEADLINE: u64 = 120;
+const DEFAULT_RETRIES: u32 = 2;
+const BACKOFF: Duration = Duration::from_secs(1);
+
+/// Run `f` with a timeout, retrying transient errors with exponential backoff
+async fn with_retries<T, F: Future<Output = Result<T, Error>>>(
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    mut f: impl FnMut() -> F,
+) -> Result<T, Error> {
+    let mut attempt = 0;
+    loop {
+        let err = match tokio::time::timeout(timeout, f()).await {
+            Ok(Ok(ret)) => return Ok(ret),
+            Ok(Err(err)) => err,
+            Err(_) => Error::Timeout,
+        };
+        if attempt >= retries || !err.is_transient() {
+            return Err(err);
+        }
+        tokio::time::sleep(backoff * 2u32.pow(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Try every provider in the chain for the receipt's provider id until one succeeds, one asks for
+/// a redirect, or the deadline passes
 pub(crate) async fn fetch(state: &State, rec: Object) -> Result<Document, Error> {
-    let provider = registry()
-        .await
-        .by_id(&rec.get::<custom::ProviderId>()?.unwrap_or_default(), &rec)
-        .next()
-        .ok_or(Error::MissingData("provider"))?;
-    let ret = fetch2(state, &*provider, rec).await?;
+    let timeout = Duration::from_secs(state.config.fetch_timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let deadline = tokio::time::Instant::now()
+        + Duration::from_secs(state.config.fetch_deadline.unwrap_or(DEFAULT_DEADLINE));
+    let retries = state.config.fetch_retries.unwrap_or(DEFAULT_RETRIES);
+    let mut id = rec.get::<custom::ProviderId>()?.unwrap_or_default();
+    if id.is_empty() {
+        if let Some(route) = state.routes.read().await.route(&rec) {
+            log::debug!("routing to {route}");
+            id = route.to_owned();
+        }
+    }
+    let mut seen = Vec::<Arc<dyn Provider>>::new();
+    let mut errors = vec![];
+    for provider in registry().await.by_id(&id, &rec) {
+        if seen.iter().any(|x| Arc::ptr_eq(x, &provider)) {
+            continue;
+        }
+        seen.push(provider.clone());
+        // the provider can't identify this receipt
+        if let Err(err) = provider.cache_id(&rec) {
+            log::debug!("skipping {}: {err}", provider.id());
+            continue;
+        }
+        let res = tokio::time::timeout_at(
+            deadline,
+            with_retries(timeout, retries, BACKOFF, || {
+                fetch2(state, &*provider, rec.clone())
+            }),
+        )
+        .await;
+        match res {
+            Ok(Ok(doc)) => return finish(state, &*provider, doc).await,
+            // the user has to log in before any other provider is worth trying
+            Ok(Err(Error::Redirect(url))) => {
+                log::info!("{} wants a redirect to {url}", provider.id());
+                return Err(Error::Redirect(url));
+            }
+            Ok(Err(err)) => {
+                log::warn!("{} failed: {err}", provider.id());
+                errors.push((provider.id().to_owned(), err));
+            }
+            Err(_) => {
+                log::warn!("{} didn't finish before the deadline", provider.id());
+                errors.push((provider.id().to_owned(), Error::Timeout));
+                break;
+            }
+        }
+    }
+    Err(Error::AllFailed(errors))
+}
+
+/// Fetch the receipt from a specific provider, without retries or fallbacks
+pub(crate) async fn fetch_from(
+    state: &State,
+    provider: &dyn Provider,
+    rec: Object,
+) -> Result<Document, Error> {
+    let doc = fetch2(state, provider, rec).await?;
+    finish(state, provider, doc).await
+}
+
+async fn finish(state: &State, provider: &dyn Provider, doc: Document) -> Result<Document, Error> {
+    routes::learn(state, &doc, provider.id()).await;
+    save(state, doc).await
+}
+
+async fn save(state: &State, ret: Document) -> Result<Document, Error> {
     let drive_num = ret
         .data()
         .get::<fields::DriveNum>()?
@@ -315,7 +526,91 @@ pub(crate) async fn fetch(state: &State, rec: Object) -> Result<Document, Error>
     let final_cache_id = format!("{drive_num}_{doc_num:07}");
     let final_path = state.config.data_path(format!("ffd/{final_cache_id}.tlv"));
     if !final_path.is_file() {
-        let _ = tokio::fs::write(&final_path, &ret.clone().into_bytes()?).await;
+        let tmp_path = final_path.with_extension("tlv.tmp");
+        if tokio::fs::write(&tmp_path, &ret.clone().into_bytes()?)
+            .await
+            .is_ok()
+        {
+            let _ = tokio::fs::rename(tmp_path, final_path).await;
+        }
     }
     Ok(ret)
 }
+
+#[cfg(test)]
+mod test {
+    use std::{sync::atomic::AtomicU32, time::Duration};
+
+    use fiscal_data::fields;
+
+    use super::{ofd_ru::OfdRu, oneofd::OneOfd, taxcom::Taxcom, with_retries, Error, Provider};
+
+    #[test]
+    fn test() {
+        tokio_test::block_on(async {
+            let calls = AtomicU32::new(0);
+            let ret = with_retries(Duration::from_secs(1), 2, Duration::ZERO, || async {
+                match calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    0 => Err(Error::Timeout),
+                    _ => Ok(()),
+                }
+            })
+            .await;
+            assert!(ret.is_ok());
+            assert_eq!(calls.into_inner(), 2);
+            let calls = AtomicU32::new(0);
+            let ret = with_retries(Duration::from_secs(1), 2, Duration::ZERO, || async {
+                calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Err::<(), _>(Error::ParseError)
+            })
+            .await;
+            assert!(matches!(ret, Err(Error::ParseError)));
+            assert_eq!(calls.into_inner(), 1);
+            // the provider answered, asking again won't change anything
+            assert!(!Error::NoResponse.is_transient());
+            assert!(!Error::Io(std::io::ErrorKind::NotFound.into()).is_transient());
+            let err = Error::AllFailed(vec![
+                ("a".to_owned(), Error::ParseError),
+                ("b".to_owned(), Error::Timeout),
+            ]);
+            assert_eq!(
+                err.to_string(),
+                "all providers failed: a: parse error; b: timed out"
+            );
+            let url = |x: &str| reqwest::Url::parse(x).unwrap();
+            let rec = OfdRu
+                .parse_url(&url(
+                    "https://check.ofd.ru/rec/7707083893/0000000001002003/9999078900000001/42/258",
+                ))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                rec.get::<fields::DriveNum>().unwrap().unwrap(),
+                "9999078900000001"
+            );
+            assert_eq!(rec.get::<fields::DocNum>().unwrap(), Some(42));
+            let rec = Taxcom
+                .parse_url(&url("https://receipt.taxcom.ru/v01/show?fp=258&s=123.05"))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                rec.get::<fields::DocFiscalSign>().unwrap(),
+                Some([0, 0, 0, 0, 1, 2])
+            );
+            assert_eq!(rec.get::<fields::TotalSum>().unwrap(), Some(12305));
+            let rec = OneOfd
+                .parse_url(&url("https://consumer.1-ofd.ru/#/ticket?t=20240203T0405&s=123.05&fn=9999078900000001&i=42&fp=258&n=1"))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(rec.get::<fields::DocNum>().unwrap(), Some(42));
+            assert!(Taxcom
+                .parse_url(&url("https://check.ofd.ru/rec/1/2/3"))
+                .await
+                .unwrap()
+                .is_none());
+        });
+    }
+}
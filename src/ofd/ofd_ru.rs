@@ -570,6 +570,32 @@ impl Provider for OfdRu {
     fn inn(&self) -> &'static str {
         "7841465198"
     }
+    async fn parse_url(&self, url: &reqwest::Url) -> Result<Option<Object>, Error> {
+        if url.host_str() != Some("check.ofd.ru") {
+            return Ok(None);
+        }
+        // /rec/{fn}/{i}/{fp}, sometimes with the INN and KKT reg num in front
+        let segments = url
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>();
+        let ["rec", .., drive_num, doc_num, fiscal_sign] = segments[..] else {
+            return Ok(None);
+        };
+        if !drive_num.bytes().all(|x| x.is_ascii_digit()) {
+            return Err(Error::ParseError);
+        }
+        let doc_num = doc_num.parse::<u32>().map_err(|_| Error::ParseError)?;
+        let fiscal_sign = fiscal_sign.parse::<u64>().map_err(|_| Error::ParseError)?;
+        let [_, _, a, b, c, d, e, f] = fiscal_sign.to_be_bytes();
+        let mut rec = Object::new();
+        rec.set::<fields::DriveNum>(drive_num.to_owned())?;
+        rec.set::<fields::DocNum>(doc_num)?;
+        rec.set::<fields::DocFiscalSign>([a, b, c, d, e, f])?;
+        Ok(Some(rec))
+    }
     async fn fetch_raw_data(&self, state: &State, rec: &mut Object) -> Result<Vec<u8>, Error> {
         let client = reqwest::Client::builder()
             // .user_agent("Mozilla/5.0 (Windows NT 10.0; rv:109.0) Gecko/20100101 Firefox/118.0")
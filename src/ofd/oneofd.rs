@@ -2,7 +2,7 @@ use async_trait::async_trait;
 use fiscal_data::{enums::FfdVersion, fields, Document, Object};
 use serde::Deserialize;
 
-use crate::server::State;
+use crate::{parse_qr_params, server::State};
 
 use super::{custom, fill_missing_fields, Error, Provider};
 
@@ -254,6 +254,22 @@ impl Provider for OneOfd {
     fn inn(&self) -> &'static str {
         "7709364346"
     }
+    async fn parse_url(&self, url: &reqwest::Url) -> Result<Option<Object>, Error> {
+        if url.host_str() != Some("consumer.1-ofd.ru") {
+            return Ok(None);
+        }
+        // the fields are either in the query or in the fragment (`#/ticket?t=...`)
+        let params = url
+            .query()
+            .or_else(|| url.fragment().and_then(|x| x.split_once('?')).map(|x| x.1))
+            .or_else(|| url.path().rsplit('/').next())
+            .unwrap_or_default();
+        let rec = parse_qr_params(params);
+        if !rec.contains::<fields::DriveNum>() {
+            return Err(Error::MissingData("fn"));
+        }
+        Ok(Some(rec))
+    }
     async fn fetch_raw_data(&self, state: &State, rec: &mut Object) -> Result<Vec<u8>, Error> {
         let drive_num = rec
             .get::<fields::DriveNum>()?
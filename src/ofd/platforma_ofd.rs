@@ -0,0 +1,73 @@
+//! Links to receipts on platformaofd.ru, which can't fetch receipts by their QR code fields, so it
+//! isn't a provider: the fields scraped from the page are fetched from the other providers
+
+use fiscal_data::{enums::PaymentType, fields, Object};
+
+use super::Error;
+use crate::parse_sum;
+
+/// The value following `label` on the receipt page
+fn field<'a>(text: &'a str, label: &str) -> Option<&'a str> {
+    text.split(label).nth(1).and_then(|x| x.split('<').next())
+}
+
+fn parse_page(text: &str) -> Object {
+    let mut rec = Object::new();
+    if let Some(x) = field(text, "<div>ФН №: <right>") {
+        if x.bytes().all(|x| x.is_ascii_digit()) {
+            let _ = rec.set::<fields::DriveNum>(x.to_owned());
+        }
+    }
+    if let Some(x) = field(text, "<div>ФП: <right>").and_then(|x| x.parse::<u64>().ok()) {
+        let [_, _, a, b, c, d, e, f] = x.to_be_bytes();
+        let _ = rec.set::<fields::DocFiscalSign>([a, b, c, d, e, f]);
+    }
+    if let Some(x) = field(text, "<div>ФД №: <right>").and_then(|x| x.parse::<u32>().ok()) {
+        let _ = rec.set::<fields::DocNum>(x);
+    }
+    let _ = rec.set::<fields::PaymentType>(PaymentType::Sale);
+    if let Some(x) = field(text, "<div>Дата Время <right>")
+        .and_then(|x| chrono::NaiveDateTime::parse_from_str(x, "%d.%m.%Y %H:%M").ok())
+    {
+        let _ = rec.set::<fields::DateTime>(x);
+    }
+    if let Some(x) = field(text, "<big>ИТОГ <right>≡").and_then(parse_sum) {
+        let _ = rec.set::<fields::TotalSum>(x);
+    }
+    rec
+}
+
+/// Fields printed on the page `url` links to. Made for platformaofd.ru, but tried on every link no
+/// provider recognizes, since other receipt pages often use the same markup
+pub async fn scrape(url: &reqwest::Url) -> Result<Object, Error> {
+    let text = reqwest::get(url.clone()).await?.text().await?;
+    Ok(parse_page(&text))
+}
+
+#[cfg(test)]
+mod test {
+    use fiscal_data::fields;
+
+    use super::parse_page;
+
+    #[test]
+    fn test() {
+        let rec = parse_page(concat!(
+            "<div>Дата Время <right>03.02.2024 04:05</right></div>",
+            "<div>ФН №: <right>9999078900000001</right></div>",
+            "<div>ФД №: <right>42</right></div>",
+            "<div>ФП: <right>258</right></div>",
+            "<big>ИТОГ <right>≡123.05</right></big>",
+        ));
+        assert_eq!(
+            rec.get::<fields::DriveNum>().unwrap().unwrap(),
+            "9999078900000001"
+        );
+        assert_eq!(rec.get::<fields::DocNum>().unwrap(), Some(42));
+        assert_eq!(
+            rec.get::<fields::DocFiscalSign>().unwrap(),
+            Some([0, 0, 0, 0, 1, 2])
+        );
+        assert_eq!(rec.get::<fields::TotalSum>().unwrap(), Some(12305));
+    }
+}
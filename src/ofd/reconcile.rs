@@ -0,0 +1,133 @@
+//! Fetching the same receipt from every provider that has it and merging the results
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use fiscal_data::{fields, Document, Object, TlvType};
+use serde::Serialize;
+
+use super::{
+    compare, custom, fetch_raw, fill_missing_fields, registry, with_retries, Error, Provider,
+    BACKOFF, DEFAULT_RETRIES, DEFAULT_TIMEOUT,
+};
+use crate::server::State;
+
+#[derive(Debug, Serialize)]
+pub struct Conflict {
+    /// Provider whose value was dropped
+    pub provider: String,
+    #[serde(flatten)]
+    pub change: compare::Change,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Reconciled {
+    /// Receipt id (`fn_i`)
+    pub id: String,
+    /// Providers that returned the receipt, in order of priority
+    pub providers: Vec<String>,
+    /// Which provider each field was taken from
+    pub sources: BTreeMap<String, String>,
+    /// Fields where a lower priority provider disagreed
+    pub conflicts: Vec<Conflict>,
+    pub errors: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub doc: Document,
+}
+
+/// Fetch the receipt bypassing the `ffd` cache, so that every provider gets its say
+async fn fetch_fresh(
+    state: &State,
+    provider: &dyn Provider,
+    rec: Object,
+) -> Result<Document, Error> {
+    let mut rec1 = rec.clone();
+    let data = fetch_raw(state, provider, &mut rec1, false).await?;
+    let mut doc = provider.parse(state, &data, rec1).await?;
+    fill_missing_fields(doc.data_mut(), &rec);
+    Ok(doc)
+}
+
+/// Fetch the receipt from every eligible provider and merge the results, higher priority providers
+/// winning conflicts
+pub async fn reconcile(state: &State, rec: Object) -> Result<Reconciled, Error> {
+    let timeout = Duration::from_secs(state.config.fetch_timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let retries = state.config.fetch_retries.unwrap_or(DEFAULT_RETRIES);
+    let id = rec.get::<custom::ProviderId>()?.unwrap_or_default();
+    let mut seen = Vec::<Arc<dyn Provider>>::new();
+    let mut docs = vec![];
+    let mut errors = BTreeMap::new();
+    for provider in registry().await.by_id(&id, &rec) {
+        if seen.iter().any(|x| Arc::ptr_eq(x, &provider)) {
+            continue;
+        }
+        seen.push(provider.clone());
+        if provider.cache_id(&rec).is_err() {
+            continue;
+        }
+        match with_retries(timeout, retries, BACKOFF, || {
+            fetch_fresh(state, &*provider, rec.clone())
+        })
+        .await
+        {
+            Ok(doc) => docs.push((provider.id().to_owned(), doc)),
+            Err(err) => {
+                errors.insert(provider.id().to_owned(), err.to_string());
+            }
+        }
+    }
+    let mut docs = docs.into_iter();
+    let Some((first, mut doc)) = docs.next() else {
+        return Err(Error::AllFailed(
+            errors
+                .into_iter()
+                .map(|(k, v)| (k, Error::Custom(v)))
+                .collect(),
+        ));
+    };
+    let mut ret = Reconciled {
+        id: String::new(),
+        providers: vec![first.clone()],
+        sources: BTreeMap::new(),
+        conflicts: vec![],
+        errors,
+        doc: Document::default(),
+    };
+    compare::attribute(doc.data(), &first, &mut ret.sources);
+    for (provider, other) in docs {
+        for change in compare::diff(doc.data(), other.data()) {
+            if change.old.is_some() && change.new.is_some() {
+                ret.conflicts.push(Conflict {
+                    provider: provider.clone(),
+                    change,
+                });
+            }
+        }
+        compare::merge(doc.data_mut(), other.data(), &provider, &mut ret.sources)?;
+        ret.providers.push(provider);
+    }
+    let drive_num = doc
+        .data()
+        .get::<fields::DriveNum>()?
+        .ok_or(Error::MissingData("fn"))?;
+    let doc_num = doc
+        .data()
+        .get::<fields::DocNum>()?
+        .ok_or(Error::MissingData("fd"))?;
+    ret.id = format!("{drive_num}_{doc_num:07}");
+    ret.doc = doc;
+    Ok(ret)
+}
+
+/// Overwrite the stored document with the merged one and record where its fields came from
+pub async fn save(state: &State, reconciled: &Reconciled) -> Result<(), Error> {
+    let path = state.config.data_path(format!("ffd/{}.tlv", reconciled.id));
+    let tmp_path = path.with_extension("tlv.tmp");
+    tokio::fs::write(&tmp_path, reconciled.doc.clone().into_bytes()?).await?;
+    tokio::fs::rename(tmp_path, &path).await?;
+    tokio::fs::write(
+        path.with_extension("sources.json"),
+        serde_json::to_vec(&reconciled.sources)?,
+    )
+    .await?;
+    Ok(())
+}
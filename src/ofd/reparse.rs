@@ -0,0 +1,181 @@
+//! Re-running `Provider::parse` over the raw cache, for when a parser gets fixed
+
+use std::path::{Path, PathBuf};
+
+use fiscal_data::{fields, internal::FieldInternal, Document, Object, TlvType};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use super::{compare, custom, fill_missing_fields, registry, Error, Provider};
+use crate::{auth, server::State};
+
+#[derive(Debug, Serialize)]
+pub struct Reparsed {
+    pub provider: String,
+    /// Cache id, also the name of the `ffd` file
+    pub id: String,
+    pub changes: Vec<compare::Change>,
+    /// Set if the raw data couldn't be parsed anymore
+    pub error: Option<String>,
+    /// SHA-256 of the stored and the reparsed document, so that a rewrite can check that it's
+    /// writing what was shown
+    pub digest: String,
+    #[serde(skip)]
+    doc: Option<Document>,
+}
+
+/// The fields that come from the QR code or from us rather than from the provider
+pub fn base_fields(doc: &Object) -> Object {
+    let mut ret = Object::new();
+    for (tag, value) in doc.iter_raw() {
+        if [
+            <fields::DriveNum as FieldInternal>::TAG,
+            <fields::DocNum as FieldInternal>::TAG,
+            <fields::DocFiscalSign as FieldInternal>::TAG,
+            <fields::DateTime as FieldInternal>::TAG,
+            <fields::TotalSum as FieldInternal>::TAG,
+            <fields::PaymentType as FieldInternal>::TAG,
+        ]
+        .contains(&tag)
+            || (<custom::Id as FieldInternal>::TAG..=<custom::IcomCode as FieldInternal>::TAG)
+                .contains(&tag)
+        {
+            ret.set_raw(tag, value);
+        }
+    }
+    ret
+}
+
+/// Raw files of `ofd`, as cache ids and paths
+async fn raw_files(state: &State, ofd: &dyn Provider) -> Result<Vec<(String, PathBuf)>, Error> {
+    let mut files = vec![];
+    let Ok(mut dir) = tokio::fs::read_dir(state.config.data_path("raw").join(ofd.id())).await
+    else {
+        return Ok(files);
+    };
+    while let Some(file) = dir.next_entry().await? {
+        let path = file.path();
+        let (Some(id), Some(ext)) = (
+            path.file_stem().and_then(|x| x.to_str()),
+            path.extension().and_then(|x| x.to_str()),
+        ) else {
+            continue;
+        };
+        if ofd.exts().contains(&ext) {
+            files.push((id.to_owned(), path.clone()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Parse a raw file again, `None` if there's no stored document or nothing changed
+async fn reparse_file(
+    state: &State,
+    ofd: &dyn Provider,
+    id: String,
+    path: &Path,
+) -> Result<Option<Reparsed>, Error> {
+    let Ok(old) = tokio::fs::read(state.config.data_path(format!("ffd/{id}.tlv"))).await else {
+        return Ok(None);
+    };
+    let mut item = Reparsed {
+        provider: ofd.id().to_owned(),
+        id,
+        changes: vec![],
+        error: None,
+        digest: String::new(),
+        doc: None,
+    };
+    let old = match Document::from_bytes(old) {
+        Ok(x) => x,
+        Err(err) => {
+            item.error = Some(format!("invalid stored document: {err}"));
+            return Ok(Some(item));
+        }
+    };
+    let raw = tokio::fs::read(path).await?;
+    let rec = base_fields(old.data());
+    match ofd.parse(state, &raw, rec.clone()).await {
+        Ok(mut doc) => {
+            fill_missing_fields(doc.data_mut(), &rec);
+            item.changes = compare::diff(old.data(), doc.data());
+            if item.changes.is_empty() {
+                return Ok(None);
+            }
+            let mut hasher = Sha256::new();
+            hasher.update(old.into_bytes()?);
+            hasher.update(doc.clone().into_bytes()?);
+            item.digest = auth::hex(&hasher.finalize());
+            item.doc = Some(doc);
+        }
+        Err(err) => item.error = Some(err.to_string()),
+    }
+    Ok(Some(item))
+}
+
+/// Parse every raw file of `provider` (or of every provider) again and compare the result with
+/// the stored document. Only documents that changed or failed to parse are returned.
+pub async fn reparse(state: &State, provider: Option<&str>) -> Result<Vec<Reparsed>, Error> {
+    let mut ret = vec![];
+    let mut seen = vec![];
+    for ofd in registry().await.all() {
+        if provider.is_some_and(|x| x != ofd.id()) || seen.iter().any(|x| x == ofd.id()) {
+            continue;
+        }
+        seen.push(ofd.id().to_owned());
+        for (id, path) in raw_files(state, &*ofd).await? {
+            if let Some(item) = reparse_file(state, &*ofd, id, &path).await? {
+                ret.push(item);
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// A document picked for rewriting on the dry run page
+#[derive(Clone, Debug, Serialize)]
+pub struct Selected {
+    pub provider: String,
+    pub id: String,
+    /// [`Reparsed::digest`] as shown
+    pub digest: String,
+}
+
+/// Overwrite the stored documents with the selected reparsed ones. Returns how many were written
+/// and the ones that were skipped because they no longer match what was shown.
+pub async fn apply(state: &State, selected: &[Selected]) -> Result<(usize, Vec<Selected>), Error> {
+    let registry = registry().await;
+    let mut count = 0;
+    let mut stale = vec![];
+    for sel in selected {
+        let Some(ofd) = registry.all().find(|x| x.id() == sel.provider) else {
+            return Err(Error::Custom(format!("unknown provider {}", sel.provider)));
+        };
+        let path = raw_files(state, &*ofd)
+            .await?
+            .into_iter()
+            .find_map(|(id, path)| (id == sel.id).then_some(path));
+        let item = match path {
+            Some(path) => reparse_file(state, &*ofd, sel.id.clone(), &path).await?,
+            None => None,
+        };
+        let Some(doc) = item.filter(|x| x.digest == sel.digest).and_then(|x| x.doc) else {
+            log::warn!(
+                "not rewriting ffd/{}.tlv, it changed since the dry run",
+                sel.id
+            );
+            stale.push(sel.clone());
+            continue;
+        };
+        log::info!("rewriting ffd/{}.tlv from {}", sel.id, sel.provider);
+        // follows the symlinks made by `fetch2`
+        tokio::fs::write(
+            state.config.data_path(format!("ffd/{}.tlv", sel.id)),
+            doc.into_bytes()?,
+        )
+        .await?;
+        count += 1;
+    }
+    Ok((count, stale))
+}
@@ -0,0 +1,159 @@
+//! Which provider to ask for receipts from a given fiscal drive or cash register, learned from the
+//! receipts fetched so far and stored in `data/routes.json`.
+
+use std::{collections::BTreeMap, io};
+
+use fiscal_data::{fields, Document, Object, TlvType};
+use serde::{Deserialize, Serialize};
+
+use super::{registry, Provider};
+use crate::{server::State, Config};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Routes {
+    /// Fiscal drive number -> provider id
+    #[serde(default)]
+    pub drives: BTreeMap<String, String>,
+    /// KKT registration number -> provider id
+    #[serde(default)]
+    pub kkts: BTreeMap<String, String>,
+}
+
+fn keys(rec: &Object) -> (Option<String>, Option<String>) {
+    (
+        rec.get::<fields::DriveNum>().ok().flatten(),
+        rec.get::<fields::KktRegNum>()
+            .ok()
+            .flatten()
+            .map(|x| x.trim().to_owned())
+            .filter(|x| !x.is_empty()),
+    )
+}
+
+impl Routes {
+    /// The provider for `rec`, by fiscal drive or else by cash register
+    pub fn route(&self, rec: &Object) -> Option<&str> {
+        let (drive, kkt) = keys(rec);
+        drive
+            .and_then(|x| self.drives.get(&x))
+            .or_else(|| kkt.and_then(|x| self.kkts.get(&x)))
+            .map(String::as_str)
+    }
+    /// Remember `provider` for the document's fiscal drive and cash register, returns whether
+    /// anything changed
+    pub fn learn(&mut self, rec: &Object, provider: &str) -> bool {
+        let (drive, kkt) = keys(rec);
+        let mut changed = false;
+        for (map, key) in [(&mut self.drives, drive), (&mut self.kkts, kkt)] {
+            if let Some(key) = key {
+                if map.get(&key).map(String::as_str) != Some(provider) {
+                    map.insert(key, provider.to_owned());
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// The provider that should be remembered for a document: the OFD itself if we support it,
+/// otherwise whoever returned it
+fn provider_for<'a>(rec: &Object, fetched_by: &'a str, all: &[&'a dyn Provider]) -> &'a str {
+    rec.get::<fields::OfdInn>()
+        .ok()
+        .flatten()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .and_then(|inn| all.iter().find(|x| x.inn() == inn))
+        .map_or(fetched_by, |x| x.id())
+}
+
+pub async fn load(config: &Config) -> io::Result<Routes> {
+    match tokio::fs::read(config.data_path("routes.json")).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Routes::default()),
+        Err(err) => Err(err),
+    }
+}
+
+async fn save(config: &Config, routes: &Routes) -> io::Result<()> {
+    let path1 = config.data_path("routes.json.tmp");
+    tokio::fs::write(&path1, serde_json::to_vec(routes)?).await?;
+    tokio::fs::rename(path1, config.data_path("routes.json")).await
+}
+
+/// Remember the provider that successfully fetched `doc`
+pub async fn learn(state: &State, doc: &Document, fetched_by: &str) {
+    let all = registry().await.all().collect::<Vec<_>>();
+    let all = all.iter().map(|x| &**x).collect::<Vec<_>>();
+    let provider = provider_for(doc.data(), fetched_by, &all);
+    let mut routes = state.routes.write().await;
+    if routes.learn(doc.data(), provider) {
+        if let Err(err) = save(&state.config, &routes).await {
+            log::error!("failed to save routes: {err}");
+        }
+    }
+}
+
+/// Fill the routes from the stored receipts if they've never been saved
+pub async fn bootstrap(state: &State) -> io::Result<()> {
+    if state.config.data_path("routes.json").is_file() {
+        return Ok(());
+    }
+    let all = registry().await.all().collect::<Vec<_>>();
+    let all = all.iter().map(|x| &**x).collect::<Vec<_>>();
+    let mut routes = state.routes.write().await;
+    let mut dir = tokio::fs::read_dir(state.config.data_path("ffd")).await?;
+    while let Some(file) = dir.next_entry().await? {
+        let path = file.path();
+        if !matches!(path.extension().and_then(|x| x.to_str()), Some("tlv")) {
+            continue;
+        }
+        let Ok(doc) = Document::from_bytes(tokio::fs::read(&path).await?) else {
+            continue;
+        };
+        // only documents from OFDs we know, as we don't know who fetched the rest
+        let provider = provider_for(doc.data(), "", &all);
+        if !provider.is_empty() {
+            routes.learn(doc.data(), provider);
+        }
+    }
+    log::info!(
+        "learned routes for {} fiscal drives from stored receipts",
+        routes.drives.len()
+    );
+    save(&state.config, &routes).await
+}
+
+#[cfg(test)]
+mod test {
+    use fiscal_data::{fields, Object};
+
+    use super::Routes;
+
+    #[test]
+    fn test() {
+        let mut routes = Routes::default();
+        let mut doc = Object::new();
+        doc.set::<fields::DriveNum>("9999078900000001".to_owned())
+            .unwrap();
+        doc.set::<fields::KktRegNum>("0000000001002003".to_owned())
+            .unwrap();
+        assert!(routes.learn(&doc, "taxcom"));
+        assert!(!routes.learn(&doc, "taxcom"));
+        let mut qr = Object::new();
+        qr.set::<fields::DriveNum>("9999078900000001".to_owned())
+            .unwrap();
+        assert_eq!(routes.route(&qr), Some("taxcom"));
+        // a new fiscal drive in the same cash register
+        let mut other = Object::new();
+        other
+            .set::<fields::DriveNum>("9999078900000002".to_owned())
+            .unwrap();
+        assert_eq!(routes.route(&other), None);
+        other
+            .set::<fields::KktRegNum>("0000000001002003".to_owned())
+            .unwrap();
+        assert_eq!(routes.route(&other), Some("taxcom"));
+    }
+}
@@ -0,0 +1,614 @@
+//! Providers described in the config instead of code: a request built from the QR code fields and
+//! rules for extracting FNS JSON fields from the response.
+
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use fiscal_data::{fields, internal::FieldInternal, Document, Object, TlvType};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::server::State;
+
+use super::{Error, Provider};
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    #[default]
+    Get,
+    Post,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Html,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Keep the value as is (strings for regex matches)
+    #[default]
+    Auto,
+    String,
+    Int,
+    /// Rubles, converted to kopecks
+    Sum,
+    Float,
+    /// Parsed with `format`
+    DateTime,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Extract {
+    /// JSON pointer, for JSON responses
+    #[serde(default)]
+    pub pointer: Option<String>,
+    /// Regex, the first capture group (or the whole match) is the value
+    #[serde(default)]
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub kind: Kind,
+    /// `chrono` format for `date_time`, defaults to `%d.%m.%Y %H:%M`
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ItemsSpec {
+    /// JSON pointer to the array of items
+    #[serde(default)]
+    pub pointer: Option<String>,
+    /// Regex matching every item's block of HTML
+    #[serde(default)]
+    pub regex: Option<String>,
+    /// FNS JSON name -> rule, relative to the item
+    pub fields: BTreeMap<String, Extract>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScraperConfig {
+    pub id: String,
+    pub name: String,
+    /// Website shown in the UI
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub inn: String,
+    /// Request URL, with `{fn}`, `{i}`, `{fp}`, `{n}`, `{s}` (rubles), `{sum}` (kopecks), `{t}`
+    /// (QR code format) and `{date}` (`YYYY-MM-DD`) replaced by the QR code fields
+    pub request_url: String,
+    #[serde(default)]
+    pub method: Method,
+    /// Header values support the same placeholders as the URL
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Request body, supports the same placeholders as the URL
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub format: Format,
+    /// JSON pointer to a complete FNS JSON document in the response
+    #[serde(default)]
+    pub document: Option<String>,
+    /// FNS JSON name -> rule
+    #[serde(default)]
+    pub fields: BTreeMap<String, Extract>,
+    #[serde(default)]
+    pub items: Option<ItemsSpec>,
+}
+
+struct Rule {
+    name: String,
+    pointer: Option<String>,
+    regex: Option<regex::Regex>,
+    kind: Kind,
+    format: String,
+}
+
+impl Rule {
+    fn new(name: &str, x: &Extract) -> Result<Self, regex::Error> {
+        Ok(Self {
+            name: name.to_owned(),
+            pointer: x.pointer.clone(),
+            regex: x.regex.as_deref().map(regex::Regex::new).transpose()?,
+            kind: x.kind,
+            format: x
+                .format
+                .clone()
+                .unwrap_or_else(|| "%d.%m.%Y %H:%M".to_owned()),
+        })
+    }
+    fn extract(&self, json: Option<&Value>, text: &str) -> Option<Value> {
+        let raw = if let Some(pointer) = &self.pointer {
+            json?.pointer(pointer)?.clone()
+        } else {
+            let caps = self.regex.as_ref()?.captures(text)?;
+            Value::String(
+                caps.get(1)
+                    .or_else(|| caps.get(0))?
+                    .as_str()
+                    .trim()
+                    .to_owned(),
+            )
+        };
+        let as_str = |x: &Value| match x {
+            Value::String(x) => x.trim().to_owned(),
+            x => x.to_string(),
+        };
+        Some(match self.kind {
+            Kind::Auto => raw,
+            Kind::String => Value::String(as_str(&raw)),
+            Kind::Int => json!(as_str(&raw).parse::<u64>().ok()?),
+            Kind::Sum => {
+                let s = as_str(&raw).replace([' ', '\u{a0}'], "").replace(',', ".");
+                json!(crate::parse_sum(&s).or_else(|| {
+                    // JSON numbers with a single decimal digit or a trailing zero dropped
+                    s.parse::<f64>().ok().map(|x| (x * 100.0).round() as u64)
+                })?)
+            }
+            Kind::Float => json!(as_str(&raw).replace(',', ".").parse::<f64>().ok()?),
+            Kind::DateTime => {
+                let x = chrono::NaiveDateTime::parse_from_str(&as_str(&raw), &self.format).ok()?;
+                json!(x.and_utc().timestamp())
+            }
+        })
+    }
+}
+
+struct Items {
+    pointer: Option<String>,
+    regex: Option<regex::Regex>,
+    rules: Vec<Rule>,
+}
+
+pub struct Scraper {
+    exts: &'static [&'static str],
+    config: ScraperConfig,
+    rules: Vec<Rule>,
+    items: Option<Items>,
+}
+
+/// Mandatory FNS JSON fields that scraped pages often lack, and placeholder values for them. The
+/// placeholders are removed from the resulting document.
+fn placeholders() -> [(u16, &'static str, Value); 11] {
+    [
+        (
+            <fields::ReceiptNum as FieldInternal>::TAG,
+            "requestNumber",
+            json!(0),
+        ),
+        (
+            <fields::ShiftNum as FieldInternal>::TAG,
+            "shiftNumber",
+            json!(0),
+        ),
+        (
+            <fields::KktRegNum as FieldInternal>::TAG,
+            "kktRegId",
+            json!(""),
+        ),
+        (
+            <fields::TotalSum as FieldInternal>::TAG,
+            "totalSum",
+            json!(0),
+        ),
+        (
+            <fields::TotalCashSum as FieldInternal>::TAG,
+            "cashTotalSum",
+            json!(0),
+        ),
+        (
+            <fields::TotalEcashSum as FieldInternal>::TAG,
+            "ecashTotalSum",
+            json!(0),
+        ),
+        (
+            <fields::TotalPrepaidSum as FieldInternal>::TAG,
+            "prepaidSum",
+            json!(0),
+        ),
+        (
+            <fields::TotalCreditSum as FieldInternal>::TAG,
+            "creditSum",
+            json!(0),
+        ),
+        (
+            <fields::TotalProvisionSum as FieldInternal>::TAG,
+            "provisionSum",
+            json!(0),
+        ),
+        (
+            <fields::PaymentType as FieldInternal>::TAG,
+            "operationType",
+            json!(1),
+        ),
+        (
+            <fields::DocFiscalSign as FieldInternal>::TAG,
+            "fiscalSign",
+            json!(0),
+        ),
+    ]
+}
+
+fn item_placeholders() -> [(u16, &'static str, Value); 3] {
+    [
+        (
+            <fields::ItemTotalPrice as FieldInternal>::TAG,
+            "sum",
+            json!(0),
+        ),
+        (
+            <fields::ItemUnitPrice as FieldInternal>::TAG,
+            "price",
+            json!(0),
+        ),
+        (
+            <fields::ItemQuantity as FieldInternal>::TAG,
+            "quantity",
+            json!(0),
+        ),
+    ]
+}
+
+fn without(obj: &Object, tags: &[u16]) -> Object {
+    let mut ret = Object::new();
+    for (tag, value) in obj.iter_raw() {
+        if !tags.contains(&tag) {
+            ret.set_raw(tag, value);
+        }
+    }
+    ret
+}
+
+/// The QR code fields by their FNS JSON names
+pub(super) fn qr_json(rec: &Object) -> serde_json::Map<String, Value> {
+    let mut ret = serde_json::Map::new();
+    if let Ok(Some(x)) = rec.get::<fields::DriveNum>() {
+        ret.insert("fiscalDriveNumber".to_owned(), json!(x));
+    }
+    if let Ok(Some(x)) = rec.get::<fields::DocNum>() {
+        ret.insert("fiscalDocumentNumber".to_owned(), json!(x));
+    }
+    if let Ok(Some([a, b, c, d, e, f])) = rec.get::<fields::DocFiscalSign>() {
+        ret.insert(
+            "fiscalSign".to_owned(),
+            json!(u64::from_be_bytes([0, 0, a, b, c, d, e, f])),
+        );
+    }
+    if let Ok(Some(x)) = rec.get::<fields::PaymentType>() {
+        ret.insert("operationType".to_owned(), json!(u8::from(x)));
+    }
+    if let Ok(Some(x)) = rec.get::<fields::TotalSum>() {
+        ret.insert("totalSum".to_owned(), json!(x));
+    }
+    if let Ok(Some(x)) = rec.get::<fields::DateTime>() {
+        ret.insert("dateTime".to_owned(), json!(x.and_utc().timestamp()));
+    }
+    ret
+}
+
+/// Parse an FNS JSON document, either wrapped (`{"receipt": {...}}`) or a bare receipt
+pub(super) fn parse_fns_json(json: Value) -> Result<Document, Error> {
+    let doc =
+        serde_json::from_value::<fiscal_data::json::Document>(json.clone()).or_else(|err| {
+            serde_json::from_value::<fiscal_data::json::Document>(json!({ "receipt": json }))
+                .map_err(|_| err)
+        })?;
+    Ok(Document::try_from(doc)?)
+}
+
+/// Replace `{name}` placeholders with the QR code fields
+fn fill_template(template: &str, rec: &Object) -> String {
+    let mut vars = BTreeMap::<&str, String>::new();
+    if let Ok(Some(x)) = rec.get::<fields::DriveNum>() {
+        vars.insert("fn", x);
+    }
+    if let Ok(Some(x)) = rec.get::<fields::DocNum>() {
+        vars.insert("i", x.to_string());
+    }
+    if let Ok(Some([a, b, c, d, e, f])) = rec.get::<fields::DocFiscalSign>() {
+        vars.insert(
+            "fp",
+            u64::from_be_bytes([0, 0, a, b, c, d, e, f]).to_string(),
+        );
+    }
+    if let Ok(Some(x)) = rec.get::<fields::PaymentType>() {
+        vars.insert("n", u8::from(x).to_string());
+    }
+    if let Ok(Some(x)) = rec.get::<fields::TotalSum>() {
+        vars.insert("sum", x.to_string());
+        vars.insert("s", format!("{}.{:02}", x / 100, x % 100));
+    }
+    if let Ok(Some(x)) = rec.get::<fields::DateTime>() {
+        vars.insert("t", x.format(crate::QR_DATE_FORMAT1).to_string());
+        vars.insert("date", x.format("%Y-%m-%d").to_string());
+    }
+    let mut ret = template.to_owned();
+    for (k, v) in vars {
+        ret = ret.replace(&format!("{{{k}}}"), &v);
+    }
+    ret
+}
+
+impl Scraper {
+    pub fn new(config: &ScraperConfig) -> Result<Self, regex::Error> {
+        let rules = config
+            .fields
+            .iter()
+            .map(|(k, v)| Rule::new(k, v))
+            .collect::<Result<_, _>>()?;
+        let items = config
+            .items
+            .as_ref()
+            .map(|x| -> Result<_, regex::Error> {
+                Ok(Items {
+                    pointer: x.pointer.clone(),
+                    regex: x.regex.as_deref().map(regex::Regex::new).transpose()?,
+                    rules: x
+                        .fields
+                        .iter()
+                        .map(|(k, v)| Rule::new(k, v))
+                        .collect::<Result<_, _>>()?,
+                })
+            })
+            .transpose()?;
+        Ok(Self {
+            exts: match config.format {
+                Format::Json => &["json"],
+                Format::Html => &["html"],
+            },
+            config: config.clone(),
+            rules,
+            items,
+        })
+    }
+
+    /// Build the FNS JSON receipt from the response and the QR code fields. Also returns the
+    /// placeholder tags to remove from the receipt and from every item.
+    #[allow(clippy::type_complexity)]
+    fn receipt(
+        &self,
+        data: &[u8],
+        rec: &Object,
+    ) -> Result<(Value, Vec<u16>, Vec<Vec<u16>>), Error> {
+        let text = String::from_utf8_lossy(data);
+        let json = match self.config.format {
+            Format::Json => Some(serde_json::from_slice::<Value>(data)?),
+            Format::Html => None,
+        };
+        if let Some(pointer) = &self.config.document {
+            let doc = json
+                .as_ref()
+                .and_then(|x| x.pointer(pointer))
+                .ok_or(Error::MissingData("document"))?
+                .clone();
+            return Ok((doc, vec![], vec![]));
+        }
+        let mut ret = serde_json::Map::new();
+        for rule in &self.rules {
+            if let Some(value) = rule.extract(json.as_ref(), &text) {
+                ret.insert(rule.name.clone(), value);
+            }
+        }
+        let mut items = vec![];
+        if let Some(spec) = &self.items {
+            let blocks = if let Some(pointer) = &spec.pointer {
+                json.as_ref()
+                    .and_then(|x| x.pointer(pointer))
+                    .and_then(Value::as_array)
+                    .cloned()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|x| (Some(x), String::new()))
+                    .collect::<Vec<_>>()
+            } else if let Some(regex) = &spec.regex {
+                regex
+                    .find_iter(&text)
+                    .map(|x| (None, x.as_str().to_owned()))
+                    .collect()
+            } else {
+                vec![]
+            };
+            for (json, text) in blocks {
+                let mut item = serde_json::Map::new();
+                for rule in &spec.rules {
+                    if let Some(value) = rule.extract(json.as_ref(), &text) {
+                        item.insert(rule.name.clone(), value);
+                    }
+                }
+                items.push(item);
+            }
+        }
+        // the QR code is more trustworthy than a scraped page, except for the time, which it only
+        // has with minute precision
+        for (k, v) in qr_json(rec) {
+            if k == "dateTime" {
+                ret.entry(k).or_insert(v);
+            } else {
+                ret.insert(k, v);
+            }
+        }
+        if !ret.contains_key("fiscalDriveNumber")
+            || !ret.contains_key("fiscalDocumentNumber")
+            || !ret.contains_key("dateTime")
+        {
+            return Err(Error::ParseError);
+        }
+        let mut removed = vec![];
+        for (tag, name, value) in placeholders() {
+            if !ret.contains_key(name) {
+                ret.insert(name.to_owned(), value);
+                removed.push(tag);
+            }
+        }
+        let mut item_removed = vec![];
+        for item in &mut items {
+            let mut removed = vec![];
+            for (tag, name, value) in item_placeholders() {
+                if !item.contains_key(name) {
+                    item.insert(name.to_owned(), value);
+                    removed.push(tag);
+                }
+            }
+            item_removed.push(removed);
+        }
+        ret.insert("code".to_owned(), json!(3));
+        ret.insert("items".to_owned(), json!(items));
+        Ok((json!({ "receipt": ret }), removed, item_removed))
+    }
+}
+
+#[async_trait]
+impl Provider for Scraper {
+    fn id(&self) -> &str {
+        &self.config.id
+    }
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+    fn url(&self) -> &str {
+        &self.config.url
+    }
+    fn exts(&self) -> &'static [&'static str] {
+        self.exts
+    }
+    fn inn(&self) -> &str {
+        &self.config.inn
+    }
+    async fn fetch_raw_data(&self, _state: &State, rec: &mut Object) -> Result<Vec<u8>, Error> {
+        let client = reqwest::Client::builder().build()?;
+        let url = fill_template(&self.config.request_url, rec);
+        let mut req = match self.config.method {
+            Method::Get => client.get(url),
+            Method::Post => client.post(url),
+        };
+        for (k, v) in &self.config.headers {
+            req = req.header(k, fill_template(v, rec));
+        }
+        if let Some(body) = &self.config.body {
+            req = req.body(fill_template(body, rec));
+        }
+        Ok(client
+            .execute(req.build()?)
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec())
+    }
+    async fn parse(&self, _state: &State, data: &[u8], rec: Object) -> Result<Document, Error> {
+        let (json, removed, item_removed) = self.receipt(data, &rec)?;
+        let mut doc = parse_fns_json(json)?;
+        let mut data = without(doc.data(), &removed);
+        if item_removed.iter().any(|x| !x.is_empty()) {
+            let items = data
+                .get_all::<fields::ReceiptItem>()?
+                .iter()
+                .enumerate()
+                .map(|(i, x)| {
+                    without(x, item_removed.get(i).map_or(&[], Vec::as_slice)).into_bytes()
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            data.set_raw(<fields::ReceiptItem as FieldInternal>::TAG, &items);
+        }
+        *doc.data_mut() = data;
+        Ok(doc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fiscal_data::{fields, Object};
+
+    use super::{Provider, Scraper, ScraperConfig};
+
+    #[test]
+    fn test() {
+        let config: ScraperConfig = serde_json::from_str(
+            r#"{
+                "id": "test",
+                "name": "Test",
+                "request_url": "https://example.com/{fn}/{i}/{fp}?s={s}",
+                "format": "html",
+                "fields": {
+                    "user": { "regex": "<h1>([^<]*)</h1>", "kind": "string" },
+                    "totalSum": { "regex": "ИТОГ ([0-9.,]+)", "kind": "sum" },
+                    "dateTime": { "regex": "Дата ([0-9.: ]+)<", "kind": "datetime" }
+                },
+                "items": {
+                    "regex": "<li>[^<]*</li>",
+                    "fields": {
+                        "name": { "regex": "<li>([^=]*)=", "kind": "string" },
+                        "sum": { "regex": "=([0-9.,]+)<", "kind": "sum" }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let scraper = Scraper::new(&config).unwrap();
+        let mut rec = Object::new();
+        rec.set::<fields::DriveNum>("9999078900000001".to_owned())
+            .unwrap();
+        rec.set::<fields::DocNum>(12).unwrap();
+        rec.set::<fields::DocFiscalSign>([0, 0, 0, 0, 1, 0])
+            .unwrap();
+        rec.set::<fields::TotalSum>(15050).unwrap();
+        assert_eq!(
+            super::fill_template(&config.request_url, &rec),
+            "https://example.com/9999078900000001/12/256?s=150.50"
+        );
+        let rec2 = rec.clone();
+        let html = "<h1>ООО Ромашка</h1>Дата 01.02.2024 12:30<ul><li>Хлеб=50,50</li><li>Молоко=100</li></ul>ИТОГ 150,50";
+        let doc =
+            tokio_test::block_on(scraper.parse(&Default::default(), html.as_bytes(), rec)).unwrap();
+        let data = doc.data();
+        assert_eq!(
+            data.get::<fields::User>().unwrap().as_deref(),
+            Some("ООО Ромашка")
+        );
+        assert_eq!(data.get::<fields::TotalSum>().unwrap(), Some(15050));
+        assert_eq!(data.get::<fields::DocNum>().unwrap(), Some(12));
+        // placeholders are removed
+        assert!(!data.contains::<fields::ShiftNum>());
+        let items = data.get_all::<fields::ReceiptItem>().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0].get::<fields::ItemName>().unwrap().as_deref(),
+            Some("Хлеб")
+        );
+        assert_eq!(
+            items[0].get::<fields::ItemTotalPrice>().unwrap(),
+            Some(5050)
+        );
+        assert!(!items[0].contains::<fields::ItemQuantity>());
+
+        // placeholders are only removed from the items that lack the field
+        let mut config = config;
+        config.items.as_mut().unwrap().fields.insert(
+            "name".to_owned(),
+            serde_json::from_str(r#"{ "regex": "<li>([^=<]*)", "kind": "string" }"#).unwrap(),
+        );
+        let scraper = Scraper::new(&config).unwrap();
+        let html = "<h1>ООО Ромашка</h1>Дата 01.02.2024 12:30<ul><li>Хлеб=50,50</li><li>Соль</li></ul>ИТОГ 50,50";
+        let doc = tokio_test::block_on(scraper.parse(&Default::default(), html.as_bytes(), rec2))
+            .unwrap();
+        let items = doc.data().get_all::<fields::ReceiptItem>().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0].get::<fields::ItemTotalPrice>().unwrap(),
+            Some(5050)
+        );
+        assert_eq!(
+            items[1].get::<fields::ItemName>().unwrap().as_deref(),
+            Some("Соль")
+        );
+        assert!(!items[1].contains::<fields::ItemTotalPrice>());
+        assert!(!items[0].contains::<fields::ItemQuantity>());
+        assert!(!items[1].contains::<fields::ItemQuantity>());
+    }
+}
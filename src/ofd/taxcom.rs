@@ -2,7 +2,7 @@ use async_trait::async_trait;
 use fiscal_data::{fields, FieldInternal, Object};
 use serde::Serialize;
 
-use crate::{ofd::custom, parse_sum, server::State};
+use crate::{ofd::custom, parse_qr_params, parse_sum, server::State};
 
 use super::{Error, Provider};
 
@@ -65,6 +65,16 @@ impl Provider for Taxcom {
     fn inn(&self) -> &'static str {
         "7704211201"
     }
+    async fn parse_url(&self, url: &reqwest::Url) -> Result<Option<Object>, Error> {
+        if url.host_str() != Some("receipt.taxcom.ru") || !url.path().ends_with("/show") {
+            return Ok(None);
+        }
+        let mut rec = parse_qr_params(url.query().unwrap_or_default());
+        if let Some((_, id)) = url.query_pairs().find(|x| x.0 == "id") {
+            rec.set::<custom::Id>(id.into_owned())?;
+        }
+        Ok(Some(rec))
+    }
     fn cache_id(&self, rec: &Object) -> Result<String, Error> {
         let [_, _, a, b, c, d] = rec
             .get::<fields::DocFiscalSign>()?
@@ -0,0 +1,268 @@
+//! Receipts that couldn't be fetched yet (usually because the OFD hasn't received them), kept in
+//! `data/pending` and retried in the background.
+
+use std::{io, time::Duration};
+
+use chrono::{DateTime, Utc};
+use fiscal_data::{fields, Object, TlvType};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{auth, events, ofd, server::State, webhook, Config};
+
+/// Give up after this many attempts
+const MAX_ATTEMPTS: u32 = 30;
+const FIRST_DELAY: Duration = Duration::from_secs(5 * 60);
+const MAX_DELAY: Duration = Duration::from_secs(24 * 3600);
+
+fn serialize_hex<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&auth::hex(data))
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(d)?;
+    crate::decode_hex(&s).ok_or_else(|| serde::de::Error::custom("invalid hex"))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Pending {
+    pub id: String,
+    /// Who scanned the receipt
+    pub username: String,
+    /// The scanned QR code, used for opening the split page once the receipt arrives
+    pub query: String,
+    /// The parsed QR code
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub rec: Vec<u8>,
+    #[serde(with = "crate::iso8601")]
+    pub created: DateTime<Utc>,
+    pub attempts: u32,
+    #[serde(with = "crate::iso8601")]
+    pub next_attempt: DateTime<Utc>,
+    pub last_error: String,
+    /// Receipt id (`fn_i`) once it has been fetched
+    #[serde(default)]
+    pub ready: Option<String>,
+}
+
+impl Pending {
+    pub fn failed(&self) -> bool {
+        self.ready.is_none() && self.attempts >= MAX_ATTEMPTS
+    }
+    fn schedule(&mut self, now: DateTime<Utc>) {
+        let delay = FIRST_DELAY
+            .saturating_mul(2u32.saturating_pow(self.attempts.saturating_sub(1)))
+            .min(MAX_DELAY);
+        self.next_attempt = now + chrono::Duration::from_std(delay).unwrap_or_default();
+    }
+}
+
+/// Webhook payload for a receipt that couldn't be fetched
+fn fetch_failed(pending: &Pending) -> serde_json::Value {
+    serde_json::json!({
+        "id": pending.id,
+        "username": pending.username,
+        "query": pending.query,
+        "error": pending.last_error,
+        "gave_up": pending.failed(),
+    })
+}
+
+async fn save(config: &Config, pending: &Pending) -> io::Result<()> {
+    let dir = config.data_path("pending");
+    tokio::fs::create_dir_all(&dir).await?;
+    let path1 = dir.join(format!("{}.json.tmp", pending.id));
+    let path2 = dir.join(format!("{}.json", pending.id));
+    tokio::fs::write(&path1, serde_json::to_vec(pending)?).await?;
+    tokio::fs::rename(path1, path2).await
+}
+
+pub async fn load(config: &Config) -> io::Result<Vec<Pending>> {
+    let mut ret = vec![];
+    let Ok(mut dir) = tokio::fs::read_dir(config.data_path("pending")).await else {
+        return Ok(ret);
+    };
+    while let Some(file) = dir.next_entry().await? {
+        let path = file.path();
+        if !matches!(path.extension().and_then(|x| x.to_str()), Some("json")) {
+            continue;
+        }
+        match serde_json::from_slice::<Pending>(&tokio::fs::read(&path).await?) {
+            Ok(x) => ret.push(x),
+            Err(err) => log::error!("invalid pending receipt {}: {err}", path.display()),
+        }
+    }
+    ret.sort_by_key(|x| x.created);
+    Ok(ret)
+}
+
+/// Queue a receipt that failed to fetch, or update the existing entry for the same QR code
+pub async fn enqueue(
+    state: &State,
+    username: &str,
+    query: &str,
+    rec: Object,
+    err: &ofd::Error,
+) -> io::Result<()> {
+    let now = Utc::now();
+    let existing = state
+        .pending
+        .iter()
+        .find(|x| x.query == query && x.username == username)
+        .map(|x| x.value().clone());
+    let mut pending = existing.unwrap_or_else(|| Pending {
+        id: uuid::Uuid::new_v4().to_string(),
+        username: username.to_owned(),
+        query: query.to_owned(),
+        rec: Vec::new(),
+        created: now,
+        attempts: 0,
+        next_attempt: now,
+        last_error: String::new(),
+        ready: None,
+    });
+    pending.rec = rec.into_bytes().map_err(io::Error::other)?;
+    pending.attempts = pending.attempts.max(1);
+    pending.last_error = err.to_string();
+    pending.schedule(now);
+    save(&state.config, &pending).await?;
+    webhook::fire(state, "fetch_failed", fetch_failed(&pending)).await;
+    let event = events::Event::Pending {
+        id: pending.id.clone(),
+        username: pending.username.clone(),
+        ready: false,
+        failed: false,
+        removed: false,
+    };
+    state.pending.insert(pending.id.clone(), pending);
+    state.events.publish(event);
+    Ok(())
+}
+
+pub async fn remove(state: &State, id: &str) -> io::Result<()> {
+    // the id comes from the client, so only touch files we know about
+    let Some((_, pending)) = state.pending.remove(id) else {
+        return Ok(());
+    };
+    state.events.publish(events::Event::Pending {
+        id: pending.id,
+        username: pending.username,
+        ready: false,
+        failed: false,
+        removed: true,
+    });
+    match tokio::fs::remove_file(state.config.data_path(format!("pending/{id}.json"))).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Forget the entries for a receipt that has been split
+pub async fn remove_receipt(state: &State, receipt_id: &str) {
+    let ids = state
+        .pending
+        .iter()
+        .filter(|x| x.ready.as_deref() == Some(receipt_id))
+        .map(|x| x.id.clone())
+        .collect::<Vec<_>>();
+    for id in ids {
+        if let Err(err) = remove(state, &id).await {
+            log::error!("failed to remove pending receipt {id}: {err}");
+        }
+    }
+}
+
+/// Retry every entry that is due
+pub async fn retry(state: &State) {
+    let now = Utc::now();
+    let due = state
+        .pending
+        .iter()
+        .filter(|x| x.ready.is_none() && !x.failed() && x.next_attempt <= now)
+        .map(|x| x.value().clone())
+        .collect::<Vec<_>>();
+    for mut pending in due {
+        let rec = match Object::from_bytes(pending.rec.clone()) {
+            Ok(rec) => rec,
+            Err(err) => {
+                log::error!("invalid pending receipt {}: {err}", pending.id);
+                continue;
+            }
+        };
+        pending.attempts += 1;
+        match ofd::fetch(state, rec).await {
+            Ok(doc) => {
+                let rec = doc.data();
+                let r#fn = rec
+                    .get::<fields::DriveNum>()
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default();
+                let i = rec
+                    .get::<fields::DocNum>()
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default();
+                log::info!("pending receipt {} arrived as {fn}_{i:07}", pending.id);
+                pending.ready = Some(format!("{fn}_{i:07}"));
+            }
+            Err(err) => {
+                log::info!("pending receipt {} still unavailable: {err}", pending.id);
+                pending.last_error = err.to_string();
+                pending.schedule(Utc::now());
+                if pending.failed() {
+                    webhook::fire(state, "fetch_failed", fetch_failed(&pending)).await;
+                }
+            }
+        }
+        if let Err(err) = save(&state.config, &pending).await {
+            log::error!("failed to save pending receipt {}: {err}", pending.id);
+        }
+        // let the user who scanned it know
+        let event = (pending.ready.is_some() || pending.failed()).then(|| events::Event::Pending {
+            id: pending.id.clone(),
+            username: pending.username.clone(),
+            ready: pending.ready.is_some(),
+            failed: pending.failed(),
+            removed: false,
+        });
+        state.pending.insert(pending.id.clone(), pending);
+        if let Some(event) = event {
+            state.events.publish(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::Pending;
+
+    #[test]
+    fn test() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let mut pending = Pending {
+            id: "x".to_owned(),
+            username: "a".to_owned(),
+            query: "t=20240101T0000&s=1.00&fn=1&i=2&fp=3&n=1".to_owned(),
+            rec: vec![0x01, 0xab],
+            created: now,
+            attempts: 1,
+            next_attempt: now,
+            last_error: String::new(),
+            ready: None,
+        };
+        pending.schedule(now);
+        assert_eq!(pending.next_attempt - now, chrono::Duration::minutes(5));
+        pending.attempts = 3;
+        pending.schedule(now);
+        assert_eq!(pending.next_attempt - now, chrono::Duration::minutes(20));
+        pending.attempts = 25;
+        pending.schedule(now);
+        assert_eq!(pending.next_attempt - now, chrono::Duration::days(1));
+        let json = serde_json::to_string(&pending).unwrap();
+        assert!(json.contains("\"01ab\""));
+        let pending: Pending = serde_json::from_str(&json).unwrap();
+        assert_eq!(pending.rec, [0x01, 0xab]);
+    }
+}
@@ -0,0 +1,203 @@
+//! Matching refund receipts to the original purchase
+
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::NaiveDateTime;
+use fiscal_data::{enums::PaymentType, fields, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::server::State;
+
+pub struct RefundMatch {
+    /// `{fn}_{i:07}` of the original receipt
+    pub receipt_id: String,
+    /// Refund item index -> users who were charged for the original item
+    pub split: HashMap<usize, BTreeSet<String>>,
+}
+
+/// Item name and unit price
+type ItemKey = (String, u64);
+
+/// What matching needs to know about a sale receipt, kept in memory so that rendering a refund
+/// doesn't read every paid receipt
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Sale {
+    /// `{fn}_{i:07}`
+    pub receipt_id: String,
+    pub date: Option<NaiveDateTime>,
+    /// In the receipt order, `None` for items without a name
+    pub items: Vec<Option<ItemKey>>,
+}
+
+fn item_key(item: &Object) -> Option<ItemKey> {
+    Some((
+        item.get::<fields::ItemName>().ok().flatten()?,
+        item.get::<fields::ItemUnitPrice>()
+            .ok()
+            .flatten()
+            .unwrap_or_default(),
+    ))
+}
+
+fn user_inn(rec: &Object) -> Option<String> {
+    rec.get::<fields::UserInn>()
+        .ok()
+        .flatten()
+        .map(|x| x.trim().to_owned())
+}
+
+/// The store INN and the index entry for a sale receipt
+pub fn sale(rec: &Object) -> Option<(String, Sale)> {
+    if rec.get::<fields::PaymentType>().ok().flatten() != Some(PaymentType::Sale) {
+        return None;
+    }
+    let drive_num = rec.get::<fields::DriveNum>().ok().flatten()?;
+    let doc_num = rec.get::<fields::DocNum>().ok().flatten()?;
+    Some((
+        user_inn(rec)?,
+        Sale {
+            receipt_id: format!("{drive_num}_{doc_num:07}"),
+            date: rec.get::<fields::DateTime>().ok().flatten(),
+            items: rec
+                .get_all::<fields::ReceiptItem>()
+                .unwrap_or_default()
+                .iter()
+                .map(item_key)
+                .collect(),
+        },
+    ))
+}
+
+/// Add a sale to the receipts of its store, once
+pub fn insert(sales: &mut Vec<Sale>, sale: Sale) {
+    if !sales.iter().any(|x| x.receipt_id == sale.receipt_id) {
+        sales.push(sale);
+    }
+}
+
+/// Map every refunded item to an unused item of the original receipt with the same name and unit
+/// price. Returns refund item index -> original item index.
+fn match_items(refund: &[Option<ItemKey>], original: &[Option<ItemKey>]) -> HashMap<usize, usize> {
+    let mut used = BTreeSet::new();
+    let mut ret = HashMap::new();
+    for (i, key) in refund.iter().enumerate() {
+        let Some(key) = key else {
+            continue;
+        };
+        if let Some(j) = original
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| !used.contains(j))
+            .find(|(_, x)| x.as_ref() == Some(key))
+            .map(|(j, _)| j)
+        {
+            used.insert(j);
+            ret.insert(i, j);
+        }
+    }
+    ret
+}
+
+/// Find the sale receipt a refund most likely belongs to: same store INN, as many matching items
+/// as possible, most recent first. Only receipts that were split before are considered, because
+/// the split is what we want to reverse.
+pub fn find_original(state: &State, rec: &Object) -> Option<RefundMatch> {
+    if rec.get::<fields::PaymentType>().ok().flatten() != Some(PaymentType::SaleReturn) {
+        return None;
+    }
+    let inn = user_inn(rec)?;
+    let date = rec.get::<fields::DateTime>().ok().flatten();
+    let items = rec
+        .get_all::<fields::ReceiptItem>()
+        .unwrap_or_default()
+        .iter()
+        .map(item_key)
+        .collect::<Vec<_>>();
+    let sales = state.sales.get(&inn)?;
+    let mut best = None::<(usize, Option<NaiveDateTime>, RefundMatch)>;
+    for orig in sales.iter() {
+        let Some(paid) = state.receipt_payments.get(&orig.receipt_id) else {
+            continue;
+        };
+        if matches!((date, orig.date), (Some(a), Some(b)) if b > a) {
+            continue;
+        }
+        let matched = match_items(&items, &orig.items);
+        if matched.is_empty() {
+            continue;
+        }
+        if matches!(&best, Some((n, d, _)) if (*n, *d) >= (matched.len(), orig.date)) {
+            continue;
+        }
+        let split = matched
+            .into_iter()
+            .map(|(i, j)| {
+                (
+                    i,
+                    paid.iter()
+                        .filter(|(_, idx)| idx.contains(&j))
+                        .map(|(user, _)| user.clone())
+                        .collect(),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        best = Some((
+            split.len(),
+            orig.date,
+            RefundMatch {
+                receipt_id: orig.receipt_id.clone(),
+                split,
+            },
+        ));
+    }
+    best.map(|x| x.2)
+}
+
+#[cfg(test)]
+mod test {
+    use fiscal_data::{enums::PaymentType, fields, Object};
+
+    fn item(name: &str, price: u64) -> Object {
+        let mut ret = Object::new();
+        ret.set::<fields::ItemName>(name.to_owned()).unwrap();
+        ret.set::<fields::ItemUnitPrice>(price).unwrap();
+        ret
+    }
+
+    #[test]
+    fn test() {
+        let original = [item("a", 100), item("b", 200), item("a", 100)];
+        let refund = [
+            item("a", 100),
+            item("a", 100),
+            item("a", 150),
+            item("b", 200),
+        ];
+        let key = |x: &[Object]| x.iter().map(super::item_key).collect::<Vec<_>>();
+        let matched = super::match_items(&key(&refund), &key(&original));
+        assert_eq!(matched.get(&0), Some(&0));
+        assert_eq!(matched.get(&1), Some(&2));
+        assert_eq!(matched.get(&2), None);
+        assert_eq!(matched.get(&3), Some(&1));
+        // sales are indexed by store, once per receipt
+        let mut rec = Object::new();
+        rec.set::<fields::PaymentType>(PaymentType::Sale).unwrap();
+        rec.set::<fields::DriveNum>("9999078900001234".to_owned())
+            .unwrap();
+        rec.set::<fields::DocNum>(42).unwrap();
+        rec.set::<fields::UserInn>("7700000000  ".to_owned())
+            .unwrap();
+        rec.push::<fields::ReceiptItem>(item("a", 100)).unwrap();
+        let (inn, sale) = super::sale(&rec).unwrap();
+        assert_eq!(inn, "7700000000");
+        assert_eq!(sale.receipt_id, "9999078900001234_0000042");
+        assert_eq!(sale.items, [Some(("a".to_owned(), 100))]);
+        let mut sales = vec![];
+        super::insert(&mut sales, sale.clone());
+        super::insert(&mut sales, sale);
+        assert_eq!(sales.len(), 1);
+        rec.set::<fields::PaymentType>(PaymentType::SaleReturn)
+            .unwrap();
+        assert!(super::sale(&rec).is_none());
+    }
+}
@@ -0,0 +1,174 @@
+//! Receipt rules from the config, checked against the receipt however it came in (the scanner
+//! page, a photo, the API, account sync). A rule can ignore the receipt, pick the provider to
+//! fetch it from, tag the transaction or propose who to split it between.
+
+use std::collections::BTreeSet;
+
+use fiscal_data::{enums::PaymentType, fields, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::{ofd, server::State};
+
+/// What a receipt must look like for the rule to apply, every set field has to match
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Condition {
+    /// Provider ids, as chosen on the scanner page or recognized from the QR code URL
+    pub providers: Option<Vec<String>>,
+    /// Fiscal drive numbers
+    pub drives: Option<Vec<String>>,
+    /// Payment types (`n` in the QR code: 1 sale, 2 sale return, 3 purchase, 4 purchase return)
+    pub payment_types: Option<Vec<PaymentType>>,
+    /// In kopecks, inclusive
+    pub min_sum: Option<u64>,
+    /// In kopecks, inclusive
+    pub max_sum: Option<u64>,
+    /// Who scanned the receipt
+    pub users: Option<Vec<String>>,
+}
+
+impl Condition {
+    pub fn matches(&self, rec: &Object) -> bool {
+        fn check<T>(allowed: &Option<Vec<T>>, value: impl FnOnce() -> Option<T>) -> bool
+        where
+            T: PartialEq,
+        {
+            allowed
+                .as_ref()
+                .is_none_or(|allowed| value().is_some_and(|x| allowed.contains(&x)))
+        }
+        let sum = rec.get::<fields::TotalSum>().ok().flatten();
+        check(&self.drives, || {
+            rec.get::<fields::DriveNum>().ok().flatten()
+        }) && check(&self.payment_types, || {
+            rec.get::<fields::PaymentType>().ok().flatten()
+        }) && check(&self.users, || {
+            rec.get::<ofd::custom::Username>().ok().flatten()
+        }) && self.providers.as_ref().is_none_or(|allowed| {
+            rec.get_all::<ofd::custom::ProviderId>()
+                .unwrap_or_default()
+                .iter()
+                .any(|x| allowed.contains(x))
+        }) && self.min_sum.is_none_or(|min| sum.is_some_and(|x| x >= min))
+            && self.max_sum.is_none_or(|max| sum.is_some_and(|x| x <= max))
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Rule {
+    /// Shown when the rule ignores a receipt
+    pub name: String,
+    pub when: Condition,
+    /// Refuse the receipt
+    pub ignore: bool,
+    /// Fetch from this provider unless one was picked by hand
+    pub route: Option<String>,
+    /// Added to the transaction
+    pub tags: Vec<String>,
+    /// Tick these users for every item on the split page, and split between them when the API
+    /// doesn't say who had what
+    pub split: BTreeSet<String>,
+}
+
+/// All the rules that matched, merged: the first `route` and `split` win, tags add up
+#[derive(Clone, Debug, Default, Serialize, utoipa::ToSchema)]
+pub struct Outcome {
+    /// Name of the rule that ignores the receipt
+    pub ignored_by: Option<String>,
+    pub route: Option<String>,
+    pub tags: BTreeSet<String>,
+    pub split: BTreeSet<String>,
+}
+
+pub fn evaluate(rules: &[Rule], rec: &Object) -> Outcome {
+    let mut ret = Outcome::default();
+    for rule in rules.iter().filter(|x| x.when.matches(rec)) {
+        if rule.ignore && ret.ignored_by.is_none() {
+            ret.ignored_by = Some(rule.name.clone());
+        }
+        if ret.route.is_none() {
+            ret.route.clone_from(&rule.route);
+        }
+        if ret.split.is_empty() {
+            ret.split.clone_from(&rule.split);
+        }
+        ret.tags.extend(rule.tags.iter().cloned());
+    }
+    ret
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("receipt ignored by rule {0:?}")]
+pub struct Ignored(pub String);
+
+/// Check a scanned receipt before fetching it, and point it to the provider a rule routes it to
+pub async fn apply(rules: &[Rule], rec: &mut Object) -> Result<Outcome, Ignored> {
+    let outcome = evaluate(rules, rec);
+    if let Some(name) = &outcome.ignored_by {
+        return Err(Ignored(name.clone()));
+    }
+    if let Some(route) = &outcome.route {
+        if !rec.contains::<ofd::custom::ProviderId>() {
+            if let Err(err) = ofd::registry().await.fill(route, rec) {
+                log::error!("can't route to {route}: {err}");
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+/// The rules for a fetched receipt paid by `username`, which has the provider it was learned
+/// from instead of the one from the QR code
+pub async fn for_receipt(state: &State, rec: &Object, username: &str) -> Outcome {
+    let mut rec = rec.clone();
+    if !username.is_empty() {
+        let _ = rec.set::<ofd::custom::Username>(username.to_owned());
+    }
+    if !rec.contains::<ofd::custom::ProviderId>() {
+        if let Some(route) = state.routes.read().await.route(&rec) {
+            let _ = rec.push::<ofd::custom::ProviderId>(route.to_owned());
+        }
+    }
+    evaluate(&state.config.rules, &rec)
+}
+
+#[cfg(test)]
+mod test {
+    use fiscal_data::{enums::PaymentType, fields, Object};
+
+    use super::{evaluate, Rule};
+
+    #[test]
+    fn test() {
+        let rules = serde_json::from_value::<Vec<Rule>>(serde_json::json!([
+            { "name": "small", "when": { "max_sum": 5000 }, "ignore": true },
+            { "when": { "payment_types": [1], "min_sum": 100000 }, "tags": ["big"], "split": ["a", "b"] },
+            { "when": { "drives": ["7380440700000001"] }, "route": "taxcom", "tags": ["home"] },
+            { "when": { "drives": ["9999078900001234"], "providers": ["ofd-ru"] }, "ignore": true },
+        ]))
+        .unwrap();
+        let mut rec = Object::new();
+        rec.set::<fields::TotalSum>(4000).unwrap();
+        assert_eq!(evaluate(&rules, &rec).ignored_by.as_deref(), Some("small"));
+        rec.set::<fields::TotalSum>(150000).unwrap();
+        rec.set::<fields::PaymentType>(PaymentType::Sale).unwrap();
+        rec.set::<fields::DriveNum>("7380440700000001".to_owned())
+            .unwrap();
+        let outcome = evaluate(&rules, &rec);
+        assert_eq!(outcome.ignored_by, None);
+        assert_eq!(outcome.route.as_deref(), Some("taxcom"));
+        assert_eq!(outcome.split.into_iter().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(
+            outcome.tags.into_iter().collect::<Vec<_>>(),
+            ["big", "home"]
+        );
+        // a missing field never matches
+        rec.set::<fields::DriveNum>("9999078900001234".to_owned())
+            .unwrap();
+        assert_eq!(evaluate(&rules, &rec).ignored_by, None);
+        rec.set::<fields::PaymentType>(PaymentType::Purchase)
+            .unwrap();
+        assert!(evaluate(&rules, &rec).tags.is_empty());
+    }
+}
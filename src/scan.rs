@@ -0,0 +1,56 @@
+//! Decoding receipt QR codes from photos, for clients that can't scan them themselves
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("no QR code found")]
+    NotFound,
+}
+
+/// Text of the receipt QR code in a JPEG or PNG image, or of the first QR code if none of them
+/// looks like a receipt
+pub fn decode(data: &[u8]) -> Result<String, Error> {
+    let img = image::load_from_memory(data)?.to_luma8();
+    let mut img = rqrr::PreparedImage::prepare(img);
+    let codes = img
+        .detect_grids()
+        .into_iter()
+        .filter_map(|grid| grid.decode().ok().map(|(_, content)| content))
+        .collect::<Vec<_>>();
+    codes
+        .iter()
+        .find(|x| x.split('&').any(|x| x.starts_with("fn=")))
+        .or(codes.first())
+        .cloned()
+        .ok_or(Error::NotFound)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{decode, Error};
+
+    #[test]
+    fn test() {
+        let text = "t=20240203T040506&s=123.05&fn=9999078900000001&i=42&fp=258&n=1";
+        let img = qrcode::QrCode::new(text)
+            .unwrap()
+            .render::<image::Luma<u8>>()
+            .build();
+        let mut data = vec![];
+        img.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)
+            .unwrap();
+        assert_eq!(decode(&data).unwrap(), text);
+        let blank = image::GrayImage::from_pixel(64, 64, image::Luma([255]));
+        let mut data = vec![];
+        blank
+            .write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)
+            .unwrap();
+        assert!(matches!(decode(&data), Err(Error::NotFound)));
+        assert!(matches!(decode(b"not an image"), Err(Error::Image(_))));
+    }
+}
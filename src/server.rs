@@ -1,28 +1,37 @@
 use std::{
-    collections::{BTreeSet, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     convert::Infallible,
     future::Future,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::SystemTime,
 };
 
 use axum::{response::IntoResponse, routing::MethodRouter};
+use chrono::NaiveDate;
 use dashmap::{DashMap, DashSet};
-use fiscal_data::{enums::PaymentType, fields, Document, TlvType};
+use fiscal_data::{enums::PaymentType, fields, Document, Object, TlvType};
 use liquid::Template;
 use tokio::sync::RwLock;
 
 use crate::{
-    add_transaction, is_advance, item_is_advance, ofd, parse_qr, parse_sum, save_list,
-    CEscapeFilter, Comment, Commodity, Config, CurrencyFilter, ListItem, Transaction,
-    TransactionMeta,
+    allocation, api, approval, audit, auth, checkpoint, events, i18n, is_advance, item_is_advance,
+    metrics, ofd, parse_qr, pending, refund, rules, save_list, scan, sync, webhook, Added,
+    CEscapeFilter, Comment, Commodity, Config, CurrencyFilter, Idempotent, ListItem, Rejected,
+    Transaction, TransactionMeta,
 };
 
 type AxumState = axum::extract::State<State>;
 
+/// Modification time of the override and the value computed from it
+type Cached<T> = Option<(Option<SystemTime>, Arc<T>)>;
+
+/// A template or static file. Debug builds re-read it on every request, release builds only when
+/// its override in `templates_path` changes.
 pub struct FileRes<T> {
-    #[cfg(not(debug_assertions))]
-    value: Option<Arc<T>>,
-    #[cfg(debug_assertions)]
+    /// Override from `templates_path`
+    path: Option<PathBuf>,
+    cache: Arc<std::sync::Mutex<Cached<T>>>,
     compute: Arc<
         dyn 'static
             + Send
@@ -34,9 +43,8 @@ pub struct FileRes<T> {
 impl<T> Clone for FileRes<T> {
     fn clone(&self) -> Self {
         Self {
-            #[cfg(not(debug_assertions))]
-            value: self.value.clone(),
-            #[cfg(debug_assertions)]
+            path: self.path.clone(),
+            cache: self.cache.clone(),
             compute: self.compute.clone(),
         }
     }
@@ -44,52 +52,92 @@ impl<T> Clone for FileRes<T> {
 
 impl<T: 'static> FileRes<T> {
     pub async fn new<F: 'static + Future<Output = T> + Send + Sync>(
+        path: Option<PathBuf>,
         compute: impl 'static + Send + Sync + Fn() -> F,
     ) -> Self {
-        Self {
-            #[cfg(not(debug_assertions))]
-            value: Some(Arc::new(compute().await)),
-            #[cfg(debug_assertions)]
+        let ret = Self {
+            path,
+            cache: Arc::default(),
             compute: Arc::new(move || Box::pin(compute())),
-        }
+        };
+        // fail on startup rather than on the first request
+        ret.get().await;
+        ret
     }
     pub async fn get(&self) -> Arc<T> {
-        #[cfg(not(debug_assertions))]
-        {
-            self.value.clone().unwrap()
+        // without an override there's nothing to stat
+        let modified = match &self.path {
+            Some(path) => tokio::fs::metadata(path)
+                .await
+                .and_then(|x| x.modified())
+                .ok(),
+            None => None,
+        };
+        if !cfg!(debug_assertions) {
+            if let Some((time, value)) = &*self.cache.lock().unwrap() {
+                if *time == modified {
+                    return value.clone();
+                }
+            }
         }
-        #[cfg(debug_assertions)]
-        {
-            Arc::new((self.compute)().await)
+        let value = Arc::new((self.compute)().await);
+        *self.cache.lock().unwrap() = Some((modified, value.clone()));
+        value
+    }
+}
+
+/// Read an override from `templates_path`, or else the file from the working directory, or else
+/// the copy built into the binary
+pub async fn read_res(path: Option<&Path>, name: &str, builtin: &str) -> String {
+    if let Some(path) = path {
+        match tokio::fs::read_to_string(path).await {
+            Ok(x) => return x,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => log::error!("failed to read {path:?}: {err}"),
         }
     }
+    tokio::fs::read_to_string(name)
+        .await
+        .unwrap_or_else(|_| builtin.to_owned())
+}
+
+/// Where `templates_path` overrides the resource `name`: `templates/index.html` is overridden by
+/// `<templates_path>/index.html`
+pub fn override_path(config: &Config, name: &str) -> Option<PathBuf> {
+    let name = name.split_once('/').map_or(name, |x| x.1);
+    config.templates_path.as_ref().map(|x| x.join(name))
 }
 
 macro_rules! file_res {
-    ($s:literal) => {
-        async {
-            FileRes::new(|| async {
-                tokio::fs::read_to_string($s)
-                    .await
-                    .unwrap_or_else(|_| include_str!(concat!("../", $s)).to_owned())
+    ($config:expr; $s:literal) => {{
+        let path = override_path($config, $s);
+        async move {
+            let path1 = path.clone();
+            FileRes::new(path, move || {
+                let path = path1.clone();
+                async move { read_res(path.as_deref(), $s, include_str!(concat!("../", $s))).await }
             })
             .await
         }
-    };
-    ($parser:expr; $s:literal) => {{
+    }};
+    ($config:expr, $parser:expr; $s:literal) => {{
+        let path = override_path($config, $s);
         let parser = $parser.clone();
         async move {
-            let parser = parser.clone();
-            FileRes::new(move || {
+            let path1 = path.clone();
+            FileRes::new(path, move || {
                 let parser = parser.clone();
+                let path = path1.clone();
                 async move {
-                    parser
-                        .parse(
-                            &tokio::fs::read_to_string($s)
-                                .await
-                                .unwrap_or_else(|_| include_str!(concat!("../", $s)).to_owned()),
-                        )
-                        .unwrap_or_else(|err| panic!("{}: \n{err}", $s))
+                    let builtin = include_str!(concat!("../", $s));
+                    let text = read_res(path.as_deref(), $s, builtin).await;
+                    parser.parse(&text).unwrap_or_else(|err| {
+                        // a broken override shouldn't take the page down
+                        log::error!("{}: \n{err}", $s);
+                        parser
+                            .parse(builtin)
+                            .unwrap_or_else(|err| panic!("{}: \n{err}", $s))
+                    })
                 }
             })
             .await
@@ -100,9 +148,8 @@ macro_rules! file_res {
 impl<T> Default for FileRes<T> {
     fn default() -> Self {
         Self {
-            #[cfg(not(debug_assertions))]
-            value: None,
-            #[cfg(debug_assertions)]
+            path: None,
+            cache: Arc::default(),
             compute: Arc::new(move || panic!("resource not properly initialized")),
         }
     }
@@ -121,11 +168,43 @@ pub struct InnerState {
     pub submitted_t: FileRes<Template>,
     pub add_t: FileRes<Template>,
     pub list_t: FileRes<Template>,
+    pub history_t: FileRes<Template>,
+    pub reparse_t: FileRes<Template>,
+    pub login_t: FileRes<Template>,
+    pub account_t: FileRes<Template>,
+    pub audit_t: FileRes<Template>,
     pub balance: RwLock<HashMap<String, i64>>,
     pub list: RwLock<Vec<ListItem>>,
     pub commodities: DashMap<String, Commodity>,
     pub comments: DashMap<String, Comment>,
     pub paid_receipts: DashSet<String>,
+    /// Latest split of every paid receipt, used for matching refunds
+    pub receipt_payments: DashMap<String, HashMap<String, BTreeSet<usize>>>,
+    /// Sale receipts by store INN, for matching refunds
+    pub sales: DashMap<String, Vec<refund::Sale>>,
+    /// Sum of the balance changes of every day, for the balance history
+    pub daily_balance_changes: RwLock<BTreeMap<NaiveDate, HashMap<String, i64>>>,
+    /// Idempotency key -> balance after the transaction, only the recent ones
+    pub idempotency: DashMap<String, Idempotent>,
+    /// Receipts waiting to become available at the OFD
+    pub pending: DashMap<String, pending::Pending>,
+    /// Receipts imported from provider accounts
+    pub synced: DashMap<String, sync::Synced>,
+    /// Providers learned for fiscal drives and cash registers
+    pub routes: RwLock<ofd::routes::Routes>,
+    /// Passwords, sessions and API tokens
+    pub auth: RwLock<auth::Store>,
+    /// Transactions waiting for confirmation, and the rejected ones
+    pub approvals: DashMap<String, approval::Approval>,
+    /// Held while adding, confirming or rejecting approvals, so that checks see every approval and
+    /// the files are saved in the order they change
+    pub approvals_lock: tokio::sync::Mutex<()>,
+    pub events: events::Bus,
+    pub webhooks: webhook::Queue,
+    /// UI strings for every language
+    pub messages: HashMap<i18n::Lang, FileRes<i18n::Catalog>>,
+    pub metrics: metrics::Metrics,
+    pub audit: audit::Log,
 }
 
 pub type State = Arc<InnerState>;
@@ -139,8 +218,6 @@ impl InnerState {
                 .build()
                 .unwrap(),
         );
-        let comments = DashMap::<String, Comment>::new();
-        let paid_receipts = DashSet::<String>::new();
         let (
             style,
             fzf,
@@ -152,20 +229,36 @@ impl InnerState {
             submitted_t,
             add_t,
             list_t,
+            history_t,
+            reparse_t,
+            login_t,
+            account_t,
+            audit_t,
             list,
-            balance,
-            commodities,
+            (aggregates, replay_duration),
+            pending,
+            synced,
+            routes,
+            auth,
+            approvals,
+            webhooks,
+            messages,
         ) = tokio::join!(
-            file_res!("static/style.css"),
-            file_res!("static/fzf.js"),
-            file_res!("static/qr-scanner-worker.min.js"),
-            file_res!("static/qr-scanner-worker.min.js.map"),
-            file_res!("static/qr-scanner.umd.min.js"),
-            file_res!("static/qr-scanner.umd.min.js.map"),
-            file_res!(parser; "templates/index.html"),
-            file_res!(parser; "templates/submitted.html"),
-            file_res!(parser; "templates/add.html"),
-            file_res!(parser; "templates/list.html"),
+            file_res!(&config; "static/style.css"),
+            file_res!(&config; "static/fzf.js"),
+            file_res!(&config; "static/qr-scanner-worker.min.js"),
+            file_res!(&config; "static/qr-scanner-worker.min.js.map"),
+            file_res!(&config; "static/qr-scanner.umd.min.js"),
+            file_res!(&config; "static/qr-scanner.umd.min.js.map"),
+            file_res!(&config, parser; "templates/index.html"),
+            file_res!(&config, parser; "templates/submitted.html"),
+            file_res!(&config, parser; "templates/add.html"),
+            file_res!(&config, parser; "templates/list.html"),
+            file_res!(&config, parser; "templates/history.html"),
+            file_res!(&config, parser; "templates/reparse.html"),
+            file_res!(&config, parser; "templates/login.html"),
+            file_res!(&config, parser; "templates/account.html"),
+            file_res!(&config, parser; "templates/audit.html"),
             async {
                 RwLock::new(
                     serde_json::from_str::<Vec<ListItem>>(
@@ -177,117 +270,51 @@ impl InnerState {
                 )
             },
             async {
-                // fill balance and paid receipt list (can be parallelized)
-                let mut balance = HashMap::<String, i64>::new();
-                let mut dir = tokio::fs::read_dir(config.data_path("transactions"))
+                let start = std::time::Instant::now();
+                let ret = checkpoint::load(&config)
                     .await
-                    .expect("failed to read transaction list");
-                let mut files = vec![];
-                while let Some(file) = dir
-                    .next_entry()
+                    .unwrap_or_else(|err| panic!("failed to load state: {err}"));
+                (ret, start.elapsed())
+            },
+            async {
+                pending::load(&config)
                     .await
-                    .expect("failed to read transaction list entry")
-                {
-                    files.push(file.path());
-                }
-                files.sort_unstable();
-                for file in files {
-                    if !matches!(file.extension().and_then(|x| x.to_str()).map(str::to_lowercase), Some(x) if x.as_str() == "json")
-                    {
-                        continue;
-                    }
-                    let data = tokio::fs::read(&file)
-                        .await
-                        .expect("failed to read transaction");
-                    let tr = serde_json::from_slice::<Transaction>(&data).unwrap_or_else(|_| {
-                        panic!("failed to deserialize transaction {}", file.display())
-                    });
-                    match tr.meta {
-                        Some(TransactionMeta::Receipt { r#fn, i, paid: _ }) => {
-                            paid_receipts.insert(format!("{fn}_{i:07}"));
-                        }
-                        Some(TransactionMeta::Comment(comment)) => {
-                            let mut val = comments.entry(comment).or_default();
-                            let val = val.value_mut();
-                            val.last_time = tr.date;
-                            val.count += 1;
-                        }
-                        Some(TransactionMeta::Comment2(comment, price)) => {
-                            let mut val = comments.entry(comment).or_default();
-                            let val = val.value_mut();
-                            val.last_time = tr.date;
-                            val.last_price = price;
-                            val.count += 1;
-                        }
-                        None => {}
-                    }
-                    for (k, v) in &tr.balance_changes {
-                        let x = balance.entry(k.clone()).or_default();
-                        *x = x.checked_add(*v).expect("balance overflowed");
-                    }
-                }
-                balance.retain(|_, v| *v != 0);
-                balance
+                    .unwrap_or_else(|err| panic!("failed to load pending receipts: {err}"))
             },
             async {
-                let commodities = DashMap::<String, Commodity>::new();
-
-                // fill commodities (can be parallelized)
-                let mut dir = tokio::fs::read_dir(config.data_path("ffd"))
+                sync::load(&config)
+                    .await
+                    .unwrap_or_else(|err| panic!("failed to load synced receipts: {err}"))
+            },
+            async {
+                ofd::routes::load(&config)
                     .await
-                    .expect("failed to read receipt list");
-                while let Some(file) = dir
-                    .next_entry()
+                    .unwrap_or_else(|err| panic!("failed to load routes: {err}"))
+            },
+            async {
+                auth::load(&config)
                     .await
-                    .expect("failed to read receipt list entry")
-                {
-                    if !matches!(file.path().extension().and_then(|x| x.to_str()).map(str::to_lowercase), Some(x) if x.as_str() == "tlv")
-                    {
-                        continue;
-                    }
-                    let data = tokio::fs::read(file.path())
-                        .await
-                        .expect("failed to read ffd");
-                    let doc = Document::from_bytes(data).unwrap_or_else(|err| {
-                        panic!(
-                            "failed to deserialize receipt {}: {err}",
-                            file.path().display()
-                        )
-                    });
-                    let rec = doc.data();
-                    let date = rec.get::<fields::DateTime>().ok().flatten();
-                    for item in rec.get_all::<fields::ReceiptItem>().unwrap_or_default() {
-                        let name = item
-                            .get::<fields::ItemName>()
-                            .ok()
-                            .flatten()
-                            .expect("failed to read item name");
-                        let mut val = commodities.entry(name).or_default();
-                        let val = val.value_mut();
-                        if let Some(unit) = item
-                            .get::<fields::Unit>()
-                            .ok()
-                            .flatten()
-                            .filter(|unit| !unit.is_empty())
-                            .or_else(|| {
-                                item.get::<fields::ItemQuantityUnit>()
-                                    .ok()
-                                    .flatten()
-                                    .map(|x| x.to_string())
-                            })
-                        {
-                            val.unit = unit;
-                        }
-                        if let Some(date) = date {
-                            val.last_time = val.last_time.max(date);
-                        }
-                        val.count += 1;
-                    }
-                }
-                commodities
-            }
+                    .unwrap_or_else(|err| panic!("failed to load accounts: {err}"))
+            },
+            async {
+                approval::load(&config)
+                    .await
+                    .unwrap_or_else(|err| panic!("failed to load approvals: {err}"))
+            },
+            async {
+                webhook::load(&config)
+                    .await
+                    .unwrap_or_else(|err| panic!("failed to load webhooks: {err}"))
+            },
+            i18n::load(&config),
         );
 
+        let metrics = metrics::Metrics::default();
+        metrics.set(
+            "coop_fd_replay_duration_seconds",
+            &[],
+            replay_duration.as_secs_f64(),
+        );
         Self {
             config,
             style,
@@ -300,11 +327,31 @@ impl InnerState {
             submitted_t,
             add_t,
             list_t,
+            history_t,
+            reparse_t,
+            login_t,
+            account_t,
+            audit_t,
             list,
-            balance: balance.into(),
-            commodities,
-            comments,
-            paid_receipts,
+            balance: aggregates.balance.into(),
+            commodities: aggregates.commodities.into_iter().collect(),
+            comments: aggregates.comments.into_iter().collect(),
+            paid_receipts: aggregates.receipt_payments.keys().cloned().collect(),
+            receipt_payments: aggregates.receipt_payments.into_iter().collect(),
+            sales: aggregates.sales.into_iter().collect(),
+            daily_balance_changes: aggregates.daily_balance_changes.into(),
+            idempotency: aggregates.idempotency.into_iter().collect(),
+            pending: pending.into_iter().map(|x| (x.id.clone(), x)).collect(),
+            synced: synced.into_iter().map(|x| (x.id.clone(), x)).collect(),
+            routes: routes.into(),
+            auth: auth.into(),
+            approvals: approvals.into_iter().map(|x| (x.id.clone(), x)).collect(),
+            approvals_lock: tokio::sync::Mutex::default(),
+            events: events::Bus::default(),
+            webhooks: webhook::Queue::new(webhooks),
+            messages,
+            metrics,
+            audit: audit::Log::default(),
         }
         .into()
     }
@@ -344,7 +391,121 @@ pub fn res_content_type(
     })
 }
 
-pub async fn root(state: AxumState) -> axum::response::Html<String> {
+/// Position of the user in the config, for listing users in a stable order
+fn user_order(state: &State, username: &str) -> Option<usize> {
+    state.config.usernames.get_index_of(username)
+}
+
+pub async fn root(
+    state: AxumState,
+    identity: auth::Identity,
+    lang: i18n::Lang,
+    cookies: axum_extra::extract::CookieJar,
+) -> axum::response::Html<String> {
+    let username = identity.username_or_cookie(&cookies).unwrap_or_default();
+    let username = username.as_str();
+    let mut pending = state
+        .pending
+        .iter()
+        .map(|x| x.value().clone())
+        .collect::<Vec<_>>();
+    pending.sort_by_key(|x| x.created);
+    let pending = pending
+        .into_iter()
+        .map(|x| {
+            liquid::object!({
+                "id": x.id,
+                "username": x.username,
+                "mine": x.username == username,
+                "query": x.query,
+                "created": x.created.format("%Y-%m-%d %H:%M").to_string(),
+                "attempts": x.attempts,
+                "next_attempt": x.next_attempt.format("%Y-%m-%d %H:%M").to_string(),
+                "last_error": x.last_error,
+                "failed": x.failed(),
+                "ready": x.ready.is_some(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut synced = state
+        .synced
+        .iter()
+        .filter(|x| !x.hidden && !state.paid_receipts.contains(&x.id))
+        .map(|x| x.value().clone())
+        .collect::<Vec<_>>();
+    synced.sort_by(|a, b| b.date.cmp(&a.date));
+    let synced = synced
+        .into_iter()
+        .map(|x| {
+            liquid::object!({
+                "id": x.id,
+                "query": x.query,
+                "seller": x.seller,
+                "date": x.date,
+                "total": x.total,
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut approvals = state
+        .approvals
+        .iter()
+        .filter(|x| {
+            x.is_pending()
+                || x.author == username
+                || x.transaction.balance_changes.contains_key(username)
+        })
+        .map(|x| x.value().clone())
+        .collect::<Vec<_>>();
+    approvals.sort_by_key(|x| std::cmp::Reverse(x.created));
+    let approvals = approvals
+        .into_iter()
+        .map(|x| {
+            let mut changes = x.transaction.balance_changes.into_iter().collect::<Vec<_>>();
+            changes.sort_by_key(|(user, _)| user_order(&state, user));
+            let (rejected_by, reason) = match x.status {
+                approval::Status::Pending => (None, None),
+                approval::Status::Rejected { by, reason, .. } => (Some(by), Some(reason)),
+            };
+            liquid::object!({
+                "id": x.id,
+                "author": x.author,
+                "created": x.created.format("%Y-%m-%d %H:%M").to_string(),
+                "receipt": match &x.transaction.meta {
+                    Some(TransactionMeta::Receipt { r#fn, i, .. }) => format!("{fn}_{i:07}"),
+                    _ => String::new(),
+                },
+                "comment": match &x.transaction.meta {
+                    Some(TransactionMeta::Comment(x) | TransactionMeta::Comment2(x, _)) => x.clone(),
+                    _ => String::new(),
+                },
+                "changes": changes
+                    .into_iter()
+                    .map(|(username, change)| liquid::object!({
+                        "username": username,
+                        "change": change,
+                    }))
+                    .collect::<Vec<_>>(),
+                "waiting": x.waiting.iter().cloned().collect::<Vec<_>>(),
+                "mine": x.waiting.contains(username),
+                "rejected": rejected_by.is_some(),
+                "rejected_by": rejected_by.unwrap_or_default(),
+                "reason": reason.unwrap_or_default(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut pending_balance = approval::pending_balance(&state)
+        .into_iter()
+        .collect::<Vec<_>>();
+    pending_balance.sort_by_key(|(user, _)| user_order(&state, user));
+    let pending_balance = pending_balance
+        .into_iter()
+        .map(|(username, change)| {
+            liquid::object!({
+                "username": username,
+                "change": change,
+            })
+        })
+        .collect::<Vec<_>>();
     let comments = state
         .comments
         .iter()
@@ -364,8 +525,12 @@ pub async fn root(state: AxumState) -> axum::response::Html<String> {
             .get()
             .await
             .render(&liquid::object!({
+                "t": &*i18n::catalog(&state, lang).await,
                 "comments": comments,
-                "extra_qr_processing": format!("if({})return;", state.config.ignore_qr_condition),
+                "pending": pending,
+                "synced": synced,
+                "approvals": approvals,
+                "pending_balance": pending_balance,
                 "usernames": &state.config.usernames,
                 "ofds": ofd::registry()
                     .await
@@ -381,23 +546,84 @@ pub async fn root(state: AxumState) -> axum::response::Html<String> {
     )
 }
 
-pub fn json_endpoint_get<F: 'static + Send + Sync + Future<Output = String>>(
-    func: fn(State) -> F,
-) -> MethodRouter<State, axum::body::Body, Infallible> where {
-    axum::routing::get(move |state: AxumState| async move {
-        (
-            [(
-                axum::http::header::CONTENT_TYPE,
-                axum::http::HeaderValue::from_static("application/json"),
-            )],
-            func(state.0).await,
-        )
-            .into_response()
-    })
+/// Idempotency key from the `Idempotency-Key` header or the `idempotency_key` form field
+fn idempotency_key(headers: &axum::http::HeaderMap, f: &HashMap<String, String>) -> Option<String> {
+    headers
+        .get("idempotency-key")
+        .and_then(|x| x.to_str().ok())
+        .or_else(|| f.get("idempotency_key").map(String::as_str))
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::to_owned)
 }
 
+async fn render_submitted(
+    state: &State,
+    lang: i18n::Lang,
+    prefix: &str,
+    balance: HashMap<String, i64>,
+    username: &str,
+    removed: &[String],
+    pending: bool,
+) -> String {
+    let mut balance = balance.into_iter().collect::<Vec<_>>();
+    balance.sort_by_key(|(k, _)| {
+        state
+            .config
+            .usernames
+            .iter()
+            .enumerate()
+            .find(|(_, x)| &k == x)
+            .map(|x| x.0)
+    });
+    let balance = balance
+        .into_iter()
+        .map(|(username, balance)| {
+            liquid::object!({
+                "username": username,
+                "balance": balance,
+            })
+        })
+        .collect::<Vec<_>>();
+    state
+        .submitted_t
+        .get()
+        .await
+        .render(&liquid::object!({
+            "t": &*i18n::catalog(state, lang).await,
+            "prefix": prefix,
+            "balance": balance,
+            "username": username,
+            "removed": removed,
+            "pending": pending,
+        }))
+        .unwrap_or_else(|err| format!("Error: {err}"))
+}
+
+/// Add a payment from the web UI's form. Legacy: the fields aren't validated beyond what the form
+/// needs and errors are plain text, prefer `/api/receipt/split` for receipts.
+#[utoipa::path(
+    post,
+    path = "/api/pay",
+    request_body(
+        content = HashMap<String, String>,
+        content_type = "application/x-www-form-urlencoded",
+        description = "`to`, `amount` in kopecks, optional `from` (everyone by default), `comment` \
+            and `idempotency_key`",
+    ),
+    responses(
+        (status = 200, body = HashMap<String, i64>, description = "The new balance, or a plain text error"),
+        (status = 202, body = HashMap<String, i64>, description = "Held for confirmation, the balance doesn't include it"),
+        (status = 403, description = "Neither the payer nor the recipient"),
+        (status = 409, description = "Already paid"),
+    )
+)]
 pub async fn api_pay(
     axum::extract::State(state): AxumState,
+    identity: auth::Identity,
+    audit: audit::Context,
+    lang: i18n::Lang,
+    headers: axum::http::HeaderMap,
     axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
 ) -> axum::http::Response<http_body::combinators::UnsyncBoxBody<axum::body::Bytes, axum::Error>> {
     (
@@ -409,20 +635,9 @@ pub async fn api_pay(
             if let Some(to) = f.get("to") {
                 if let Some(amt) = f.get("amount") {
                     if let Some(amt) = amt.parse::<i64>().ok().filter(|x| *x != 0) {
-                        let mut tr = f.get("comment").map_or_else(
-                            || Transaction::new(None),
-                            |x| {
-                                let mut val = state.comments.entry(x.clone()).or_default();
-                                let val = val.value_mut();
-                                val.last_price = amt;
-                                val.count += 1;
-                                let tr = Transaction::new(Some(TransactionMeta::Comment2(
-                                    x.clone(),
-                                    amt,
-                                )));
-                                val.last_time = tr.date;
-                                tr
-                            },
+                        let mut tr = Transaction::new(
+                            f.get("comment")
+                                .map(|x| TransactionMeta::Comment2(x.clone(), amt)),
                         );
                         let is_html = matches!(f.get("response-format"), Some(x) if x == "html");
                         if is_html {
@@ -458,33 +673,87 @@ pub async fn api_pay(
                             }
                         }
                         tr.finalize();
-                        let balance = add_transaction(&state, tr).await;
+                        // only the payer or the recipient may add a payment
+                        if !tr
+                            .balance_changes
+                            .keys()
+                            .chain([to])
+                            .any(|x| identity.can_act_as(x))
+                        {
+                            return (axum::http::StatusCode::FORBIDDEN, "forbidden".to_owned())
+                                .into_response();
+                        }
+                        let date = tr.date;
+                        let author = identity.username.as_deref().unwrap_or(to);
+                        tr.set_idempotency_key(author, idempotency_key(&headers, &f));
+                        let Added {
+                            balance,
+                            id,
+                            pending,
+                        } = match approval::add(&state, tr, author, false).await {
+                            Ok(ret) => {
+                                if let Some(comment) = f.get("comment") {
+                                    let mut val =
+                                        state.comments.entry(comment.clone()).or_default();
+                                    let val = val.value_mut();
+                                    val.last_price = amt;
+                                    val.count += 1;
+                                    val.last_time = date;
+                                }
+                                ret
+                            }
+                            Err(Rejected::Replay(balance)) => Added {
+                                balance,
+                                ..Added::default()
+                            },
+                            Err(Rejected::AlreadyPaid) => {
+                                audit
+                                    .record(&state, &f, Err("already paid".to_owned()))
+                                    .await;
+                                return (
+                                    axum::http::StatusCode::CONFLICT,
+                                    "already paid".to_owned(),
+                                )
+                                    .into_response();
+                            }
+                        };
+                        audit
+                            .record(
+                                &state,
+                                &f,
+                                Ok(id.into_iter().chain(pending.clone()).collect()),
+                            )
+                            .await;
                         if is_html {
-                            let balance = balance
-                                .into_iter()
-                                .map(|(username, balance)| {
-                                    liquid::object!({
-                                        "username": username,
-                                        "balance": balance,
-                                    })
-                                })
-                                .collect::<Vec<_>>();
                             return axum::response::Html::from(
-                                state
-                                    .submitted_t
-                                    .get()
-                                    .await
-                                    .render(&liquid::object!({
-                                        "prefix": "..",
-                                        "balance": balance,
-                                        "username": to,
-                                        "removed": [],
-                                    }))
-                                    .unwrap_or_else(|err| format!("Error: {err}")),
+                                render_submitted(
+                                    &state,
+                                    lang,
+                                    "..",
+                                    balance,
+                                    to,
+                                    &[],
+                                    pending.is_some(),
+                                )
+                                .await,
                             )
                             .into_response();
                         }
-                        serde_json::to_string(&balance).expect("balance serialization failed")
+                        let body =
+                            serde_json::to_string(&balance).expect("balance serialization failed");
+                        if pending.is_some() {
+                            // the balance doesn't include this payment yet
+                            return (
+                                axum::http::StatusCode::ACCEPTED,
+                                [(
+                                    axum::http::header::CONTENT_TYPE,
+                                    axum::http::HeaderValue::from_static("application/json"),
+                                )],
+                                body,
+                            )
+                                .into_response();
+                        }
+                        body
                     } else {
                         "invalid amount".to_owned()
                     }
@@ -499,7 +768,27 @@ pub async fn api_pay(
         .into_response()
 }
 
-pub async fn list(axum::extract::State(state): AxumState) -> axum::response::Html<String> {
+pub async fn history(
+    axum::extract::State(state): AxumState,
+    lang: i18n::Lang,
+) -> axum::response::Html<String> {
+    axum::response::Html::from(
+        state
+            .history_t
+            .get()
+            .await
+            .render(&liquid::object!({
+                "t": &*i18n::catalog(&state, lang).await,
+                "usernames": &state.config.usernames,
+            }))
+            .unwrap_or_else(|err| format!("Error: {err}")),
+    )
+}
+
+pub async fn list(
+    axum::extract::State(state): AxumState,
+    lang: i18n::Lang,
+) -> axum::response::Html<String> {
     let items = state
         .commodities
         .iter()
@@ -519,6 +808,7 @@ pub async fn list(axum::extract::State(state): AxumState) -> axum::response::Htm
             .get()
             .await
             .render(&liquid::object!({
+                "t": &*i18n::catalog(&state, lang).await,
                 "items": items,
                 "list": state.list.read().await.iter().map(|x| {
                     liquid::object!({
@@ -536,78 +826,436 @@ pub async fn list(axum::extract::State(state): AxumState) -> axum::response::Htm
     )
 }
 
+async fn render_reparse(
+    state: &State,
+    lang: i18n::Lang,
+    provider: &str,
+    written: Option<(usize, Vec<ofd::reparse::Selected>)>,
+) -> String {
+    let docs = match ofd::reparse::reparse(state, Some(provider).filter(|x| !x.is_empty())).await {
+        Ok(x) => x,
+        Err(err) => return format!("Error: {err}"),
+    };
+    let mut ofds = ofd::registry()
+        .await
+        .all()
+        .map(|x| x.id().to_owned())
+        .collect::<Vec<_>>();
+    ofds.dedup();
+    let (written, stale) = written.unzip();
+    state
+        .reparse_t
+        .get()
+        .await
+        .render(&liquid::object!({
+            "t": &*i18n::catalog(state, lang).await,
+            "ofds": ofds,
+            "provider": provider,
+            "written": written.map(|x| x.to_string()).unwrap_or_default(),
+            "stale": liquid::model::to_value(&stale.unwrap_or_default()).unwrap_or_default(),
+            "docs": liquid::model::to_value(&docs).unwrap_or_default(),
+        }))
+        .unwrap_or_else(|err| format!("Error: {err}"))
+}
+
+/// Dry run of reparsing the raw cache
+pub async fn reparse(
+    axum::extract::State(state): AxumState,
+    lang: i18n::Lang,
+    axum::extract::Query(q): axum::extract::Query<HashMap<String, String>>,
+) -> axum::response::Html<String> {
+    let provider = q.get("provider").map_or("", String::as_str);
+    axum::response::Html::from(render_reparse(&state, lang, provider, None).await)
+}
+
+/// Overwrite the documents selected on the dry run page, if they still reparse the same way
+pub async fn reparse_apply(
+    axum::extract::State(state): AxumState,
+    audit: audit::Context,
+    lang: i18n::Lang,
+    axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
+) -> axum::response::Html<String> {
+    let provider = f.get("provider").map_or("", String::as_str);
+    let selected = f
+        .iter()
+        .filter(|(_, v)| !matches!(v.as_str(), "" | "off" | "0" | "false"))
+        .filter_map(|(k, _)| k.strip_prefix("doc:"))
+        .filter_map(|n| {
+            Some(ofd::reparse::Selected {
+                provider: f.get(&format!("provider:{n}"))?.clone(),
+                id: f.get(&format!("id:{n}"))?.clone(),
+                digest: f.get(&format!("digest:{n}"))?.clone(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let written = ofd::reparse::apply(&state, &selected).await;
+    audit
+        .record(
+            &state,
+            &f,
+            written
+                .as_ref()
+                .map(|(_, stale)| {
+                    selected
+                        .iter()
+                        .filter(|x| {
+                            !stale
+                                .iter()
+                                .any(|y| y.provider == x.provider && y.id == x.id)
+                        })
+                        .map(|x| format!("{}/{}", x.provider, x.id))
+                        .collect()
+                })
+                .map_err(ToString::to_string),
+        )
+        .await;
+    match written {
+        Ok(written) => {
+            axum::response::Html::from(render_reparse(&state, lang, provider, Some(written)).await)
+        }
+        Err(err) => axum::response::Html::from(format!("Error: {err}")),
+    }
+}
+
+/// The receipt to reconcile, either `id=fn_i` of a stored receipt or a QR code
+async fn reconcile_rec(state: &State, q: &str) -> Result<Object, String> {
+    if let Some(id) = q.strip_prefix("id=") {
+        if !id.bytes().all(|x| x.is_ascii_digit() || x == b'_') {
+            return Err("invalid id".to_owned());
+        }
+        let data = tokio::fs::read(state.config.data_path(format!("ffd/{id}.tlv")))
+            .await
+            .map_err(|err| err.to_string())?;
+        let doc = Document::from_bytes(data).map_err(|err| err.to_string())?;
+        Ok(ofd::reparse::base_fields(doc.data()))
+    } else {
+        Ok(parse_qr(q).await)
+    }
+}
+
+async fn reconcile_impl(state: &State, q: &str, save: bool) -> Result<String, String> {
+    let rec = reconcile_rec(state, q).await?;
+    let ret = ofd::reconcile::reconcile(state, rec)
+        .await
+        .map_err(|err| err.to_string())?;
+    if save {
+        ofd::reconcile::save(state, &ret)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+    Ok(serde_json::to_string(&ret).expect("report serialization failed"))
+}
+
+/// Dry run of reconciling a receipt across providers
+pub async fn reconcile(
+    axum::extract::State(state): AxumState,
+    axum::extract::RawQuery(q): axum::extract::RawQuery,
+) -> axum::http::Response<http_body::combinators::UnsyncBoxBody<axum::body::Bytes, axum::Error>> {
+    match reconcile_impl(&state, &q.unwrap_or_default(), false).await {
+        Ok(x) => (
+            [(
+                axum::http::header::CONTENT_TYPE,
+                axum::http::HeaderValue::from_static("application/json"),
+            )],
+            x,
+        )
+            .into_response(),
+        Err(err) => (axum::http::StatusCode::BAD_REQUEST, err).into_response(),
+    }
+}
+
+/// Reconcile a receipt across providers and store the merged document
+pub async fn reconcile_save(
+    axum::extract::State(state): AxumState,
+    audit: audit::Context,
+    axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
+) -> axum::http::Response<http_body::combinators::UnsyncBoxBody<axum::body::Bytes, axum::Error>> {
+    let q = match (f.get("id"), f.get("qr")) {
+        (Some(id), _) => format!("id={id}"),
+        (None, Some(qr)) => qr.clone(),
+        (None, None) => {
+            return (axum::http::StatusCode::BAD_REQUEST, "missing id").into_response();
+        }
+    };
+    let res = reconcile_impl(&state, &q, true).await;
+    audit
+        .record(
+            &state,
+            &f,
+            res.as_ref()
+                .map(|_| f.get("id").cloned().into_iter().collect())
+                .map_err(Clone::clone),
+        )
+        .await;
+    match res {
+        Ok(x) => (
+            [(
+                axum::http::header::CONTENT_TYPE,
+                axum::http::HeaderValue::from_static("application/json"),
+            )],
+            x,
+        )
+            .into_response(),
+        Err(err) => (axum::http::StatusCode::BAD_REQUEST, err).into_response(),
+    }
+}
+
+pub async fn pending_remove(
+    axum::extract::State(state): AxumState,
+    audit: audit::Context,
+    axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
+) -> axum::response::Redirect {
+    if let Some(id) = f.get("id") {
+        let res = pending::remove(&state, id).await;
+        if let Err(err) = &res {
+            log::error!("failed to remove pending receipt {id}: {err}");
+        }
+        let res = res.map(|()| vec![id.clone()]).map_err(|x| x.to_string());
+        audit.record(&state, &f, res).await;
+    }
+    axum::response::Redirect::to("..")
+}
+
+pub async fn synced_hide(
+    axum::extract::State(state): AxumState,
+    audit: audit::Context,
+    axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
+) -> axum::response::Redirect {
+    if let Some(id) = f.get("id") {
+        let res = sync::hide(&state, id).await;
+        if let Err(err) = &res {
+            log::error!("failed to hide synced receipt {id}: {err}");
+        }
+        let res = res.map(|()| vec![id.clone()]).map_err(|x| x.to_string());
+        audit.record(&state, &f, res).await;
+    }
+    axum::response::Redirect::to("..")
+}
+
+/// Tell the open pages and the webhooks about a shopping list change
+async fn list_changed(state: &State, list: &[ListItem]) {
+    state.events.publish(events::Event::List(list.to_vec()));
+    webhook::fire(state, "list", list).await;
+}
+
+/// Remove an item from the shopping list, returns whether it was there
+pub async fn list_remove(state: &State, name: &str) -> bool {
+    let mut list = state.list.write().await;
+    let len = list.len();
+    list.retain(|x| x.name != name);
+    let removed = list.len() != len;
+    let _ = save_list(&state.config, &list).await;
+    list_changed(state, &list).await;
+    removed
+}
+
+/// Add an item to the shopping list, or increase its amount if it's already there
+pub async fn list_add(state: &State, name: &str, amount: f64) -> Vec<ListItem> {
+    let mut list = state.list.write().await;
+    let mut added = false;
+    for item in &mut *list {
+        if item.name == name {
+            item.amount += amount;
+            added = true;
+        }
+    }
+    if !added {
+        list.push(ListItem {
+            name: name.to_owned(),
+            amount,
+        });
+    }
+    let _ = save_list(&state.config, &list).await;
+    list_changed(state, &list).await;
+    list.clone()
+}
+
+/// Confirm or reject an approval from the main page, the form's `username` field selects who
+/// is confirming when it isn't the logged in user
+async fn approval_decide(
+    state: &State,
+    identity: &auth::Identity,
+    cookies: &axum_extra::extract::CookieJar,
+    audit: audit::Context,
+    lang: i18n::Lang,
+    f: &HashMap<String, String>,
+    reject: bool,
+) -> axum::response::Response {
+    let t = i18n::catalog(state, lang).await;
+    let error = |err: api::ApiError| {
+        (err.status(), axum::response::Html::from(t.error(&err))).into_response()
+    };
+    let (Some(id), Some(username)) = (
+        f.get("id"),
+        f.get("username")
+            .filter(|x| !x.is_empty())
+            .cloned()
+            .or_else(|| identity.username_or_cookie(cookies)),
+    ) else {
+        return error(api::ApiError::bad_request(t.get("missing_approval")));
+    };
+    if !identity.can_act_as(&username) {
+        return error(api::ApiError::new(
+            axum::http::StatusCode::FORBIDDEN,
+            "forbidden",
+            t.get("forbidden"),
+        ));
+    }
+    let res = if reject {
+        let reason = f.get("reason").map_or("", String::as_str);
+        approval::reject(state, id, &username, reason).await
+    } else {
+        approval::confirm(state, id, &username).await
+    };
+    let ids = res
+        .as_ref()
+        .map(|()| vec![id.clone()])
+        .map_err(ToString::to_string);
+    audit.record(state, f, ids).await;
+    match res {
+        Ok(()) => axum::response::Redirect::to("..").into_response(),
+        Err(err) => error(err.into()),
+    }
+}
+
+pub async fn approval_confirm(
+    axum::extract::State(state): AxumState,
+    identity: auth::Identity,
+    cookies: axum_extra::extract::CookieJar,
+    audit: audit::Context,
+    lang: i18n::Lang,
+    axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
+) -> axum::response::Response {
+    approval_decide(&state, &identity, &cookies, audit, lang, &f, false).await
+}
+
+pub async fn approval_reject(
+    axum::extract::State(state): AxumState,
+    identity: auth::Identity,
+    cookies: axum_extra::extract::CookieJar,
+    audit: audit::Context,
+    lang: i18n::Lang,
+    axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
+) -> axum::response::Response {
+    approval_decide(&state, &identity, &cookies, audit, lang, &f, true).await
+}
+
 pub async fn listremove(
     axum::extract::State(state): AxumState,
+    audit: audit::Context,
     axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
 ) -> axum::response::Redirect {
     if let Some(name) = f.get("name") {
-        let mut list = state.list.write().await;
-        list.retain(|x| &x.name != name);
-        let _ = save_list(&state.config, &list).await;
+        let res = if list_remove(&state, name).await {
+            Ok(vec![name.clone()])
+        } else {
+            Err("not in the list".to_owned())
+        };
+        audit.record(&state, &f, res).await;
     }
     axum::response::Redirect::to("list")
 }
 
 pub async fn listadd(
     axum::extract::State(state): AxumState,
+    audit: audit::Context,
     axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
 ) -> axum::response::Redirect {
     if let Some(name) = f.get("name") {
         if let Some(amount) = f.get("amount").and_then(|x| x.parse::<f64>().ok()) {
-            let mut list = state.list.write().await;
-            let mut added = false;
-            for item in &mut *list {
-                if &item.name == name {
-                    item.amount += amount;
-                    added = true;
-                }
-            }
-            if !added {
-                list.push(ListItem {
-                    name: name.clone(),
-                    amount,
-                });
-            }
-            let _ = save_list(&state.config, &list).await;
+            list_add(&state, name, amount).await;
+            audit.record(&state, &f, Ok(vec![name.clone()])).await;
         }
     }
     axum::response::Redirect::to("list")
 }
 
-pub async fn submit(
-    axum::extract::State(state): AxumState,
-    axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
-) -> axum::response::Html<String> {
-    let Some(r#fn) = f
-        .get("fn")
-        .filter(|x| x.bytes().all(|x| x.is_ascii_digit()))
-    else {
-        return axum::response::Html::from("missing fn".to_owned());
-    };
-    let Some(i) = f.get("i").and_then(|x| x.parse::<u32>().ok()) else {
-        return axum::response::Html::from("missing i".to_owned());
-    };
-    let Some(username) = f.get("username") else {
-        return axum::response::Html::from("missing username".to_owned());
-    };
+#[derive(Debug, thiserror::Error)]
+pub enum SplitError {
+    #[error("missing receipt cache 1")]
+    MissingReceipt,
+    #[error("invalid receipt cache 2")]
+    InvalidReceipt,
+    #[error("invalid payment type")]
+    InvalidPaymentType,
+    #[error("receipt already paid")]
+    AlreadyPaid,
+    #[error("receipt ignored by rule {0:?}")]
+    Ignored(String),
+}
+
+pub struct Split {
+    pub r#fn: String,
+    pub i: u32,
+    /// Who paid for the receipt
+    pub username: String,
+    /// Item indices each user is paying for
+    pub paid: HashMap<String, BTreeSet<usize>>,
+    pub refund_of: Option<String>,
+    pub idempotency_key: Option<String>,
+    pub force: bool,
+    /// Who is adding the split, the other users it charges have to confirm it
+    pub author: String,
+}
+
+pub struct Submitted {
+    pub balance: HashMap<String, i64>,
+    /// Shopping list items that were bought
+    pub removed: Vec<String>,
+    /// Id of the added transaction
+    pub transaction: Option<String>,
+    /// Approval id if the split waits for confirmation
+    pub pending: Option<String>,
+}
+
+impl Submitted {
+    /// The receipt, and the transaction or approval added for it
+    pub fn ids(&self, r#fn: &str, i: u32) -> Vec<String> {
+        [
+            Some(format!("{fn}_{i:07}")),
+            self.transaction.clone(),
+            self.pending.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Add the transaction for splitting a stored receipt, and update the shopping list and the
+/// commodity stats
+/// Read a fetched receipt from the cache
+pub async fn load_receipt(state: &State, r#fn: &str, i: u32) -> Result<Document, SplitError> {
     let path = state.config.data_path(format!("ffd/{fn}_{i:07}.tlv"));
     let Ok(data) = tokio::fs::read(&path).await else {
         log::error!("missing {path:?}");
-        return axum::response::Html::from("missing receipt cache 1".to_owned());
-    };
-    let Ok(doc) = Document::from_bytes(data) else {
-        return axum::response::Html::from("invalid receipt cache 2".to_owned());
-    };
-    let rec = doc.data();
-    let Ok(payment_type) = rec.get::<fields::PaymentType>() else {
-        return axum::response::Html::from("invalid payment type".to_owned());
-    };
-    let invert = match payment_type {
-        Some(PaymentType::Sale | PaymentType::PurchaseReturn) => false,
-        Some(PaymentType::Purchase | PaymentType::SaleReturn) => true,
-        _ => return axum::response::Html::from("invalid payment type".to_owned()),
+        return Err(SplitError::MissingReceipt);
     };
+    Document::from_bytes(data).map_err(|_| SplitError::InvalidReceipt)
+}
+
+/// Apply a paid receipt: forget it in the queue, remember the sale for refunds, cross its items
+/// off the shopping list and count them. Returns the shopping list entries that were removed.
+pub async fn receipt_paid(state: &State, rec: &Object) -> Vec<String> {
+    let r#fn = rec
+        .get::<fields::DriveNum>()
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let i = rec
+        .get::<fields::DocNum>()
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    pending::remove_receipt(state, &format!("{fn}_{i:07}")).await;
+    if let Some((inn, sale)) = refund::sale(rec) {
+        refund::insert(&mut state.sales.entry(inn).or_default(), sale);
+    }
     let mut removed = Vec::<String>::new();
+    let invert = matches!(
+        rec.get::<fields::PaymentType>(),
+        Ok(Some(PaymentType::Purchase | PaymentType::SaleReturn))
+    );
     if !invert {
         let mut list = state.list.write().await;
         list.retain_mut(|list_item| {
@@ -635,67 +1283,10 @@ pub async fn submit(
             ret
         });
         let _ = save_list(&state.config, &list).await;
+        list_changed(state, &list).await;
     }
-    let mut paid = HashMap::<String, BTreeSet<usize>>::new();
-    let mut per_item = HashMap::<usize, HashSet<String>>::new();
-    for (k, v) in &f {
-        let Some((username, idx)) = k.split_once('$') else {
-            continue;
-        };
-        if matches!(v.as_str(), "" | "off" | "0" | "false") {
-            continue;
-        }
-        let Ok(idx) = idx.parse::<usize>() else {
-            continue;
-        };
-        paid.entry(username.to_owned()).or_default().insert(idx);
-        per_item.entry(idx).or_default().insert(username.to_owned());
-    }
-    let mut groups = HashMap::<Vec<String>, Vec<usize>>::new();
-    for (k, v) in per_item {
-        let (mut k, v) = (v.into_iter().collect::<Vec<_>>(), k);
-        k.sort();
-        groups.entry(k).or_default().push(v);
-    }
-    for v in groups.values_mut() {
-        v.sort_unstable();
-    }
-    let mut tr = Transaction::new(Some(TransactionMeta::Receipt {
-        r#fn: r#fn.clone(),
-        i,
-        paid,
-    }));
-    let items = rec.get_all::<fields::ReceiptItem>().unwrap_or_default();
-    for (k, v) in &groups {
-        let total: u64 = v
-            .iter()
-            .filter_map(|x| items.get(*x))
-            .map(|item| {
-                item.get::<fields::ItemTotalPrice>()
-                    .ok()
-                    .flatten()
-                    .unwrap_or_default()
-            })
-            .sum();
-        for user in k {
-            if user != username {
-                tr.pay(
-                    user,
-                    username,
-                    (total / u64::try_from(k.len()).expect("wtf, 128-bit cpus???"))
-                        .try_into()
-                        .expect("u64->i64 conversion failed"),
-                );
-            }
-        }
-    }
-    if invert {
-        tr.invert();
-    }
-    tr.finalize();
-    let balance = add_transaction(&state, tr).await;
     let date = rec.get::<fields::DateTime>().ok().flatten();
-    for item in &items {
+    for item in rec.get_all::<fields::ReceiptItem>().unwrap_or_default() {
         let name = item
             .get::<fields::ItemName>()
             .ok()
@@ -722,211 +1313,554 @@ pub async fn submit(
         }
         val.count += 1;
     }
-    state.paid_receipts.insert(format!("{fn}_{i:07}"));
-    let mut balance = balance.into_iter().collect::<Vec<_>>();
-    balance.sort_by_key(|(k, _)| {
-        state
-            .config
-            .usernames
+    removed
+}
+
+pub async fn split_receipt(state: &State, split: Split) -> Result<Submitted, SplitError> {
+    let Split {
+        r#fn,
+        i,
+        username,
+        paid,
+        refund_of,
+        idempotency_key,
+        force,
+        author,
+    } = split;
+    let doc = load_receipt(state, &r#fn, i).await?;
+    let rec = doc.data();
+    let invert = match rec.get::<fields::PaymentType>() {
+        Ok(Some(PaymentType::Sale | PaymentType::PurchaseReturn)) => false,
+        Ok(Some(PaymentType::Purchase | PaymentType::SaleReturn)) => true,
+        _ => return Err(SplitError::InvalidPaymentType),
+    };
+    let outcome = rules::for_receipt(state, rec, &username).await;
+    if let Some(rule) = outcome.ignored_by {
+        return Err(SplitError::Ignored(rule));
+    }
+    let items = rec.get_all::<fields::ReceiptItem>().unwrap_or_default();
+    let allocation = state.config.allocation.rules(rec);
+    let mut paid = paid;
+    // nobody said who had what, so the rules may
+    if paid.values().all(BTreeSet::is_empty) && !outcome.split.is_empty() {
+        let all = (0..items.len())
+            .filter(|x| !allocation.is_fee(&items[*x]))
+            .collect::<BTreeSet<_>>();
+        paid = outcome
+            .split
             .iter()
-            .enumerate()
-            .find(|(_, x)| &k == x)
-            .map(|x| x.0)
-    });
-    let balance = balance
-        .into_iter()
-        .map(|(username, balance)| {
-            liquid::object!({
-                "username": username,
-                "balance": balance,
+            .map(|user| (user.clone(), all.clone()))
+            .collect();
+    }
+    let mut per_item = HashMap::<usize, HashSet<String>>::new();
+    for (user, items) in &paid {
+        for idx in items {
+            per_item.entry(*idx).or_default().insert(user.clone());
+        }
+    }
+    let refund_of = refund_of
+        .filter(|_| invert)
+        .filter(|x| state.receipt_payments.contains_key(x));
+    let mut tr = Transaction::new(Some(TransactionMeta::Receipt {
+        r#fn: r#fn.clone(),
+        i,
+        paid,
+        refund_of,
+    }));
+    tr.set_idempotency_key(&author, idempotency_key);
+    tr.tags = outcome.tags;
+    for (user, amount) in allocation::split(rec, &allocation, &per_item, &username) {
+        if user != username {
+            tr.pay(&user, &username, amount);
+        }
+    }
+    if invert {
+        tr.invert();
+    }
+    tr.finalize();
+    let Added {
+        balance,
+        id: transaction,
+        pending,
+    } = match approval::add(state, tr, &author, force).await {
+        Ok(ret) => ret,
+        Err(Rejected::Replay(balance)) => {
+            return Ok(Submitted {
+                balance,
+                removed: vec![],
+                transaction: None,
+                pending: None,
             })
+        }
+        Err(Rejected::AlreadyPaid) => return Err(SplitError::AlreadyPaid),
+    };
+    // the rest happens once everyone confirms it
+    let removed = if pending.is_some() {
+        vec![]
+    } else {
+        receipt_paid(state, rec).await
+    };
+    Ok(Submitted {
+        balance,
+        removed,
+        transaction,
+        pending,
+    })
+}
+
+pub async fn submit(
+    axum::extract::State(state): AxumState,
+    identity: auth::Identity,
+    audit: audit::Context,
+    lang: i18n::Lang,
+    headers: axum::http::HeaderMap,
+    axum::extract::Form(f): axum::extract::Form<HashMap<String, String>>,
+) -> axum::http::Response<http_body::combinators::UnsyncBoxBody<axum::body::Bytes, axum::Error>> {
+    let t = i18n::catalog(&state, lang).await;
+    let error = |err: api::ApiError| {
+        (err.status(), axum::response::Html::from(t.error(&err))).into_response()
+    };
+    let Some(r#fn) = f
+        .get("fn")
+        .filter(|x| x.bytes().all(|x| x.is_ascii_digit()))
+    else {
+        return error(api::ApiError::bad_request(t.get("missing_receipt")));
+    };
+    let Some(i) = f.get("i").and_then(|x| x.parse::<u32>().ok()) else {
+        return error(api::ApiError::bad_request(t.get("missing_receipt")));
+    };
+    let Some(username) = f.get("username") else {
+        return error(api::ApiError::bad_request(t.get("missing_username")));
+    };
+    if !identity.can_act_as(username) {
+        return error(api::ApiError::new(
+            axum::http::StatusCode::FORBIDDEN,
+            "forbidden",
+            t.get("forbidden"),
+        ));
+    }
+    let mut paid = HashMap::<String, BTreeSet<usize>>::new();
+    for (k, v) in &f {
+        let Some((username, idx)) = k.split_once('$') else {
+            continue;
+        };
+        if matches!(v.as_str(), "" | "off" | "0" | "false") {
+            continue;
+        }
+        let Ok(idx) = idx.parse::<usize>() else {
+            continue;
+        };
+        paid.entry(username.to_owned()).or_default().insert(idx);
+    }
+    let split = Split {
+        r#fn: r#fn.clone(),
+        i,
+        username: username.clone(),
+        paid,
+        refund_of: f.get("refund_of").cloned(),
+        idempotency_key: idempotency_key(&headers, &f),
+        force: f
+            .get("force")
+            .is_some_and(|x| !matches!(x.as_str(), "" | "off" | "0" | "false")),
+        author: identity
+            .username
+            .clone()
+            .unwrap_or_else(|| username.clone()),
+    };
+    let res = split_receipt(&state, split).await;
+    audit
+        .record(
+            &state,
+            &f,
+            res.as_ref()
+                .map(|x| x.ids(r#fn, i))
+                .map_err(ToString::to_string),
+        )
+        .await;
+    match res {
+        Ok(Submitted {
+            balance,
+            removed,
+            pending,
+            ..
+        }) => axum::response::Html::from(
+            render_submitted(
+                &state,
+                lang,
+                ".",
+                balance,
+                username,
+                &removed,
+                pending.is_some(),
+            )
+            .await,
+        )
+        .into_response(),
+        Err(err) => error(err.into()),
+    }
+}
+
+/// Render `view` with some extra variables
+fn render_with(template: &Template, view: &impl serde::Serialize, extra: liquid::Object) -> String {
+    match liquid::to_object(view) {
+        Ok(mut obj) => {
+            obj.extend(extra);
+            template
+                .render(&obj)
+                .unwrap_or_else(|err| format!("Error: {err}"))
+        }
+        Err(err) => format!("Error: {err}"),
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct ReceiptItemView {
+    /// Index of the item, used when splitting
+    pub num: usize,
+    pub name: String,
+    /// Negative for refunds
+    pub count: f64,
+    pub unit: String,
+    /// In kopecks, negative for refunds
+    pub per_item: i64,
+    /// In kopecks, negative for refunds
+    pub total: i64,
+    pub is_advance: bool,
+    /// Split between everyone by the allocation rules
+    pub is_fee: bool,
+    pub has_proposal: bool,
+    /// Users proposed from the split of the original receipt for refunds, or by the receipt rules
+    pub proposed: Vec<String>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct ReceiptView {
+    pub r#fn: String,
+    pub i: u32,
+    /// In kopecks
+    pub total: u64,
+    pub already_paid: bool,
+    pub is_advance: bool,
+    pub is_refund: bool,
+    /// Id of the receipt this is a refund for, empty if none was found
+    pub refund_of: String,
+    /// Tags the transaction will get from the receipt rules
+    pub tags: Vec<String>,
+    pub items: Vec<ReceiptItemView>,
+}
+
+/// What the split page shows for a receipt
+pub async fn receipt_view(
+    state: &State,
+    rec: &Object,
+    username: &str,
+) -> Result<ReceiptView, SplitError> {
+    let r#fn = rec
+        .get::<fields::DriveNum>()
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let i = rec
+        .get::<fields::DocNum>()
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let payment_type = rec
+        .get::<fields::PaymentType>()
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let invert = match payment_type {
+        PaymentType::Sale | PaymentType::PurchaseReturn => false,
+        PaymentType::Purchase | PaymentType::SaleReturn => true,
+        PaymentType::Unknown => return Err(SplitError::InvalidPaymentType),
+    };
+    let inv = |x: u64| if invert { -(x as i64) } else { x as i64 };
+    let inv_f = |x: f64| if invert { -x } else { x };
+    let refund = refund::find_original(state, rec);
+    let outcome = rules::for_receipt(state, rec, username).await;
+    let rules = state.config.allocation.rules(rec);
+    let items = rec
+        .get_all::<fields::ReceiptItem>()
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let proposed = refund
+                .as_ref()
+                .and_then(|x| x.split.get(&i))
+                .or_else(|| Some(&outcome.split).filter(|x| !x.is_empty()));
+            ReceiptItemView {
+                num: i,
+                name: item
+                    .get::<fields::ItemName>()
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default(),
+                count: item
+                    .get::<fields::ItemQuantity>()
+                    .ok()
+                    .flatten()
+                    .map(|x| inv_f(x.f64_approximation()))
+                    .unwrap_or_default(),
+                unit: item
+                    .get::<fields::Unit>()
+                    .ok()
+                    .flatten()
+                    .filter(|unit| !unit.is_empty())
+                    .or_else(|| {
+                        item.get::<fields::ItemQuantityUnit>()
+                            .ok()
+                            .flatten()
+                            .map(|x| x.to_string())
+                    })
+                    .unwrap_or_default(),
+                per_item: item
+                    .get::<fields::ItemUnitPrice>()
+                    .ok()
+                    .flatten()
+                    .map(inv)
+                    .unwrap_or_default(),
+                total: item
+                    .get::<fields::ItemTotalPrice>()
+                    .ok()
+                    .flatten()
+                    .map(inv)
+                    .unwrap_or_default(),
+                is_advance: item_is_advance(&item).unwrap_or_default(),
+                is_fee: rules.is_fee(&item),
+                has_proposal: proposed.is_some(),
+                proposed: proposed.into_iter().flatten().cloned().collect(),
+            }
         })
-        .collect::<Vec<_>>();
-    axum::response::Html::from(
-        state
-            .submitted_t
-            .get()
-            .await
-            .render(&liquid::object!({
-                "prefix": ".",
-                "balance": balance,
-                "username": username,
-                "removed": removed,
-            }))
-            .unwrap_or_else(|err| format!("Error: {err}")),
-    )
+        .collect();
+    Ok(ReceiptView {
+        total: rec
+            .get::<fields::TotalSum>()
+            .ok()
+            .flatten()
+            .unwrap_or_default(),
+        already_paid: state.paid_receipts.contains(&format!("{fn}_{i:07}")),
+        is_advance: is_advance(rec).unwrap_or_default(),
+        is_refund: invert,
+        refund_of: refund.map(|x| x.receipt_id).unwrap_or_default(),
+        tags: outcome.tags.into_iter().collect(),
+        r#fn,
+        i,
+        items,
+    })
+}
+
+/// Why [`fetch_receipt`] failed
+enum FetchFailure {
+    /// The provider needs the user to log in first
+    Redirect(String),
+    Error {
+        err: api::ApiError,
+        /// The receipt was queued to be fetched later
+        queued: bool,
+    },
+}
+
+/// Fetch the receipt from QR code query `q` for `username`, queueing it to be retried later if the
+/// providers failed transiently
+async fn fetch_receipt(
+    state: &State,
+    q: &str,
+    username: Option<&str>,
+) -> Result<Document, FetchFailure> {
+    let mut rec = parse_qr(q).await;
+    if let Some(username) = username {
+        let _ = rec.set::<ofd::custom::Username>(username.to_owned());
+    }
+    if let Err(err) = rules::apply(&state.config.rules, &mut rec).await {
+        return Err(FetchFailure::Error {
+            err: err.into(),
+            queued: false,
+        });
+    }
+    let err = match ofd::fetch(state, rec.clone()).await {
+        Ok(doc) => return Ok(doc),
+        Err(ofd::Error::Redirect(url)) => return Err(FetchFailure::Redirect(url)),
+        Err(err) => err,
+    };
+    log::error!("ofd fetch failed: {err}");
+    let mut queued = false;
+    // worth retrying later
+    let transient = matches!(err, ofd::Error::AllFailed(_)) && err.is_transient();
+    if let (true, Some(username)) = (transient, username) {
+        match pending::enqueue(state, username, q, rec, &err).await {
+            Ok(()) => queued = true,
+            Err(err2) => log::error!("failed to queue receipt: {err2}"),
+        }
+    }
+    Err(FetchFailure::Error {
+        err: err.into(),
+        queued,
+    })
 }
 
 pub async fn add(
     axum::extract::RawQuery(q): axum::extract::RawQuery,
     cookies: axum_extra::extract::CookieJar,
+    identity: auth::Identity,
+    lang: i18n::Lang,
     axum::extract::State(state): AxumState,
 ) -> axum::http::Response<http_body::combinators::UnsyncBoxBody<axum::body::Bytes, axum::Error>> {
+    let t = i18n::catalog(&state, lang).await;
     axum::response::Html::from(if let Some(q) = q {
-        if let Some(username) = cookies
-            .get("username")
-            .map(axum_extra::extract::cookie::Cookie::value)
-        {
-            let mut rec = parse_qr(&q).await;
-            if !rec.contains::<fields::DriveNum>()
-                || !rec.contains::<fields::DocNum>()
-                || !rec.contains::<fields::DocFiscalSign>() && q.starts_with("http")
-            {
-                let q = if q.starts_with("ofd=") {
-                    q.split_once('&').map_or(q.as_str(), |x| x.1)
-                } else {
-                    &q
-                };
-                if let Ok(res) = reqwest::get(q).await {
-                    if let Ok(text) = res.text().await {
-                        if let Some(x) = text
-                            .split("<div>ФН №: <right>")
-                            .nth(1)
-                            .and_then(|x| x.split('<').next())
-                        {
-                            if x.bytes().all(|x| x.is_ascii_digit()) {
-                                let _ = rec.set::<fiscal_data::fields::DriveNum>(x.to_owned());
-                            }
-                        }
-                        if let Some(x) = text
-                            .split("<div>ФП: <right>")
-                            .nth(1)
-                            .and_then(|x| x.split('<').next())
-                        {
-                            if let Ok(x) = x.parse::<u64>() {
-                                let [_, _, a, b, c, d, e, f] = x.to_be_bytes();
-                                let _ = rec
-                                    .set::<fiscal_data::fields::DocFiscalSign>([a, b, c, d, e, f]);
-                            }
-                        }
-                        if let Some(x) = text
-                            .split("<div>ФД №: <right>")
-                            .nth(1)
-                            .and_then(|x| x.split('<').next())
-                        {
-                            if let Ok(x) = x.parse::<u32>() {
-                                let _ = rec.set::<fiscal_data::fields::DocNum>(x);
-                            }
-                        }
-                        if !rec.contains::<fiscal_data::fields::PaymentType>() {
-                            let _ = rec.set::<fiscal_data::fields::PaymentType>(PaymentType::Sale);
-                        }
-                        if let Some(x) = text
-                            .split("<div>Дата Время <right>")
-                            .nth(1)
-                            .and_then(|x| x.split('<').next())
-                            .and_then(|x| {
-                                chrono::NaiveDateTime::parse_from_str(x, "%d.%m.%Y %H:%M").ok()
-                            })
-                        {
-                            let _ = rec.set::<fields::DateTime>(x);
-                        }
-                        if let Some(x) = text
-                            .split("<big>ИТОГ <right>≡")
-                            .nth(1)
-                            .and_then(|x| x.split('<').next())
-                        {
-                            if let Some(sum) = parse_sum(x) {
-                                let _ = rec.set::<fields::TotalSum>(sum);
-                            }
-                        }
-                    }
-                }
-            }
-            match ofd::fetch(&state, rec).await {
-                Ok(doc) => {
-                    let rec = doc.data();
-                    let r#fn = rec
-                        .get::<fields::DriveNum>()
-                        .ok()
-                        .flatten()
-                        .unwrap_or_default();
-                    let i = rec
-                        .get::<fields::DocNum>()
-                        .ok()
-                        .flatten()
-                        .unwrap_or_default();
-                    let payment_type = rec
-                        .get::<fields::PaymentType>()
-                        .ok()
-                        .flatten()
-                        .unwrap_or_default();
-                    let invert = match payment_type {
-                        PaymentType::Sale | PaymentType::PurchaseReturn => false,
-                        PaymentType::Purchase | PaymentType::SaleReturn => true,
-                        PaymentType::Unknown => {
-                            return axum::response::Html::from("invalid payment type".to_owned())
-                                .into_response()
-                        }
-                    };
-                    let inv = |x: u64| if invert { -(x as i64) } else { x as i64 };
-                    let inv_f = |x: f64| if invert { -x } else { x };
-                    state.add_t.get().await.render(&liquid::object!({
-                        "total": rec.get::<fields::TotalSum>().ok().flatten().unwrap_or_default(),
-                        "username": username,
-                        "already_paid": state.paid_receipts.contains(&format!("{fn}_{i:07}")),
-                        "is_advance": is_advance(rec).unwrap_or_default(),
-                        "is_refund": invert,
-                        "fn": r#fn,
-                        "i": i,
-                        "items": rec.get_all::<fields::ReceiptItem>().unwrap_or_default().into_iter().enumerate().map(|(i, item)| {
-                            liquid::object!({
-                                "is_advance": item_is_advance(&item).unwrap_or_default(),
-                                "num": i,
-                                "name": item
-                                    .get::<fields::ItemName>()
-                                    .ok()
-                                    .flatten()
-                                    .unwrap_or_default(),
-                                "count": item
-                                    .get::<fields::ItemQuantity>()
-                                    .ok()
-                                    .flatten()
-                                    .map(|x| inv_f(x.f64_approximation()))
-                                    .unwrap_or_default(),
-                                "unit": item
-                                    .get::<fields::Unit>()
-                                    .ok()
-                                    .flatten()
-                                    .filter(|unit| !unit.is_empty())
-                                    .or_else(|| {
-                                        item.get::<fields::ItemQuantityUnit>()
-                                            .ok()
-                                            .flatten()
-                                            .map(|x| x.to_string())
-                                    })
-                                    .unwrap_or_default(),
-                                "per_item": item
-                                    .get::<fields::ItemUnitPrice>()
-                                    .ok()
-                                    .flatten()
-                                    .map(inv)
-                                    .unwrap_or_default(),
-                                "total": item
-                                    .get::<fields::ItemTotalPrice>()
-                                    .ok()
-                                    .flatten()
-                                    .map(inv)
-                                    .unwrap_or_default(),
-                            })
-                        }).collect::<Vec<_>>(),
-                        "usernames": &state.config.usernames,
-                    }))
-                    .unwrap_or_else(|err| format!("Error: {err}"))
-                }
-                Err(ofd::Error::Redirect(url)) => {
+        if let Some(username) = identity.username_or_cookie(&cookies) {
+            let username = username.as_str();
+            match fetch_receipt(&state, &q, Some(username)).await {
+                Ok(doc) => match receipt_view(&state, doc.data(), username).await {
+                    Ok(view) => render_with(
+                        &*state.add_t.get().await,
+                        &view,
+                        liquid::object!({
+                            "t": &*t,
+                            "username": username,
+                            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+                            "usernames": &state.config.usernames,
+                        }),
+                    ),
+                    Err(err) => t.error(&err.into()),
+                },
+                Err(FetchFailure::Redirect(url)) => {
                     return axum::response::Redirect::to(&url).into_response();
                 }
-                Err(err) => {
-                    log::error!("ofd fetch failed: {err}");
-                    format!("error: {err}")
+                Err(FetchFailure::Error { err, queued: true }) => {
+                    format!("{}<br>{}", t.error(&err), t.get("receipt_queued"))
                 }
+                Err(FetchFailure::Error { err, queued: false }) => t.error(&err),
             }
         } else {
-            "missing username cookie".to_owned()
+            t.get("missing_username").to_owned()
         }
     } else {
-        "missing qr info".to_owned()
+        t.get("missing_qr").to_owned()
     })
     .into_response()
 }
+
+/// Decode the QR code in an uploaded photo and continue as `/add` would: browsers get redirected
+/// to the split page, API clients (`Accept: application/json`) get the fetched receipt
+pub async fn scan(
+    axum::extract::State(state): AxumState,
+    cookies: axum_extra::extract::CookieJar,
+    identity: auth::Identity,
+    lang: i18n::Lang,
+    headers: axum::http::HeaderMap,
+    mut multipart: axum::extract::Multipart,
+) -> axum::http::Response<http_body::combinators::UnsyncBoxBody<axum::body::Bytes, axum::Error>> {
+    let is_json = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.contains("application/json"));
+    let t = i18n::catalog(&state, lang).await;
+    let error = |err: api::ApiError| {
+        if is_json {
+            err.into_response()
+        } else {
+            (err.status(), axum::response::Html::from(t.error(&err))).into_response()
+        }
+    };
+    let mut image = None;
+    let mut username = identity.username_or_cookie(&cookies);
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return error(api::ApiError::bad_request(err)),
+        };
+        match field.name() {
+            Some("image") => match field.bytes().await {
+                Ok(x) => image = Some(x),
+                Err(err) => return error(api::ApiError::bad_request(err)),
+            },
+            Some("username") => match field.text().await {
+                Ok(x) if !x.is_empty() && !identity.can_act_as(&x) => {
+                    return error(auth::forbidden())
+                }
+                Ok(x) if !x.is_empty() => username = Some(x),
+                Ok(_) => {}
+                Err(err) => return error(api::ApiError::bad_request(err)),
+            },
+            _ => {}
+        }
+    }
+    let Some(image) = image else {
+        return error(api::ApiError::bad_request("missing image"));
+    };
+    let q = match tokio::task::spawn_blocking(move || scan::decode(&image)).await {
+        Ok(Ok(q)) => q,
+        Ok(Err(err)) => {
+            return error(api::ApiError::new(
+                axum::http::StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_qr",
+                err,
+            ))
+        }
+        Err(err) => {
+            return error(api::ApiError::new(
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                err,
+            ))
+        }
+    };
+    log::info!("decoded qr code: {q}");
+    if !is_json {
+        // keep the header value valid no matter what the QR code contains
+        let q = q
+            .bytes()
+            .map(|x| match x {
+                b'!'..=b'~' => char::from(x).to_string(),
+                x => format!("%{x:02X}"),
+            })
+            .collect::<String>();
+        return axum::response::Redirect::to(&format!("add?{q}")).into_response();
+    }
+    match fetch_receipt(&state, &q, username.as_deref()).await {
+        Ok(doc) => {
+            let id = format!(
+                "{}_{:07}",
+                doc.data()
+                    .get::<fields::DriveNum>()
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default(),
+                doc.data()
+                    .get::<fields::DocNum>()
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default()
+            );
+            match fiscal_data::json::Document::try_from(doc) {
+                Ok(receipt) => axum::Json(serde_json::json!({
+                    "query": q,
+                    "id": id,
+                    "already_paid": state.paid_receipts.contains(&id),
+                    "receipt": receipt,
+                }))
+                .into_response(),
+                Err(err) => error(api::ApiError::new(
+                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    "invalid_receipt",
+                    err,
+                )),
+            }
+        }
+        Err(FetchFailure::Redirect(url)) => error(ofd::Error::Redirect(url).into()),
+        // not an error yet, the receipt may still be fetched later
+        Err(FetchFailure::Error { err, queued: true }) => (
+            axum::http::StatusCode::ACCEPTED,
+            axum::Json(serde_json::json!({
+                "query": q,
+                "pending": true,
+                "error": err,
+            })),
+        )
+            .into_response(),
+        Err(FetchFailure::Error { err, queued: false }) => error(err),
+    }
+}
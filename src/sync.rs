@@ -0,0 +1,196 @@
+//! Importing the receipts attached to our accounts at the providers (e.g. the FNS mobile app
+//! account), so that purchases nobody scanned still show up for splitting.
+
+use std::{collections::BTreeMap, io};
+
+use chrono::{DateTime, Utc};
+use fiscal_data::{fields, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::{events, ofd, rules, server::State, Config, QR_DATE_FORMAT2};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Synced {
+    /// Receipt id (`fn_i`)
+    pub id: String,
+    pub provider: String,
+    /// QR code built from the receipt, for opening the split page
+    pub query: String,
+    pub seller: String,
+    pub date: String,
+    pub total: u64,
+    #[serde(with = "crate::iso8601")]
+    pub imported: DateTime<Utc>,
+    /// Dismissed by a user, kept so that it isn't imported again
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+/// The QR code query string of a receipt, the inverse of `parse_qr`
+pub fn qr_query(rec: &Object) -> Option<String> {
+    let date = rec.get::<fields::DateTime>().ok()??;
+    let sum = rec.get::<fields::TotalSum>().ok()??;
+    let r#fn = rec.get::<fields::DriveNum>().ok()??;
+    let i = rec.get::<fields::DocNum>().ok()??;
+    let [a, b, c, d, e, f] = rec.get::<fields::DocFiscalSign>().ok()??;
+    let n = rec.get::<fields::PaymentType>().ok()??;
+    Some(format!(
+        "t={}&s={}.{:02}&fn={fn}&i={i}&fp={}&n={}",
+        date.format(QR_DATE_FORMAT2),
+        sum / 100,
+        sum % 100,
+        u64::from_be_bytes([0, 0, a, b, c, d, e, f]),
+        n as u8,
+    ))
+}
+
+pub async fn load(config: &Config) -> io::Result<Vec<Synced>> {
+    match tokio::fs::read(config.data_path("synced.json")).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err),
+    }
+}
+
+async fn save(state: &State) -> io::Result<()> {
+    let all = state
+        .synced
+        .iter()
+        .map(|x| (x.key().clone(), x.value().clone()))
+        .collect::<BTreeMap<_, _>>();
+    let path1 = state.config.data_path("synced.json.tmp");
+    tokio::fs::write(
+        &path1,
+        serde_json::to_vec(&all.values().collect::<Vec<_>>())?,
+    )
+    .await?;
+    tokio::fs::rename(path1, state.config.data_path("synced.json")).await
+}
+
+pub async fn hide(state: &State, id: &str) -> io::Result<()> {
+    // the id comes from the client, so only touch entries we know about
+    let Some(mut item) = state.synced.get_mut(id) else {
+        return Ok(());
+    };
+    item.hidden = true;
+    drop(item);
+    state.events.publish(events::Event::Synced);
+    save(state).await
+}
+
+/// Import the receipts we haven't seen yet from every provider that can list them
+pub async fn run(state: &State) {
+    let mut seen = vec![];
+    let mut changed = false;
+    for provider in ofd::registry().await.all() {
+        if seen.iter().any(|x| x == provider.id()) {
+            continue;
+        }
+        seen.push(provider.id().to_owned());
+        let recs = match provider.list(state).await {
+            Ok(x) => x,
+            Err(ofd::Error::Redirect(_)) => {
+                log::debug!("{} needs authorization, not syncing", provider.id());
+                continue;
+            }
+            Err(err) => {
+                log::warn!("failed to list receipts at {}: {err}", provider.id());
+                continue;
+            }
+        };
+        for rec in recs {
+            let Ok(id) = provider.cache_id(&rec) else {
+                continue;
+            };
+            if state.synced.contains_key(&id) {
+                continue;
+            }
+            let doc = match ofd::fetch_from(state, &*provider, rec).await {
+                Ok(doc) => doc,
+                Err(err) => {
+                    log::warn!("failed to import {id} from {}: {err}", provider.id());
+                    continue;
+                }
+            };
+            let rec = doc.data();
+            let Some(query) = qr_query(rec) else {
+                log::warn!("{id} from {} has no QR code fields", provider.id());
+                continue;
+            };
+            log::info!("imported {id} from {}", provider.id());
+            // keep it so that it isn't imported again, but don't offer it for splitting
+            let ignored = rules::for_receipt(state, rec, "")
+                .await
+                .ignored_by
+                .is_some();
+            state.synced.insert(
+                id.clone(),
+                Synced {
+                    id,
+                    provider: provider.id().to_owned(),
+                    query,
+                    seller: rec.get::<fields::User>().ok().flatten().unwrap_or_default(),
+                    date: rec
+                        .get::<fields::DateTime>()
+                        .ok()
+                        .flatten()
+                        .map(|x| x.format("%Y-%m-%d %H:%M").to_string())
+                        .unwrap_or_default(),
+                    total: rec
+                        .get::<fields::TotalSum>()
+                        .ok()
+                        .flatten()
+                        .unwrap_or_default(),
+                    imported: Utc::now(),
+                    hidden: ignored,
+                },
+            );
+            changed = true;
+        }
+    }
+    if changed {
+        state.events.publish(events::Event::Synced);
+        if let Err(err) = save(state).await {
+            log::error!("failed to save synced receipts: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fiscal_data::{enums::PaymentType, fields, Object, TlvType};
+
+    use super::qr_query;
+    use crate::parse_qr;
+
+    #[test]
+    fn test() {
+        let mut rec = Object::new();
+        rec.set::<fields::DateTime>(
+            chrono::NaiveDate::from_ymd_opt(2024, 2, 3)
+                .unwrap()
+                .and_hms_opt(4, 5, 6)
+                .unwrap(),
+        )
+        .unwrap();
+        rec.set::<fields::TotalSum>(12305).unwrap();
+        rec.set::<fields::DriveNum>("9999078900000001".to_owned())
+            .unwrap();
+        rec.set::<fields::DocNum>(42).unwrap();
+        rec.set::<fields::DocFiscalSign>([0, 0, 0, 0, 1, 2])
+            .unwrap();
+        rec.set::<fields::PaymentType>(PaymentType::Sale).unwrap();
+        let query = qr_query(&rec).unwrap();
+        assert_eq!(
+            query,
+            "t=20240203T040506&s=123.05&fn=9999078900000001&i=42&fp=258&n=1"
+        );
+        let parsed = tokio_test::block_on(parse_qr(&query));
+        assert_eq!(
+            parsed.into_bytes().unwrap(),
+            rec.clone().into_bytes().unwrap()
+        );
+        rec.remove::<fields::DocNum>();
+        assert_eq!(qr_query(&rec), None);
+    }
+}
@@ -0,0 +1,344 @@
+//! Outgoing webhooks. Every event is first written to `data/webhooks` and then delivered in the
+//! background, retrying until the receiver accepts it, so nothing is lost while it's down.
+//!
+//! Requests are `POST`s of `{"id", "event", "date", "data"}`. With a secret configured they carry
+//! `X-Signature-256: sha256=<hex HMAC-SHA256 of the body>`.
+
+use std::{collections::HashSet, io, time::Duration};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::Notify;
+
+use crate::{auth, server::State, Config};
+
+const FIRST_DELAY: Duration = Duration::from_secs(10);
+const MAX_DELAY: Duration = Duration::from_secs(3600);
+/// Drop deliveries that have failed for this long
+const MAX_AGE: chrono::Days = chrono::Days::new(7);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    /// Stable name of the receiver, queued deliveries follow it if the `url` changes
+    pub id: String,
+    pub url: String,
+    /// Event types to send, all of them if empty: `transaction`, `settlement`, `list`,
+    /// `fetch_failed`
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Key for signing the requests
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+impl WebhookConfig {
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|x| x == event)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Delivery {
+    pub id: String,
+    /// `id` of the webhook in the config
+    pub hook: String,
+    pub event: String,
+    pub data: serde_json::Value,
+    #[serde(with = "crate::iso8601")]
+    pub created: DateTime<Utc>,
+    pub attempts: u32,
+    #[serde(with = "crate::iso8601")]
+    pub next_attempt: DateTime<Utc>,
+    #[serde(default)]
+    pub last_error: String,
+}
+
+impl Delivery {
+    fn body(&self) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "id": self.id,
+            "event": self.event,
+            "date": self.created.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            "data": self.data,
+        }))
+        .expect("webhook serialization failed")
+    }
+    fn schedule(&mut self, now: DateTime<Utc>) {
+        let delay = FIRST_DELAY
+            .saturating_mul(2u32.saturating_pow(self.attempts.saturating_sub(1)))
+            .min(MAX_DELAY);
+        self.next_attempt = now + chrono::Duration::from_std(delay).unwrap_or_default();
+    }
+}
+
+/// Deliveries that haven't been accepted yet
+#[derive(Default)]
+pub struct Queue {
+    pub deliveries: DashMap<String, Delivery>,
+    notify: Notify,
+}
+
+impl Queue {
+    pub fn new(deliveries: Vec<Delivery>) -> Self {
+        Self {
+            deliveries: deliveries.into_iter().map(|x| (x.id.clone(), x)).collect(),
+            notify: Notify::new(),
+        }
+    }
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", auth::hex(&mac.finalize().into_bytes()))
+}
+
+async fn save(config: &Config, delivery: &Delivery) -> io::Result<()> {
+    let dir = config.data_path("webhooks");
+    tokio::fs::create_dir_all(&dir).await?;
+    let path1 = dir.join(format!("{}.json.tmp", delivery.id));
+    let path2 = dir.join(format!("{}.json", delivery.id));
+    tokio::fs::write(&path1, serde_json::to_vec(delivery)?).await?;
+    tokio::fs::rename(path1, path2).await
+}
+
+async fn remove(config: &Config, id: &str) -> io::Result<()> {
+    match tokio::fs::remove_file(config.data_path(format!("webhooks/{id}.json"))).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+pub async fn load(config: &Config) -> io::Result<Vec<Delivery>> {
+    let mut ret = vec![];
+    let Ok(mut dir) = tokio::fs::read_dir(config.data_path("webhooks")).await else {
+        return Ok(ret);
+    };
+    while let Some(file) = dir.next_entry().await? {
+        let path = file.path();
+        if path.extension().and_then(|x| x.to_str()) != Some("json") {
+            continue;
+        }
+        let data = tokio::fs::read(&path).await?;
+        match serde_json::from_slice::<Delivery>(&data) {
+            Ok(x) => ret.push(x),
+            Err(err) => log::error!("invalid webhook delivery {path:?}: {err}"),
+        }
+    }
+    Ok(ret)
+}
+
+/// Queue `data` for every webhook subscribed to `event`
+pub async fn fire(state: &State, event: &str, data: impl Serialize) {
+    let hooks = state
+        .config
+        .webhooks
+        .iter()
+        .filter(|x| x.wants(event))
+        .collect::<Vec<_>>();
+    if hooks.is_empty() {
+        return;
+    }
+    let data = match serde_json::to_value(data) {
+        Ok(x) => x,
+        Err(err) => {
+            log::error!("failed to serialize {event} webhook: {err}");
+            return;
+        }
+    };
+    let now = Utc::now();
+    for hook in hooks {
+        let delivery = Delivery {
+            id: uuid::Uuid::new_v4().to_string(),
+            hook: hook.id.clone(),
+            event: event.to_owned(),
+            data: data.clone(),
+            created: now,
+            attempts: 0,
+            next_attempt: now,
+            last_error: String::new(),
+        };
+        if let Err(err) = save(&state.config, &delivery).await {
+            log::error!("failed to queue {event} webhook for {}: {err}", hook.id);
+        }
+        state
+            .webhooks
+            .deliveries
+            .insert(delivery.id.clone(), delivery);
+    }
+    state.webhooks.notify.notify_one();
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    hook: &WebhookConfig,
+    delivery: &Delivery,
+) -> Result<(), String> {
+    let body = delivery.body();
+    let mut req = client
+        .post(&hook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Event", &delivery.event)
+        .header("X-Webhook-Id", &delivery.id);
+    if let Some(secret) = &hook.secret {
+        req = req.header("X-Signature-256", signature(secret, &body));
+    }
+    let resp = req.body(body).send().await.map_err(|err| err.to_string())?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("status {}", resp.status()))
+    }
+}
+
+/// Deliveries that are due, oldest first. A receiver gets nothing while an older delivery to it
+/// waits for a retry, so that it sees the events in order.
+fn due(deliveries: impl IntoIterator<Item = Delivery>, now: DateTime<Utc>) -> Vec<Delivery> {
+    let mut ret = deliveries.into_iter().collect::<Vec<_>>();
+    ret.sort_by_key(|x| x.created);
+    let mut waiting = HashSet::new();
+    ret.retain(|x| {
+        if x.next_attempt > now {
+            waiting.insert(x.hook.clone());
+        }
+        !waiting.contains(&x.hook)
+    });
+    ret
+}
+
+/// Try every delivery that is due
+async fn deliver_due(state: &State, client: &reqwest::Client) {
+    let due = due(
+        state.webhooks.deliveries.iter().map(|x| x.value().clone()),
+        Utc::now(),
+    );
+    let mut failed = HashSet::new();
+    for mut delivery in due {
+        if failed.contains(&delivery.hook) {
+            continue;
+        }
+        let hook = state.config.webhooks.iter().find(|x| x.id == delivery.hook);
+        let res = match hook {
+            Some(hook) => deliver(client, hook, &delivery).await,
+            None => Err("webhook removed from the config".to_owned()),
+        };
+        let gave_up = hook.is_none() || delivery.created + MAX_AGE < Utc::now();
+        match res {
+            Ok(()) => log::debug!("delivered {} webhook {}", delivery.event, delivery.id),
+            Err(err) if gave_up => {
+                log::error!(
+                    "dropping {} webhook {} to {}: {err}",
+                    delivery.event,
+                    delivery.id,
+                    delivery.hook
+                );
+            }
+            Err(err) => {
+                log::warn!(
+                    "failed to deliver {} webhook {} to {}: {err}",
+                    delivery.event,
+                    delivery.id,
+                    delivery.hook
+                );
+                failed.insert(delivery.hook.clone());
+                delivery.attempts += 1;
+                delivery.last_error = err;
+                delivery.schedule(Utc::now());
+                if let Err(err) = save(&state.config, &delivery).await {
+                    log::error!("failed to save webhook {}: {err}", delivery.id);
+                }
+                state
+                    .webhooks
+                    .deliveries
+                    .insert(delivery.id.clone(), delivery);
+                continue;
+            }
+        }
+        state.webhooks.deliveries.remove(&delivery.id);
+        if let Err(err) = remove(&state.config, &delivery.id).await {
+            log::error!("failed to remove webhook {}: {err}", delivery.id);
+        }
+    }
+}
+
+/// Deliver webhooks forever, right after they're queued and then on their retry schedule
+pub async fn run(state: State) {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+    {
+        Ok(x) => x,
+        Err(err) => {
+            log::error!("failed to create the webhook client: {err}");
+            return;
+        }
+    };
+    loop {
+        deliver_due(&state, &client).await;
+        let _ = tokio::time::timeout(FIRST_DELAY, state.webhooks.notify.notified()).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{due, signature, Delivery, WebhookConfig};
+
+    #[test]
+    fn test() {
+        let hook = WebhookConfig {
+            id: "hook".to_owned(),
+            url: "http://localhost/hook".to_owned(),
+            events: vec!["list".to_owned()],
+            secret: None,
+        };
+        assert!(hook.wants("list"));
+        assert!(!hook.wants("transaction"));
+        let sig = signature("secret", b"{}");
+        assert!(sig.strip_prefix("sha256=").is_some_and(|x| x.len() == 64));
+        assert_ne!(sig, signature("other", b"{}"));
+        let now = chrono::Utc::now();
+        let mut delivery = Delivery {
+            id: "1".to_owned(),
+            hook: hook.id,
+            event: "list".to_owned(),
+            data: serde_json::json!([]),
+            created: now,
+            attempts: 1,
+            next_attempt: now,
+            last_error: String::new(),
+        };
+        delivery.schedule(now);
+        assert_eq!(delivery.next_attempt - now, chrono::Duration::seconds(10));
+        delivery.attempts = 100;
+        delivery.schedule(now);
+        assert_eq!(delivery.next_attempt - now, chrono::Duration::hours(1));
+        let body = serde_json::from_slice::<serde_json::Value>(&delivery.body()).unwrap();
+        assert_eq!(body["event"], "list");
+        assert_eq!(body["data"], serde_json::json!([]));
+
+        // a newer delivery waits behind an older one in backoff, other receivers don't
+        let newer = |id: &str, hook: &str| Delivery {
+            id: id.to_owned(),
+            hook: hook.to_owned(),
+            created: now + chrono::Duration::seconds(1),
+            next_attempt: now,
+            ..delivery.clone()
+        };
+        let ids = due(
+            [
+                newer("2", &delivery.hook),
+                delivery.clone(),
+                newer("3", "other"),
+            ],
+            now,
+        )
+        .into_iter()
+        .map(|x| x.id)
+        .collect::<Vec<_>>();
+        assert_eq!(ids, ["3"]);
+    }
+}